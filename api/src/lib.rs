@@ -3,6 +3,7 @@ extern crate log;
 
 pub mod errors;
 pub mod models;
+pub mod observer;
 
 use errors::ApiError;
 use http::{header::USER_AGENT, StatusCode};
@@ -23,15 +24,14 @@ pub async fn get_access_token(email: &str, password: &str) -> Result<LoginRespon
         persist: true,
     };
 
-    let res = reqwest::Client::new()
+    let request = reqwest::Client::new()
         .post(format!(
             "{}/Mitsubishi.Wifi.Client/Login/ClientLogin",
             API_URL
         ))
         .header(USER_AGENT, CUSTOM_USER_AGENT)
-        .json(&login_request)
-        .send()
-        .await?;
+        .json(&login_request);
+    let res = observer::send("Login/ClientLogin", request).await?;
 
     let status = res.status();
 
@@ -57,14 +57,14 @@ pub async fn current_data(
     device_id: &String,
     building_id: &String,
 ) -> Result<CurrentDataResponse, ApiError> {
-    let res = reqwest::Client::new()
+    let request = reqwest::Client::new()
         .get(format!(
             "{}/Mitsubishi.Wifi.Client/Device/Get?id={}&buildingID={}",
             API_URL, device_id, building_id
         ))
         .header(USER_AGENT, CUSTOM_USER_AGENT)
-        .header("X-MitsContextKey", access_token)
-        .send()
+        .header("X-MitsContextKey", access_token);
+    let res = observer::send("Device/Get", request)
         .await
         .map_err(anyhow::Error::from)?;
 
@@ -88,14 +88,14 @@ pub async fn current_data(
 }
 
 pub async fn listdevices_data(access_token: &String) -> Result<Vec<ListDevicesResponse>, ApiError> {
-    let res = reqwest::Client::new()
+    let request = reqwest::Client::new()
         .get(format!(
             "{}/Mitsubishi.Wifi.Client/User/ListDevices",
             API_URL
         ))
         .header(USER_AGENT, CUSTOM_USER_AGENT)
-        .header("X-MitsContextKey", access_token)
-        .send()
+        .header("X-MitsContextKey", access_token);
+    let res = observer::send("User/ListDevices", request)
         .await
         .map_err(anyhow::Error::from)?;
 
@@ -119,14 +119,14 @@ pub async fn listdevices_data(access_token: &String) -> Result<Vec<ListDevicesRe
 }
 
 pub async fn request_refresh(access_token: String, device_id: String) -> Result<bool, ApiError> {
-    let res = reqwest::Client::new()
+    let request = reqwest::Client::new()
         .get(format!(
             "{}/Mitsubishi.Wifi.Client/Device/RequestRefresh?id={}",
             API_URL, device_id
         ))
         .header(USER_AGENT, CUSTOM_USER_AGENT)
-        .header("X-MitsContextKey", access_token)
-        .send()
+        .header("X-MitsContextKey", access_token);
+    let res = observer::send("Device/RequestRefresh", request)
         .await
         .map_err(anyhow::Error::from)?;
 
@@ -149,6 +149,39 @@ pub async fn request_refresh(access_token: String, device_id: String) -> Result<
     Ok(data)
 }
 
+/// Sends the flagged changes to the device, MELCloud answers with the state it will apply
+pub async fn set_ata(access_token: &String, request: &SetAtaRequest) -> Result<CurrentDataResponse, ApiError> {
+    let request = reqwest::Client::new()
+        .post(format!(
+            "{}/Mitsubishi.Wifi.Client/Device/SetAta",
+            API_URL
+        ))
+        .header(USER_AGENT, CUSTOM_USER_AGENT)
+        .header("X-MitsContextKey", access_token)
+        .json(request);
+    let res = observer::send("Device/SetAta", request)
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    let status = res.status();
+
+    if status == StatusCode::UNAUTHORIZED {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let data_str = res.text().await.map_err(anyhow::Error::from)?;
+    debug!("{}", data_str);
+
+    if status != StatusCode::OK {
+        return Err(ApiError::Other(anyhow::anyhow!(data_str)));
+    }
+
+    let data: CurrentDataResponse = serde_json::from_str(&data_str).map_err(anyhow::Error::from)?;
+    debug!("CurrentDataResponse: {:#?}", data_str);
+
+    Ok(data)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -214,6 +247,6 @@ mod tests {
         let access_token = dotenv::var("ACCESS_TOKEN").unwrap();
 
         let response = request_refresh(access_token, device_id).await.unwrap();
-        assert_eq!(true, response);
+        assert!(response);
     }
 }
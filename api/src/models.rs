@@ -107,6 +107,48 @@ impl CurrentDataResponse {
     }
 }
 
+/// The body of `Device/SetAta`, the flags tell which of the fields MELCloud should apply
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+#[allow(non_snake_case)]
+pub struct SetAtaRequest {
+    pub device_iD: u32,
+    pub effective_flags: u64,
+    pub has_pending_command: bool,
+    pub power: bool,
+    pub operation_mode: u8,
+    pub set_temperature: f32,
+    pub set_fan_speed: u8,
+    pub vane_horizontal: u8,
+    pub vane_vertical: u8,
+}
+
+impl SetAtaRequest {
+    pub const FLAG_POWER: u64 = 0x01;
+    pub const FLAG_OPERATION_MODE: u64 = 0x02;
+    pub const FLAG_SET_TEMPERATURE: u64 = 0x04;
+    pub const FLAG_SET_FAN_SPEED: u64 = 0x08;
+    pub const FLAG_VANE_VERTICAL: u64 = 0x10;
+    pub const FLAG_VANE_HORIZONTAL: u64 = 0x100;
+}
+
+impl From<&CurrentDataResponse> for SetAtaRequest {
+    /// Starts from the current state of the device with no changes flagged
+    fn from(data: &CurrentDataResponse) -> Self {
+        SetAtaRequest {
+            device_iD: data.device_iD,
+            effective_flags: 0,
+            has_pending_command: true,
+            power: data.power,
+            operation_mode: data.operation_mode,
+            set_temperature: data.set_temperature,
+            set_fan_speed: data.set_fan_speed,
+            vane_horizontal: data.vane_horizontal,
+            vane_vertical: data.vane_vertical,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 #[allow(non_snake_case)]
@@ -174,6 +216,7 @@ pub struct Device {
     pub wifi_adapter_status: Option<String>,
 
     pub has_error: Option<bool>,
+    pub has_pending_command: Option<bool>,
 
     pub last_time_stamp: String,
 }
@@ -0,0 +1,33 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// How a single MELCloud request went
+#[derive(Debug)]
+pub struct RequestOutcome {
+    pub endpoint: &'static str,
+    /// `None` when no response came back at all
+    pub status: Option<u16>,
+    pub elapsed: Duration,
+}
+
+static OBSERVER: OnceLock<fn(&RequestOutcome)> = OnceLock::new();
+
+/// Sets the function called after every request, e.g. for metrics. Only the first one sticks.
+pub fn set_observer(observer: fn(&RequestOutcome)) {
+    let _ = OBSERVER.set(observer);
+}
+
+pub(crate) async fn send(endpoint: &'static str, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+    let started = Instant::now();
+    let result = request.send().await;
+
+    if let Some(observer) = OBSERVER.get() {
+        observer(&RequestOutcome {
+            endpoint,
+            status: result.as_ref().ok().map(|res| res.status().as_u16()),
+            elapsed: started.elapsed(),
+        });
+    }
+
+    result
+}
@@ -0,0 +1,41 @@
+CREATE TABLE IF NOT EXISTS melcloud (
+    time TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    device_type INTEGER NOT NULL,
+    power BOOLEAN NOT NULL,
+    offline BOOLEAN NOT NULL,
+    room_temperature REAL NOT NULL,
+    set_temperature REAL NOT NULL,
+    last_communication TEXT NOT NULL,
+    actual_fan_speed INTEGER NOT NULL,
+    fan_speed INTEGER NOT NULL,
+    automatic_fan_speed BOOLEAN,
+    vane_vertical_direction INTEGER NOT NULL,
+    vane_vertical_swing BOOLEAN,
+    vane_horizontal_direction INTEGER NOT NULL,
+    vane_horizontal_swing BOOLEAN,
+    operation_mode INTEGER NOT NULL,
+    in_standby_mode BOOLEAN NOT NULL,
+    heating_energy_consumed_rate1 REAL,
+    heating_energy_consumed_rate2 REAL,
+    cooling_energy_consumed_rate1 REAL,
+    cooling_energy_consumed_rate2 REAL,
+    auto_energy_consumed_rate1 REAL,
+    auto_energy_consumed_rate2 REAL,
+    dry_energy_consumed_rate1 REAL,
+    dry_energy_consumed_rate2 REAL,
+    fan_energy_consumed_rate1 REAL,
+    fan_energy_consumed_rate2 REAL,
+    other_energy_consumed_rate1 REAL,
+    other_energy_consumed_rate2 REAL,
+    current_energy_consumed REAL,
+    current_energy_mode INTEGER,
+    energy_correction_model REAL,
+    energy_correction_active BOOLEAN,
+    wifi_signal_strength REAL,
+    wifi_adapter_status TEXT,
+    has_error BOOLEAN,
+    UNIQUE (time, device_id)
+);
+
+CREATE INDEX IF NOT EXISTS melcloud_device_id_time_idx ON melcloud (device_id, time);
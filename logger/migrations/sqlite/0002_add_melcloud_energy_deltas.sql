@@ -0,0 +1,10 @@
+ALTER TABLE melcloud ADD COLUMN heating_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN cooling_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN auto_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN dry_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN fan_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN other_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN total_energy_delta REAL;
+ALTER TABLE melcloud ADD COLUMN energy_delta_seconds INTEGER;
+ALTER TABLE melcloud ADD COLUMN energy_counter_reset BOOLEAN;
+ALTER TABLE melcloud ADD COLUMN energy_gap BOOLEAN;
@@ -0,0 +1,2 @@
+ALTER TABLE melcloud ADD COLUMN energy_cost REAL;
+ALTER TABLE melcloud ADD COLUMN energy_price REAL;
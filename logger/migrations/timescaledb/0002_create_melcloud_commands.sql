@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS melcloud_commands (
+    time TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL,
+    device_id INTEGER NOT NULL,
+    actor TEXT NOT NULL,
+    source TEXT NOT NULL,
+    commands JSONB NOT NULL,
+    success BOOLEAN NOT NULL,
+    error TEXT,
+    previous_state JSONB,
+    response JSONB
+);
+
+CREATE INDEX IF NOT EXISTS melcloud_commands_device_id_time_idx ON melcloud_commands (device_id, time DESC);
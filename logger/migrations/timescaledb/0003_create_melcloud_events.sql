@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS melcloud_events (
+    time TIMESTAMP WITH TIME ZONE NOT NULL,
+    device_id INTEGER NOT NULL,
+    device_name TEXT,
+    kind TEXT NOT NULL,
+    from_value TEXT,
+    to_value TEXT,
+    description TEXT NOT NULL,
+    UNIQUE (time, device_id, kind)
+);
+
+SELECT CREATE_HYPERTABLE('melcloud_events', BY_RANGE('time'), if_not_exists => TRUE);
+
+CREATE INDEX IF NOT EXISTS melcloud_events_device_id_time_idx ON melcloud_events (device_id, time DESC);
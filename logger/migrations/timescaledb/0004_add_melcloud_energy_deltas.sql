@@ -0,0 +1,11 @@
+ALTER TABLE melcloud
+    ADD COLUMN IF NOT EXISTS heating_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS cooling_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS auto_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS dry_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS fan_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS other_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS total_energy_delta REAL,
+    ADD COLUMN IF NOT EXISTS energy_delta_seconds INTEGER,
+    ADD COLUMN IF NOT EXISTS energy_counter_reset BOOLEAN,
+    ADD COLUMN IF NOT EXISTS energy_gap BOOLEAN;
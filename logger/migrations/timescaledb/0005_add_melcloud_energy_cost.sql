@@ -0,0 +1,3 @@
+ALTER TABLE melcloud
+    ADD COLUMN IF NOT EXISTS energy_cost REAL,
+    ADD COLUMN IF NOT EXISTS energy_price REAL;
@@ -1,14 +1,15 @@
-use api::Device;
+use api::ListDevicesResponse;
 use api::errors::ApiError;
 
 use api::current_data;
 use api::listdevices_data;
 use api::request_refresh;
+use chrono::Utc;
 
-use crate::storage::influxdb::influx::upsert_device_list_entry_into_influxdb;
-use crate::storage::influxdb::influx::upsert_current_data_into_influxdb;
-use crate::storage::timescaledb::timescale::upsert_current_data_into_timescaledb;
-use crate::storage::timescaledb::timescale::upsert_device_list_entry_into_timescaledb;
+use crate::app::dedup::{self, SampleTracker};
+use crate::app::monitoring::monitoring;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::buffer::BufferedSink;
 
 pub async fn get_access_token() -> String {
     let email = dotenv::var("MELCLOUD_EMAIL").unwrap();
@@ -28,15 +29,56 @@ pub async fn get_access_token() -> String {
     }
 }
 
-pub async fn get_device(access_token: String) -> Result<(u32, Device), ApiError> {
-    match listdevices_data(&access_token).await {
+/// A device the logger polls
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolledDevice {
+    pub device_id: String,
+    pub building_id: String,
+}
+
+/// Picks the devices to poll: `DEVICE_IDS` as a list or `all`, otherwise `DEVICE_ID` or the first device
+fn select_devices(data: &[ListDevicesResponse]) -> Result<Vec<PolledDevice>, anyhow::Error> {
+    let devices: Vec<PolledDevice> = data
+        .iter()
+        .flat_map(|list_devices| {
+            list_devices.structure.devices.iter().map(move |entry| PolledDevice {
+                device_id: entry.device_iD.to_string(),
+                building_id: list_devices.iD.to_string(),
+            })
+        })
+        .collect();
+    let building_id = dotenv::var("BUILDING_ID").ok();
+    // A mistyped ID would otherwise poll another device, or nothing, without a word
+    let find = |device_id: &str| {
+        let device = devices.iter().find(|device| device.device_id == device_id).ok_or_else(|| {
+            let known: Vec<&str> = devices.iter().map(|device| device.device_id.as_str()).collect();
+            anyhow::anyhow!("Device {} not found in MELCloud, the account has {}", device_id, known.join(", "))
+        })?;
+        Ok(PolledDevice {
+            device_id: device_id.to_string(),
+            building_id: building_id.clone().unwrap_or_else(|| device.building_id.clone()),
+        })
+    };
+
+    match dotenv::var("DEVICE_IDS") {
+        Ok(device_ids) if device_ids == "all" => Ok(devices),
+        Ok(device_ids) => device_ids.split(',').map(|device_id| find(device_id.trim())).collect(),
+        Err(_) => match dotenv::var("DEVICE_ID") {
+            Ok(device_id) => Ok(vec![find(&device_id)?]),
+            Err(_) => devices.first().map(|device| find(&device.device_id)).into_iter().collect(),
+        },
+    }
+}
+
+pub async fn get_devices(access_token: String) -> Result<Vec<PolledDevice>, ApiError> {
+    match listdevices_data(&access_token).await.inspect_err(|err| monitoring().record_api_error(err)) {
         Ok(data) => {
-            let list_devices = &data[0];
-            let structure = &list_devices.structure;
-            let devices = &structure.devices;
-            let device = &devices[0].device;
+            let devices = select_devices(&data).map_err(ApiError::Other)?;
+            if devices.is_empty() {
+                return Err(ApiError::Other(anyhow::anyhow!("No devices found")));
+            }
 
-            Ok((list_devices.iD, device.clone()))
+            Ok(devices)
         },
         Err(ApiError::Unauthorized) => {
             error!("Failed to request list devices data because of unauthorized");
@@ -52,9 +94,12 @@ pub async fn get_device(access_token: String) -> Result<(u32, Device), ApiError>
 pub async fn refresh_device(access_token: String, device_id: String) -> Result<bool, ApiError> {
     info!("Refreshing the device {}", &device_id);
 
-    match request_refresh(access_token, device_id).await {
-        Ok(_data) => {
-            Ok(true)
+    match request_refresh(access_token, device_id.clone()).await.inspect_err(|err| monitoring().record_api_error(err)) {
+        Ok(accepted) => {
+            if !accepted {
+                warn!("MELCloud didn't accept the refresh of the device {}", &device_id);
+            }
+            Ok(accepted)
         }
         Err(ApiError::Unauthorized) => {
             error!("Failed to request a device refresh because of unauthorized");
@@ -67,30 +112,60 @@ pub async fn refresh_device(access_token: String, device_id: String) -> Result<b
     }
 }
 
-pub async fn fetch_and_log_new_entry(
-    influxdb_client: &Option<influxdb::Client>,
-    timescaledb_client: &Option<tokio_postgres::Client>,
+async fn log_snapshot(sinks: &[BufferedSink], tracker: &mut SampleTracker, snapshot: &mut DeviceSnapshot) {
+    monitoring().record_sample(snapshot.device_id, Utc::now());
+    let skip_unchanged = dedup::skip_unchanged();
+    snapshot.energy = tracker.energy_deltas(snapshot);
+
+    if !sinks.iter().any(|sink| tracker.is_new(sink.name(), snapshot)) {
+        let count = tracker.record_unchanged(snapshot);
+        if skip_unchanged {
+            debug!("Skipping unchanged sample {} for device {} ({} in a row)", snapshot.time, snapshot.device_id, count);
+            return;
+        }
+    }
+
+    for sink in sinks {
+        // A sink that already has the sample, e.g. from before a restart, doesn't get it again
+        if skip_unchanged && !tracker.is_new(sink.name(), snapshot) {
+            continue;
+        }
+        sink.write(snapshot).await;
+        tracker.record_written(sink.name(), snapshot);
+    }
+
+    let unchanged = tracker.take_unchanged(snapshot);
+    if unchanged > 0 {
+        info!("Device {} had {} unchanged sample(s) before {}", snapshot.device_id, unchanged, snapshot.time);
+    }
+}
+
+/// Fetches and logs the devices with a single ListDevices call, returning a snapshot per device
+pub async fn fetch_and_log_new_entries(
+    sinks: &[BufferedSink],
+    tracker: &mut SampleTracker,
     access_token: String,
-    device_id: String,
-    building_id: String,
-) -> Result<(), ApiError> {
-    info!("Logging new entry for device {}", &device_id);
+    devices: &[PolledDevice],
+) -> Result<Vec<Option<DeviceSnapshot>>, ApiError> {
+    info!("Logging new entries for {} device(s)", devices.len());
 
-    match listdevices_data(&access_token).await {
+    let mut snapshots = Vec::new();
+    match listdevices_data(&access_token).await.inspect_err(|err| monitoring().record_api_error(err)) {
         Ok(data) => {
-            if let Some(client) = influxdb_client {
-                if let Err(e) = upsert_device_list_entry_into_influxdb(client, &data).await {
-                    error!("Failed to log device list entry into influxdb: {}", e);
-                }
-            }
-
-            if let Some(client) = timescaledb_client {
-                if let Err(e) = upsert_device_list_entry_into_timescaledb(client, &data).await {
-                    error!("Failed to log device list entry into timescaledb: {}", e);
+            for device in devices {
+                match DeviceSnapshot::from_list_devices(&data, &device.device_id) {
+                    Ok(mut snapshot) => {
+                        log_snapshot(sinks, tracker, &mut snapshot).await;
+                        snapshots.push(Some(snapshot));
+                    }
+                    Err(e) => {
+                        error!("Failed to log device list entry for device {}: {}", device.device_id, e);
+                        snapshots.push(None);
+                    }
                 }
             }
 
-            Ok(())
+            Ok(snapshots)
         },
         Err(ApiError::Unauthorized) => {
             error!("Failed to request list devices data because of unauthorized");
@@ -98,31 +173,36 @@ pub async fn fetch_and_log_new_entry(
         }
         Err(ApiError::Other(err)) => {
             error!("Failed to request list devices data {}", err);
-            match current_data(&access_token, &device_id, &building_id).await {
-                Ok(data) => {
-                    if let Some(client) = influxdb_client {
-                        if let Err(e) = upsert_current_data_into_influxdb(client, &data).await {
-                            error!("Failed to log current data into influxdb: {}", e);
+            for device in devices {
+                match current_data(&access_token, &device.device_id, &device.building_id)
+                    .await
+                    .inspect_err(|err| monitoring().record_api_error(err))
+                {
+                    Ok(data) => {
+                        match DeviceSnapshot::from_current_data(&data, &device.building_id) {
+                            Ok(mut snapshot) => {
+                                log_snapshot(sinks, tracker, &mut snapshot).await;
+                                snapshots.push(Some(snapshot));
+                            }
+                            Err(e) => {
+                                error!("Failed to log current data for device {}: {}", device.device_id, e);
+                                snapshots.push(None);
+                            }
                         }
+                    },
+                    Err(ApiError::Unauthorized) => {
+                        error!("Failed to request current data because of unauthorized");
+                        return Err(ApiError::Unauthorized);
                     }
-        
-                    if let Some(client) = timescaledb_client {
-                        if let Err(e) = upsert_current_data_into_timescaledb(client, &data).await {
-                            error!("Failed to log current data into timescaledb: {}", e);
-                        }
+                    Err(ApiError::Other(err)) => {
+                        error!("Failed to request current data for device {}: {}", device.device_id, err);
+                        snapshots.push(None);
                     }
-    
-                    Ok(())
-                },
-                Err(ApiError::Unauthorized) => {
-                    error!("Failed to request current data because of unauthorized");
-                    Err(ApiError::Unauthorized)
-                }
-                Err(ApiError::Other(err)) => {
-                    error!("Failed to request current data {}", err);
-                    Err(api::errors::ApiError::Other(err))
                 }
             }
+
+            Ok(snapshots)
         },
     }
 }
+
@@ -0,0 +1,345 @@
+use std::sync::Arc;
+
+use api::errors::ApiError;
+use api::{current_data, set_ata, SetAtaRequest};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::app::app::PolledDevice;
+use crate::app::monitoring::monitoring;
+use crate::app::session::Session;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::buffer::{self, Buffered, DiskBuffer};
+use crate::storage::sink::is_rejected;
+use crate::storage::timescaledb::audit::insert_command_into_timescaledb;
+use crate::storage::timescaledb::pool::TimescalePool;
+
+pub const MIN_TEMPERATURE: f32 = 10.0;
+pub const MAX_TEMPERATURE: f32 = 31.0;
+
+/// A single validated change to a device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "field", content = "value")]
+pub enum DeviceCommand {
+    Power(bool),
+    /// MELCloud's operation mode: 1 heat, 2 dry, 3 cool, 7 fan, 8 auto
+    Mode(u8),
+    Temperature(f32),
+    /// 0 is automatic, 1-5 are the speeds
+    FanSpeed(u8),
+    /// 0 is automatic, 1-5 are the positions and 7 swings
+    VaneVertical(u8),
+    /// 0 is automatic, 1-5 are the positions, 8 splits and 12 swings
+    VaneHorizontal(u8),
+}
+
+fn parse_number(payload: &str, allowed: &[u8]) -> Result<u8, anyhow::Error> {
+    let value = payload.parse::<u8>().map_err(|_| anyhow::anyhow!("{} is not a number", payload))?;
+    if !allowed.contains(&value) {
+        return Err(anyhow::anyhow!("{} is not one of {:?}", value, allowed));
+    }
+    Ok(value)
+}
+
+impl DeviceCommand {
+    /// Parses a field and its payload, such as `mode` and `heat`. A mode other than `off` also
+    /// powers the device on, like a remote does.
+    pub fn parse(field: &str, payload: &str) -> Result<Vec<DeviceCommand>, anyhow::Error> {
+        let payload = payload.trim();
+        let lowercase = payload.to_ascii_lowercase();
+
+        let commands = match field {
+            "power" => match lowercase.as_str() {
+                "on" | "true" | "1" => vec![DeviceCommand::Power(true)],
+                "off" | "false" | "0" => vec![DeviceCommand::Power(false)],
+                _ => return Err(anyhow::anyhow!("Power must be ON or OFF, not {}", payload)),
+            },
+            "mode" => {
+                let mode = match lowercase.as_str() {
+                    "off" => return Ok(vec![DeviceCommand::Power(false)]),
+                    "heat" => 1,
+                    "dry" => 2,
+                    "cool" => 3,
+                    "fan_only" | "fan" => 7,
+                    "auto" | "heat_cool" => 8,
+                    _ => parse_number(payload, &[1, 2, 3, 7, 8])?,
+                };
+                vec![DeviceCommand::Power(true), DeviceCommand::Mode(mode)]
+            }
+            "temperature" => {
+                let temperature = payload
+                    .parse::<f32>()
+                    .map_err(|_| anyhow::anyhow!("{} is not a temperature", payload))?;
+                if !(MIN_TEMPERATURE..=MAX_TEMPERATURE).contains(&temperature) {
+                    return Err(anyhow::anyhow!(
+                        "Temperature must be within {}-{}, not {}",
+                        MIN_TEMPERATURE,
+                        MAX_TEMPERATURE,
+                        temperature
+                    ));
+                }
+                // The units take half degrees
+                vec![DeviceCommand::Temperature((temperature * 2.0).round() / 2.0)]
+            }
+            "fan" => match lowercase.as_str() {
+                "auto" => vec![DeviceCommand::FanSpeed(0)],
+                _ => vec![DeviceCommand::FanSpeed(parse_number(payload, &[0, 1, 2, 3, 4, 5])?)],
+            },
+            "vane_vertical" => match lowercase.as_str() {
+                "auto" => vec![DeviceCommand::VaneVertical(0)],
+                "swing" => vec![DeviceCommand::VaneVertical(7)],
+                _ => vec![DeviceCommand::VaneVertical(parse_number(payload, &[0, 1, 2, 3, 4, 5, 7])?)],
+            },
+            "vane_horizontal" => match lowercase.as_str() {
+                "auto" => vec![DeviceCommand::VaneHorizontal(0)],
+                "split" => vec![DeviceCommand::VaneHorizontal(8)],
+                "swing" => vec![DeviceCommand::VaneHorizontal(12)],
+                _ => vec![DeviceCommand::VaneHorizontal(parse_number(payload, &[0, 1, 2, 3, 4, 5, 8, 12])?)],
+            },
+            _ => return Err(anyhow::anyhow!("Unknown field {}", field)),
+        };
+
+        Ok(commands)
+    }
+
+    pub fn apply(&self, request: &mut SetAtaRequest) {
+        match *self {
+            DeviceCommand::Power(power) => {
+                request.power = power;
+                request.effective_flags |= SetAtaRequest::FLAG_POWER;
+            }
+            DeviceCommand::Mode(mode) => {
+                request.operation_mode = mode;
+                request.effective_flags |= SetAtaRequest::FLAG_OPERATION_MODE;
+            }
+            DeviceCommand::Temperature(temperature) => {
+                request.set_temperature = temperature;
+                request.effective_flags |= SetAtaRequest::FLAG_SET_TEMPERATURE;
+            }
+            DeviceCommand::FanSpeed(fan_speed) => {
+                request.set_fan_speed = fan_speed;
+                request.effective_flags |= SetAtaRequest::FLAG_SET_FAN_SPEED;
+            }
+            DeviceCommand::VaneVertical(vane) => {
+                request.vane_vertical = vane;
+                request.effective_flags |= SetAtaRequest::FLAG_VANE_VERTICAL;
+            }
+            DeviceCommand::VaneHorizontal(vane) => {
+                request.vane_horizontal = vane;
+                request.effective_flags |= SetAtaRequest::FLAG_VANE_HORIZONTAL;
+            }
+        }
+    }
+}
+
+async fn send_commands(
+    access_token: &String,
+    device: &PolledDevice,
+    commands: &[DeviceCommand],
+    previous: &mut Option<DeviceSnapshot>,
+) -> Result<DeviceSnapshot, ApiError> {
+    // SetAta takes the whole state, so it starts from the device's current one
+    let data = current_data(access_token, &device.device_id, &device.building_id).await?;
+    *previous = Some(DeviceSnapshot::from_current_data(&data, &device.building_id)?);
+    let mut request = SetAtaRequest::from(&data);
+    for command in commands {
+        command.apply(&mut request);
+    }
+
+    let data = set_ata(access_token, &request).await?;
+    Ok(DeviceSnapshot::from_current_data(&data, &device.building_id)?)
+}
+
+/// Sends the commands to the device in a single SetAta call, returns the state MELCloud will apply
+async fn execute(
+    session: &Session,
+    device: &PolledDevice,
+    commands: &[DeviceCommand],
+    previous: &mut Option<DeviceSnapshot>,
+) -> Result<DeviceSnapshot, anyhow::Error> {
+    let access_token = session.token().await;
+    let result = match send_commands(&access_token, device, commands, previous).await {
+        Err(ApiError::Unauthorized) => {
+            monitoring().record_api_error(&ApiError::Unauthorized);
+            let access_token = session.relogin(&access_token).await;
+            send_commands(&access_token, device, commands, previous).await
+        }
+        result => result,
+    };
+    if let Err(err) = &result {
+        monitoring().record_api_error(err);
+    }
+
+    match result {
+        Ok(snapshot) => Ok(snapshot),
+        Err(ApiError::Unauthorized) => Err(anyhow::anyhow!("Unauthorized")),
+        Err(ApiError::Other(err)) => Err(err),
+    }
+}
+
+/// A row of the `melcloud_commands` audit table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandAudit {
+    pub time: DateTime<Utc>,
+    pub device_id: u32,
+    pub actor: String,
+    pub source: String,
+    pub commands: Vec<DeviceCommand>,
+    pub error: Option<String>,
+    pub previous: Option<DeviceSnapshot>,
+    pub response: Option<DeviceSnapshot>,
+}
+
+impl Buffered for CommandAudit {
+    fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+/// The state of a device before and after a command
+#[derive(Debug, Serialize)]
+pub struct CommandOutcome {
+    pub previous: DeviceSnapshot,
+    pub state: DeviceSnapshot,
+}
+
+/// Runs the commands of every source one at a time and records them in TimescaleDB. Without
+/// TimescaleDB there is nowhere to record them, so control is refused.
+#[derive(Clone)]
+pub struct Commander {
+    session: Session,
+    audit: Option<TimescalePool>,
+    /// The audit rows TimescaleDB hasn't taken yet, oldest first
+    pending: Arc<Mutex<DiskBuffer<CommandAudit>>>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Commander {
+    pub fn new(session: Session, audit: Option<TimescalePool>) -> Commander {
+        Commander {
+            session,
+            audit,
+            pending: Arc::new(Mutex::new(buffer::open_or_in_memory("melcloud_commands"))),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Whether the commands can be audited, which control needs
+    pub fn is_audited(&self) -> bool {
+        self.audit.is_some()
+    }
+
+    /// `actor` is who sent the commands, such as an API key's name, and `source` through what
+    pub async fn run(
+        &self,
+        device: &PolledDevice,
+        commands: &[DeviceCommand],
+        actor: &str,
+        source: &str,
+    ) -> Result<CommandOutcome, anyhow::Error> {
+        let pool = self
+            .audit
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Control needs TimescaleDB to record the commands"))?;
+
+        // Two commands at once would both start from the same state and undo each other
+        let _guard = self.lock.lock().await;
+
+        info!("{} sent {:?} to the device {} through {}", actor, commands, device.device_id, source);
+        let mut previous = None;
+        let result = execute(&self.session, device, commands, &mut previous).await;
+
+        let audit = CommandAudit {
+            time: Utc::now(),
+            device_id: device.device_id.parse::<u32>()?,
+            actor: actor.to_string(),
+            source: source.to_string(),
+            commands: commands.to_vec(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+            previous: previous.clone(),
+            response: result.as_ref().ok().cloned(),
+        };
+        self.record(pool, audit).await;
+
+        let state = result?;
+        let previous = previous.ok_or_else(|| anyhow::anyhow!("No previous state"))?;
+        Ok(CommandOutcome { previous, state })
+    }
+
+    /// Queues the audit row behind the ones TimescaleDB hasn't taken yet, and writes as many as it takes
+    async fn record(&self, pool: &TimescalePool, audit: CommandAudit) {
+        let mut pending = self.pending.lock().await;
+        if let Err(err) = pending.push(audit).await {
+            error!("Failed to buffer the command audit: {}", err);
+        }
+
+        let mut written = 0;
+        while let Some(audit) = pending.front() {
+            let result = match pool.get().await {
+                Ok(client) => insert_command_into_timescaledb(&client, audit).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(_) => {}
+                Err(err) if is_rejected(&err) => {
+                    error!("Dropping the command audit of device {} from {}: {}", audit.device_id, audit.time, err)
+                }
+                Err(err) => {
+                    warn!("Failed to record the command audit, {} pending: {}", pending.len(), err);
+                    break;
+                }
+            }
+            pending.pop_front();
+            written += 1;
+        }
+
+        if written > 0 {
+            if let Err(err) = pending.persist().await {
+                error!("Failed to persist the pending command audits: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_apply() {
+        assert_eq!(vec![DeviceCommand::Power(true), DeviceCommand::Mode(3)], DeviceCommand::parse("mode", "cool").unwrap());
+        assert_eq!(vec![DeviceCommand::Power(false)], DeviceCommand::parse("mode", "off").unwrap());
+        assert_eq!(vec![DeviceCommand::Temperature(21.5)], DeviceCommand::parse("temperature", "21.4").unwrap());
+        assert_eq!(vec![DeviceCommand::FanSpeed(0)], DeviceCommand::parse("fan", "AUTO").unwrap());
+        assert_eq!(vec![DeviceCommand::VaneHorizontal(12)], DeviceCommand::parse("vane_horizontal", "swing").unwrap());
+
+        assert!(DeviceCommand::parse("temperature", "35").is_err());
+        assert!(DeviceCommand::parse("fan", "6").is_err());
+        assert!(DeviceCommand::parse("mode", "4").is_err());
+        assert!(DeviceCommand::parse("power", "maybe").is_err());
+        assert!(DeviceCommand::parse("humidity", "50").is_err());
+
+        let mut request = SetAtaRequest {
+            device_iD: 1,
+            effective_flags: 0,
+            has_pending_command: true,
+            power: false,
+            operation_mode: 1,
+            set_temperature: 20.0,
+            set_fan_speed: 0,
+            vane_horizontal: 0,
+            vane_vertical: 0,
+        };
+        for command in DeviceCommand::parse("mode", "cool").unwrap() {
+            command.apply(&mut request);
+        }
+        DeviceCommand::Temperature(23.0).apply(&mut request);
+        assert!(request.power);
+        assert_eq!((3, 23.0), (request.operation_mode, request.set_temperature));
+        assert_eq!(
+            SetAtaRequest::FLAG_POWER | SetAtaRequest::FLAG_OPERATION_MODE | SetAtaRequest::FLAG_SET_TEMPERATURE,
+            request.effective_flags
+        );
+    }
+}
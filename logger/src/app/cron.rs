@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, TimeZone, Timelike};
+
+/// A five field cron expression: minute, hour, day of month, month and day of week. Fields take
+/// `*`, values, ranges, lists and steps such as `*/15`, `8-18/2` or `1,3,5`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, anyhow::Error> {
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(anyhow::anyhow!("Step can't be zero in {}", part));
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse::<u32>()?, end.parse::<u32>()?)
+        } else {
+            let start = range.parse::<u32>()?;
+            // `5/10` means from 5 to the end in steps of 10
+            (start, if part.contains('/') { max } else { start })
+        };
+        if start < min || end > max || start > end {
+            return Err(anyhow::anyhow!("{} is out of range {}-{}", part, min, max));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(anyhow::anyhow!("Expected 5 fields in cron expression {}", expression));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            any_day_of_month: fields[2] == "*",
+            any_day_of_week: fields[4] == "*",
+        })
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day_of_month = self.days_of_month & (1 << date.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << date.weekday().num_days_from_sunday()) != 0;
+
+        // Like in crontab, a restricted day of month or day of week is enough when both are given
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (true, true) => true,
+            (true, false) => day_of_week,
+            (false, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        }
+    }
+
+    /// The first matching minute after `time`, in the time zone of `time`
+    pub fn next_after<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = time.timezone();
+        let mut next = time.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = next + Duration::days(4 * 366);
+
+        while next < limit {
+            let date = next.date();
+            if self.months & (1 << date.month()) == 0 {
+                let (year, month) = if date.month() == 12 { (date.year() + 1, 1) } else { (date.year(), date.month() + 1) };
+                next = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if !self.matches_day(date) {
+                next = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+                continue;
+            }
+            if self.hours & (1 << next.hour()) == 0 {
+                next = date.and_hms_opt(next.hour(), 0, 0)? + Duration::hours(1);
+                continue;
+            }
+            if self.minutes & (1 << next.minute()) == 0 {
+                next += Duration::minutes(1);
+                continue;
+            }
+
+            match timezone.from_local_datetime(&next) {
+                LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => return Some(time),
+                // Skipped by a daylight saving transition
+                LocalResult::None => next += Duration::minutes(1),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Helsinki;
+
+    #[test]
+    fn test_next_after() {
+        let every_quarter: Cron = "*/15 * * * *".parse().unwrap();
+        let time = Helsinki.with_ymd_and_hms(2024, 1, 1, 12, 7, 30).unwrap();
+        assert_eq!(Helsinki.with_ymd_and_hms(2024, 1, 1, 12, 15, 0).unwrap(), every_quarter.next_after(&time).unwrap());
+
+        let working_hours: Cron = "0 8-18/2 * * 1-5".parse().unwrap();
+        // Friday evening rolls over to Monday morning
+        let time = Helsinki.with_ymd_and_hms(2024, 1, 5, 18, 30, 0).unwrap();
+        assert_eq!(Helsinki.with_ymd_and_hms(2024, 1, 8, 8, 0, 0).unwrap(), working_hours.next_after(&time).unwrap());
+
+        let sundays: Cron = "30 6 * * 7".parse().unwrap();
+        assert_eq!(Helsinki.with_ymd_and_hms(2024, 1, 7, 6, 30, 0).unwrap(), sundays.next_after(&time).unwrap());
+
+        assert!("* * 30 2 *".parse::<Cron>().unwrap().next_after(&time).is_none());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("* * * *".parse::<Cron>().is_err());
+    }
+}
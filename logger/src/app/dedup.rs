@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::app::energy::{EnergyDeltas, EnergyTracker};
+use crate::app::snapshot::DeviceSnapshot;
+use crate::app::tariff::Tariff;
+use crate::storage::buffer::BufferedSink;
+
+/// Whether the samples MELCloud returns again unchanged are skipped or written anyway
+pub fn skip_unchanged() -> bool {
+    match dotenv::var("UNCHANGED_SAMPLES").as_deref() {
+        Ok("write") => false,
+        Ok("skip") | Err(_) => true,
+        Ok(value) => panic!("UNCHANGED_SAMPLES must be skip or write, not {}", value),
+    }
+}
+
+/// Remembers the last sample time written per sink and device, so that the same MELCloud sample
+/// isn't written again on every poll, and the energy counters of the last new sample
+#[derive(Debug, Default)]
+pub struct SampleTracker {
+    last_written: HashMap<(&'static str, u32), DateTime<Utc>>,
+    unchanged: HashMap<u32, u64>,
+    energy: EnergyTracker,
+}
+
+impl SampleTracker {
+    pub fn new(tariff: Option<Tariff>) -> SampleTracker {
+        SampleTracker {
+            energy: EnergyTracker::new(tariff),
+            ..Default::default()
+        }
+    }
+
+    /// Seeds the last written times and the energy counters from the sinks that can tell them. The
+    /// sinks that can't, or fail to, still get the first sample after a restart.
+    pub async fn seed_from(&mut self, sinks: &[BufferedSink]) {
+        for sink in sinks {
+            let last_written = match sink.last_written().await {
+                Ok(Some(last_written)) => last_written,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Failed to read the last written samples from {}: {}", sink.name(), err);
+                    continue;
+                }
+            };
+
+            for (device_id, time) in last_written {
+                info!("Last sample written into {} for device {} is from {}", sink.name(), device_id, time);
+                self.last_written.insert((sink.name(), device_id), time);
+            }
+        }
+
+        for sink in sinks {
+            match sink.last_states().await {
+                Ok(Some(states)) => states.iter().for_each(|state| self.energy.seed(state)),
+                Ok(None) => {}
+                Err(err) => warn!("Failed to read the last energy counters from {}: {}", sink.name(), err),
+            }
+        }
+    }
+
+    pub fn is_new(&self, sink: &'static str, snapshot: &DeviceSnapshot) -> bool {
+        match self.last_written.get(&(sink, snapshot.device_id)) {
+            Some(last_written) => snapshot.time > *last_written,
+            None => true,
+        }
+    }
+
+    pub fn energy_deltas(&mut self, snapshot: &DeviceSnapshot) -> Option<EnergyDeltas> {
+        self.energy.deltas(snapshot)
+    }
+
+    pub fn record_unchanged(&mut self, snapshot: &DeviceSnapshot) -> u64 {
+        let count = self.unchanged.entry(snapshot.device_id).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    pub fn record_written(&mut self, sink: &'static str, snapshot: &DeviceSnapshot) {
+        let last_written = self.last_written.entry((sink, snapshot.device_id)).or_insert(snapshot.time);
+        if snapshot.time > *last_written {
+            *last_written = snapshot.time;
+        }
+    }
+
+    /// Returns how many unchanged samples were seen since the previous written one
+    pub fn take_unchanged(&mut self, snapshot: &DeviceSnapshot) -> u64 {
+        self.unchanged.remove(&snapshot.device_id).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_only_newer_samples_are_new() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let mut tracker = SampleTracker::default();
+
+        let first = DeviceSnapshot::for_test(1, time);
+        assert!(tracker.is_new("timescaledb", &first));
+        tracker.record_written("timescaledb", &first);
+        assert_eq!(0, tracker.take_unchanged(&first));
+
+        assert!(!tracker.is_new("timescaledb", &first));
+        // A sink that couldn't tell its last sample still gets it
+        assert!(tracker.is_new("mqtt", &first));
+        assert_eq!(1, tracker.record_unchanged(&first));
+        assert_eq!(2, tracker.record_unchanged(&first));
+
+        let other_device = DeviceSnapshot::for_test(2, time);
+        assert!(tracker.is_new("timescaledb", &other_device));
+
+        let second = DeviceSnapshot::for_test(1, time + Duration::minutes(5));
+        assert!(tracker.is_new("timescaledb", &second));
+        tracker.record_written("timescaledb", &second);
+        assert_eq!(2, tracker.take_unchanged(&second));
+        assert!(!tracker.is_new("timescaledb", &DeviceSnapshot::for_test(1, time)));
+    }
+}
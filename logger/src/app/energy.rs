@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::app::events::DeviceState;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::app::tariff::Tariff;
+
+/// A counter that drops by more than this has been reset, smaller drops are MELCloud's corrections
+const RESET_TOLERANCE: f64 = 0.05;
+
+/// The longest time between two samples that still gets deltas, `ENERGY_MAX_GAP_MINUTES`
+fn max_gap() -> Duration {
+    let minutes = dotenv::var("ENERGY_MAX_GAP_MINUTES")
+        .map(|var| var.parse::<i64>())
+        .unwrap_or(Ok(180))
+        .unwrap();
+    Duration::minutes(minutes)
+}
+
+/// The kWh used per mode since the previous sample of the device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnergyDeltas {
+    /// Seconds since the previous sample
+    pub seconds: i64,
+    pub heating: Option<f32>,
+    pub cooling: Option<f32>,
+    pub auto: Option<f32>,
+    pub dry: Option<f32>,
+    pub fan: Option<f32>,
+    pub other: Option<f32>,
+    /// The sum of the modes, or the change of `current_energy_consumed` without them
+    pub total: Option<f32>,
+    /// Whether any of the counters went back to zero, its delta is then the count since the reset
+    pub reset: bool,
+    /// Whether the samples are more than `ENERGY_MAX_GAP_MINUTES` apart, the deltas then cover the
+    /// whole gap and can't be placed in time within it
+    pub gap: bool,
+    /// What the energy and its share of the fees cost with the tariff, `None` without one
+    #[serde(default)]
+    pub cost: Option<f32>,
+    /// The average price per kWh in the interval
+    #[serde(default)]
+    pub price: Option<f32>,
+}
+
+/// The cumulative counters of a sample, the rates of each mode summed up
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Counters {
+    time: DateTime<Utc>,
+    modes: [Option<f64>; 6],
+    current: Option<f64>,
+}
+
+fn rates(rate1: Option<f32>, rate2: Option<f32>) -> Option<f64> {
+    match (rate1, rate2) {
+        (None, None) => None,
+        _ => Some(rate1.unwrap_or(0.0) as f64 + rate2.unwrap_or(0.0) as f64),
+    }
+}
+
+impl From<&DeviceState> for Counters {
+    fn from(state: &DeviceState) -> Self {
+        Counters {
+            time: state.time,
+            modes: [
+                rates(state.heating_energy_consumed_rate1, state.heating_energy_consumed_rate2),
+                rates(state.cooling_energy_consumed_rate1, state.cooling_energy_consumed_rate2),
+                rates(state.auto_energy_consumed_rate1, state.auto_energy_consumed_rate2),
+                rates(state.dry_energy_consumed_rate1, state.dry_energy_consumed_rate2),
+                rates(state.fan_energy_consumed_rate1, state.fan_energy_consumed_rate2),
+                rates(state.other_energy_consumed_rate1, state.other_energy_consumed_rate2),
+            ],
+            current: state.current_energy_consumed.map(|current| current as f64),
+        }
+    }
+}
+
+/// The change of a counter and whether it was reset, `None` unless both samples have it
+fn counter_delta(previous: Option<f64>, current: Option<f64>) -> Option<(f64, bool)> {
+    let (previous, current) = (previous?, current?);
+    let delta = current - previous;
+    if delta < -RESET_TOLERANCE {
+        return Some((current, true));
+    }
+    Some((delta.max(0.0), false))
+}
+
+/// The reading to compare the next sample with, a correction doesn't lower it so that the
+/// energy it took back isn't counted again
+fn kept_counter(previous: Option<f64>, current: Option<f64>) -> Option<f64> {
+    match (previous, current) {
+        (Some(previous), Some(current)) if current < previous && previous - current <= RESET_TOLERANCE => Some(previous),
+        _ => current,
+    }
+}
+
+impl Counters {
+    fn kept(&self, previous: Option<&Counters>) -> Counters {
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return *self,
+        };
+        let mut modes = self.modes;
+        for (index, mode) in modes.iter_mut().enumerate() {
+            *mode = kept_counter(previous.modes[index], *mode);
+        }
+
+        Counters {
+            time: self.time,
+            modes,
+            current: kept_counter(previous.current, self.current),
+        }
+    }
+}
+
+/// The deltas between two samples of the same device
+fn deltas(previous: &Counters, current: &Counters) -> EnergyDeltas {
+    let mut reset = false;
+    let mut modes = [None; 6];
+    for (index, mode) in modes.iter_mut().enumerate() {
+        if let Some((delta, was_reset)) = counter_delta(previous.modes[index], current.modes[index]) {
+            *mode = Some(delta);
+            reset |= was_reset;
+        }
+    }
+
+    let total = if modes.iter().any(Option::is_some) {
+        Some(modes.iter().flatten().sum())
+    } else {
+        counter_delta(previous.current, current.current).map(|(delta, was_reset)| {
+            reset |= was_reset;
+            delta
+        })
+    };
+
+    let kwh = |delta: Option<f64>| delta.map(|delta| delta as f32);
+    EnergyDeltas {
+        seconds: (current.time - previous.time).num_seconds(),
+        heating: kwh(modes[0]),
+        cooling: kwh(modes[1]),
+        auto: kwh(modes[2]),
+        dry: kwh(modes[3]),
+        fan: kwh(modes[4]),
+        other: kwh(modes[5]),
+        total: kwh(total),
+        reset,
+        gap: current.time - previous.time > max_gap(),
+        cost: None,
+        price: None,
+    }
+}
+
+/// Remembers the counters of the previous new sample of each device, and prices the deltas
+/// with the tariff
+#[derive(Debug, Default)]
+pub struct EnergyTracker {
+    previous: HashMap<u32, Counters>,
+    tariff: Option<Tariff>,
+}
+
+impl EnergyTracker {
+    pub fn new(tariff: Option<Tariff>) -> EnergyTracker {
+        EnergyTracker {
+            previous: HashMap::new(),
+            tariff,
+        }
+    }
+
+    /// Picks up from the counters stored before a restart, unless a newer sample is already known
+    pub fn seed(&mut self, state: &DeviceState) {
+        match self.previous.get(&state.device_id) {
+            Some(previous) if previous.time >= state.time => {}
+            _ => {
+                self.previous.insert(state.device_id, Counters::from(state));
+            }
+        }
+    }
+
+    /// The deltas since the previous sample, `None` for the first sample without stored counters
+    /// and for samples that aren't newer
+    pub fn deltas(&mut self, snapshot: &DeviceSnapshot) -> Option<EnergyDeltas> {
+        let current = Counters::from(&DeviceState::from(snapshot));
+        let previous = match self.previous.get(&snapshot.device_id) {
+            Some(previous) if previous.time >= current.time => return None,
+            previous => previous.copied(),
+        };
+        self.previous.insert(snapshot.device_id, current.kept(previous.as_ref()));
+
+        let mut deltas = deltas(&previous?, &current);
+        if deltas.gap {
+            warn!(
+                "Device {} has no samples in the {}s before {}, its deltas are marked as a gap",
+                snapshot.device_id, deltas.seconds, current.time
+            );
+        }
+        if let (Some(tariff), Some(total)) = (&self.tariff, deltas.total) {
+            match tariff.cost(current.time, deltas.seconds, total as f64) {
+                Some(cost) => {
+                    deltas.cost = Some(cost.total as f32);
+                    deltas.price = Some(cost.price as f32);
+                }
+                None => warn!("No spot price for the energy of device {} before {}", snapshot.device_id, current.time),
+            }
+        }
+        if deltas.reset {
+            warn!("The energy counters of device {} were reset before {}", snapshot.device_id, current.time);
+        }
+        Some(deltas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(minutes: i64, heating: f32, cooling: f32) -> DeviceSnapshot {
+        let mut snapshot = DeviceSnapshot::for_test(3, DateTime::<Utc>::default() + Duration::minutes(minutes));
+        snapshot.heating_energy_consumed_rate1 = Some(heating);
+        snapshot.heating_energy_consumed_rate2 = Some(1.0);
+        snapshot.cooling_energy_consumed_rate1 = Some(cooling);
+        snapshot
+    }
+
+    #[test]
+    fn test_deltas_handle_resets_and_gaps() {
+        let mut tracker = EnergyTracker::default();
+        assert_eq!(None, tracker.deltas(&sample(0, 10.0, 5.0)));
+
+        let deltas = tracker.deltas(&sample(10, 10.5, 5.0)).unwrap();
+        assert_eq!(600, deltas.seconds);
+        assert_eq!(Some(0.5), deltas.heating);
+        assert_eq!(Some(0.0), deltas.cooling);
+        assert_eq!(None, deltas.dry);
+        assert_eq!(Some(0.5), deltas.total);
+        assert!(!deltas.reset);
+
+        // Unchanged and older samples are left out
+        assert_eq!(None, tracker.deltas(&sample(10, 10.5, 5.0)));
+        assert_eq!(None, tracker.deltas(&sample(5, 10.2, 5.0)));
+
+        // The cooling counter restarted from zero, a small drop is only a correction
+        let deltas = tracker.deltas(&sample(20, 10.49, 0.25)).unwrap();
+        assert_eq!(Some(0.0), deltas.heating);
+        assert_eq!(Some(0.25), deltas.cooling);
+        assert!(deltas.reset);
+
+        // The energy of a gap is kept, flagged since it can't be placed in time
+        let deltas = tracker.deltas(&sample(20 + 181, 11.0, 0.5)).unwrap();
+        assert_eq!((Some(0.5), Some(0.25), true), (deltas.heating, deltas.cooling, deltas.gap));
+        let deltas = tracker.deltas(&sample(20 + 191, 11.5, 0.5)).unwrap();
+        assert_eq!((Some(0.5), false), (deltas.heating, deltas.gap));
+    }
+
+    #[test]
+    fn test_seeded_counters_give_the_first_deltas() {
+        let mut tracker = EnergyTracker::default();
+        tracker.seed(&DeviceState::from(&sample(0, 10.0, 5.0)));
+        // An older stored row doesn't replace a newer one
+        tracker.seed(&DeviceState::from(&sample(-10, 9.0, 5.0)));
+
+        assert_eq!(None, tracker.deltas(&sample(0, 10.0, 5.0)));
+        assert_eq!(Some(0.5), tracker.deltas(&sample(10, 10.5, 5.0)).unwrap().heating);
+    }
+
+    #[test]
+    fn test_corrections_are_not_counted_again() {
+        let mut tracker = EnergyTracker::default();
+        tracker.deltas(&sample(0, 10.0, 5.0));
+
+        let deltas = tracker.deltas(&sample(10, 9.99, 5.0)).unwrap();
+        assert_eq!((Some(0.0), false), (deltas.heating, deltas.reset));
+
+        // Counted from the reading before the correction
+        let deltas = tracker.deltas(&sample(20, 10.1, 5.0)).unwrap();
+        assert!((deltas.heating.unwrap() - 0.1).abs() < 1e-5);
+    }
+}
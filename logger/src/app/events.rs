@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::buffer::BufferedSink;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    PowerOn,
+    PowerOff,
+    ModeChanged,
+    SetpointChanged,
+    FanChanged,
+    VaneVerticalChanged,
+    VaneHorizontalChanged,
+    Offline,
+    Online,
+    ErrorRaised,
+    ErrorCleared,
+    StandbyEntered,
+    StandbyLeft,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::PowerOn => "power_on",
+            EventKind::PowerOff => "power_off",
+            EventKind::ModeChanged => "mode_changed",
+            EventKind::SetpointChanged => "setpoint_changed",
+            EventKind::FanChanged => "fan_changed",
+            EventKind::VaneVerticalChanged => "vane_vertical_changed",
+            EventKind::VaneHorizontalChanged => "vane_horizontal_changed",
+            EventKind::Offline => "offline",
+            EventKind::Online => "online",
+            EventKind::ErrorRaised => "error_raised",
+            EventKind::ErrorCleared => "error_cleared",
+            EventKind::StandbyEntered => "standby_entered",
+            EventKind::StandbyLeft => "standby_left",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            EventKind::PowerOn => "Powered on",
+            EventKind::PowerOff => "Powered off",
+            EventKind::ModeChanged => "Mode changed",
+            EventKind::SetpointChanged => "Setpoint changed",
+            EventKind::FanChanged => "Fan speed changed",
+            EventKind::VaneVerticalChanged => "Vertical vane changed",
+            EventKind::VaneHorizontalChanged => "Horizontal vane changed",
+            EventKind::Offline => "Went offline",
+            EventKind::Online => "Came online",
+            EventKind::ErrorRaised => "Error raised",
+            EventKind::ErrorCleared => "Error cleared",
+            EventKind::StandbyEntered => "Entered standby",
+            EventKind::StandbyLeft => "Left standby",
+        }
+    }
+}
+
+/// A change in a device's state between two consecutive samples
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceEvent {
+    pub time: DateTime<Utc>,
+    pub device_id: u32,
+    pub device_name: Option<String>,
+    pub kind: EventKind,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl DeviceEvent {
+    /// Such as `Mode changed from heat to cool`
+    pub fn description(&self) -> String {
+        match (&self.from, &self.to) {
+            (Some(from), Some(to)) => format!("{} from {} to {}", self.kind.title(), from, to),
+            _ => self.kind.title().to_string(),
+        }
+    }
+}
+
+fn mode_name(operation_mode: u8) -> String {
+    match operation_mode {
+        1 => "heat".to_string(),
+        2 => "dry".to_string(),
+        3 => "cool".to_string(),
+        7 => "fan".to_string(),
+        8 => "auto".to_string(),
+        mode => mode.to_string(),
+    }
+}
+
+fn fan_name(fan_speed: u8) -> String {
+    match fan_speed {
+        0 => "auto".to_string(),
+        speed => speed.to_string(),
+    }
+}
+
+/// The part of a sample the events and the energy deltas are derived from, so that it can be read
+/// back from the sinks
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceState {
+    pub time: DateTime<Utc>,
+    pub device_id: u32,
+    pub power: bool,
+    pub offline: bool,
+    pub set_temperature: f32,
+    pub fan_speed: u8,
+    pub vane_vertical_direction: u8,
+    pub vane_horizontal_direction: u8,
+    pub operation_mode: u8,
+    pub in_standby_mode: bool,
+    pub has_error: Option<bool>,
+
+    pub heating_energy_consumed_rate1: Option<f32>,
+    pub heating_energy_consumed_rate2: Option<f32>,
+    pub cooling_energy_consumed_rate1: Option<f32>,
+    pub cooling_energy_consumed_rate2: Option<f32>,
+    pub auto_energy_consumed_rate1: Option<f32>,
+    pub auto_energy_consumed_rate2: Option<f32>,
+    pub dry_energy_consumed_rate1: Option<f32>,
+    pub dry_energy_consumed_rate2: Option<f32>,
+    pub fan_energy_consumed_rate1: Option<f32>,
+    pub fan_energy_consumed_rate2: Option<f32>,
+    pub other_energy_consumed_rate1: Option<f32>,
+    pub other_energy_consumed_rate2: Option<f32>,
+    pub current_energy_consumed: Option<f32>,
+}
+
+impl From<&DeviceSnapshot> for DeviceState {
+    fn from(snapshot: &DeviceSnapshot) -> Self {
+        DeviceState {
+            time: snapshot.time,
+            device_id: snapshot.device_id,
+            power: snapshot.power,
+            offline: snapshot.offline,
+            set_temperature: snapshot.set_temperature,
+            fan_speed: snapshot.fan_speed,
+            vane_vertical_direction: snapshot.vane_vertical_direction,
+            vane_horizontal_direction: snapshot.vane_horizontal_direction,
+            operation_mode: snapshot.operation_mode,
+            in_standby_mode: snapshot.in_standby_mode,
+            has_error: snapshot.has_error,
+
+            heating_energy_consumed_rate1: snapshot.heating_energy_consumed_rate1,
+            heating_energy_consumed_rate2: snapshot.heating_energy_consumed_rate2,
+            cooling_energy_consumed_rate1: snapshot.cooling_energy_consumed_rate1,
+            cooling_energy_consumed_rate2: snapshot.cooling_energy_consumed_rate2,
+            auto_energy_consumed_rate1: snapshot.auto_energy_consumed_rate1,
+            auto_energy_consumed_rate2: snapshot.auto_energy_consumed_rate2,
+            dry_energy_consumed_rate1: snapshot.dry_energy_consumed_rate1,
+            dry_energy_consumed_rate2: snapshot.dry_energy_consumed_rate2,
+            fan_energy_consumed_rate1: snapshot.fan_energy_consumed_rate1,
+            fan_energy_consumed_rate2: snapshot.fan_energy_consumed_rate2,
+            other_energy_consumed_rate1: snapshot.other_energy_consumed_rate1,
+            other_energy_consumed_rate2: snapshot.other_energy_consumed_rate2,
+            current_energy_consumed: snapshot.current_energy_consumed,
+        }
+    }
+}
+
+/// The events between the previous state of a device and its new sample
+pub fn diff(previous: &DeviceState, current: &DeviceSnapshot) -> Vec<DeviceEvent> {
+    let mut events = Vec::new();
+    let mut push = |kind: EventKind, values: Option<(String, String)>| {
+        let (from, to) = values.unzip();
+        events.push(DeviceEvent {
+            time: current.time,
+            device_id: current.device_id,
+            device_name: current.device_name.clone(),
+            kind,
+            from,
+            to,
+        });
+    };
+
+    if previous.offline != current.offline {
+        push(if current.offline { EventKind::Offline } else { EventKind::Online }, None);
+    }
+    if previous.power != current.power {
+        push(if current.power { EventKind::PowerOn } else { EventKind::PowerOff }, None);
+    }
+    if previous.operation_mode != current.operation_mode {
+        push(
+            EventKind::ModeChanged,
+            Some((mode_name(previous.operation_mode), mode_name(current.operation_mode))),
+        );
+    }
+    if previous.set_temperature != current.set_temperature {
+        push(
+            EventKind::SetpointChanged,
+            Some((previous.set_temperature.to_string(), current.set_temperature.to_string())),
+        );
+    }
+    if previous.fan_speed != current.fan_speed {
+        push(EventKind::FanChanged, Some((fan_name(previous.fan_speed), fan_name(current.fan_speed))));
+    }
+    if previous.vane_vertical_direction != current.vane_vertical_direction {
+        push(
+            EventKind::VaneVerticalChanged,
+            Some((previous.vane_vertical_direction.to_string(), current.vane_vertical_direction.to_string())),
+        );
+    }
+    if previous.vane_horizontal_direction != current.vane_horizontal_direction {
+        push(
+            EventKind::VaneHorizontalChanged,
+            Some((previous.vane_horizontal_direction.to_string(), current.vane_horizontal_direction.to_string())),
+        );
+    }
+    // Devices that don't report errors are counted as fine
+    let (had_error, has_error) = (previous.has_error.unwrap_or(false), current.has_error.unwrap_or(false));
+    if had_error != has_error {
+        push(if has_error { EventKind::ErrorRaised } else { EventKind::ErrorCleared }, None);
+    }
+    if previous.in_standby_mode != current.in_standby_mode {
+        push(
+            if current.in_standby_mode { EventKind::StandbyEntered } else { EventKind::StandbyLeft },
+            None,
+        );
+    }
+
+    events
+}
+
+/// Remembers the previous sample of each device to diff the next one against
+#[derive(Debug, Default)]
+pub struct EventDetector {
+    previous: HashMap<u32, DeviceState>,
+}
+
+impl EventDetector {
+    /// Seeds the previous states from the sinks that store them, so that the changes made while
+    /// the logger was down are detected on the first sample. The newest state across the sinks wins.
+    pub async fn seed_from(&mut self, sinks: &[BufferedSink]) {
+        for sink in sinks {
+            let states = match sink.last_states().await {
+                Ok(Some(states)) => states,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Failed to read the last device states from {}: {}", sink.name(), err);
+                    continue;
+                }
+            };
+
+            for state in states {
+                self.seed(state);
+            }
+        }
+    }
+
+    fn seed(&mut self, state: DeviceState) {
+        match self.previous.get(&state.device_id) {
+            Some(previous) if previous.time >= state.time => {}
+            _ => {
+                self.previous.insert(state.device_id, state);
+            }
+        }
+    }
+
+    /// The events since the previous sample of the device, none for the first sample without a
+    /// stored state and for samples that aren't newer
+    pub fn detect(&mut self, snapshot: &DeviceSnapshot) -> Vec<DeviceEvent> {
+        let events = match self.previous.get(&snapshot.device_id) {
+            Some(previous) if previous.time >= snapshot.time => return Vec::new(),
+            Some(previous) => diff(previous, snapshot),
+            None => Vec::new(),
+        };
+        self.previous.insert(snapshot.device_id, DeviceState::from(snapshot));
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_detects_changes() {
+        let mut detector = EventDetector::default();
+        let first = DeviceSnapshot::for_test(3, Utc::now());
+        assert!(detector.detect(&first).is_empty());
+
+        let mut second = first.clone();
+        second.time = first.time + Duration::minutes(1);
+        second.operation_mode = 3;
+        second.set_temperature = 23.5;
+        second.has_error = Some(true);
+        let events = detector.detect(&second);
+        let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(vec![EventKind::ModeChanged, EventKind::SetpointChanged, EventKind::ErrorRaised], kinds);
+        assert_eq!("Mode changed from heat to cool", events[0].description());
+        assert_eq!("Error raised", events[2].description());
+
+        // The same sample again, or an older one, changes nothing
+        assert!(detector.detect(&second).is_empty());
+        assert!(detector.detect(&first).is_empty());
+
+        let mut third = second.clone();
+        third.time = second.time + Duration::minutes(1);
+        third.power = false;
+        third.offline = true;
+        let kinds: Vec<_> = detector.detect(&third).iter().map(|event| event.kind).collect();
+        assert_eq!(vec![EventKind::Offline, EventKind::PowerOff], kinds);
+    }
+
+    #[test]
+    fn test_seeded_state_is_diffed_against() {
+        let time = Utc::now();
+        let mut stored = DeviceState::from(&DeviceSnapshot::for_test(3, time - Duration::hours(1)));
+        stored.power = false;
+
+        let mut detector = EventDetector::default();
+        detector.seed(stored);
+        detector.seed(DeviceState::from(&DeviceSnapshot::for_test(3, time - Duration::hours(2))));
+
+        // The stored state is the newest one, the device was turned on while the logger was down
+        let kinds: Vec<_> = detector.detect(&DeviceSnapshot::for_test(3, time)).iter().map(|event| event.kind).collect();
+        assert_eq!(vec![EventKind::PowerOn], kinds);
+    }
+}
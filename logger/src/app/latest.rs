@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::app::snapshot::DeviceSnapshot;
+
+/// The latest snapshot of each device, kept in memory for the HTTP API
+#[derive(Clone, Default)]
+pub struct LatestSnapshots {
+    snapshots: Arc<RwLock<HashMap<u32, DeviceSnapshot>>>,
+}
+
+impl LatestSnapshots {
+    /// Keeps the snapshot unless a newer one is already there
+    pub fn update(&self, snapshot: &DeviceSnapshot) {
+        let mut snapshots = self.snapshots.write().unwrap();
+        match snapshots.get(&snapshot.device_id) {
+            Some(latest) if latest.time > snapshot.time => {}
+            _ => {
+                snapshots.insert(snapshot.device_id, snapshot.clone());
+            }
+        }
+    }
+
+    pub fn get(&self, device_id: u32) -> Option<DeviceSnapshot> {
+        self.snapshots.read().unwrap().get(&device_id).cloned()
+    }
+}
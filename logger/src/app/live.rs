@@ -0,0 +1,47 @@
+use tokio::sync::broadcast;
+
+use crate::app::events::DeviceEvent;
+use crate::app::snapshot::DeviceSnapshot;
+
+/// How many updates a slow client may fall behind before it misses some
+const CAPACITY: usize = 256;
+
+/// Something new about a device for the live stream
+#[derive(Debug, Clone)]
+pub enum LiveUpdate {
+    Snapshot(Box<DeviceSnapshot>),
+    Event(DeviceEvent),
+}
+
+impl LiveUpdate {
+    pub fn device_id(&self) -> u32 {
+        match self {
+            LiveUpdate::Snapshot(snapshot) => snapshot.device_id,
+            LiveUpdate::Event(event) => event.device_id,
+        }
+    }
+}
+
+/// Hands the updates from the polling loop and the commands to every connected client
+#[derive(Clone)]
+pub struct LiveUpdates {
+    sender: broadcast::Sender<LiveUpdate>,
+}
+
+impl Default for LiveUpdates {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        LiveUpdates { sender }
+    }
+}
+
+impl LiveUpdates {
+    pub fn publish(&self, update: LiveUpdate) {
+        // Fails only when nobody is listening
+        let _ = self.sender.send(update);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveUpdate> {
+        self.sender.subscribe()
+    }
+}
@@ -1 +1,18 @@
-pub mod app;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod app;
+pub mod command;
+pub mod cron;
+pub mod dedup;
+pub mod energy;
+pub mod events;
+pub mod latest;
+pub mod live;
+pub mod monitoring;
+pub mod optimizer;
+pub mod prices;
+pub mod refresh;
+pub mod schedule;
+pub mod session;
+pub mod shutdown;
+pub mod snapshot;
+pub mod tariff;
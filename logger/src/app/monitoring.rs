@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use api::errors::ApiError;
+use api::observer::RequestOutcome;
+use chrono::{DateTime, Utc};
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+
+static MONITORING: LazyLock<Monitoring> = LazyLock::new(|| Monitoring::new().expect("Failed to create the logger metrics"));
+
+/// The logger's own metrics, shared by the whole process
+pub fn monitoring() -> &'static Monitoring {
+    &MONITORING
+}
+
+/// How often the summary is logged, `MONITORING_SUMMARY_MINUTES=0` turns it off
+fn summary_interval() -> chrono::Duration {
+    let minutes = dotenv::var("MONITORING_SUMMARY_MINUTES")
+        .map(|var| var.parse::<i64>())
+        .unwrap_or(Ok(60))
+        .unwrap();
+    chrono::Duration::minutes(minutes)
+}
+
+pub struct Monitoring {
+    registry: Registry,
+    started: DateTime<Utc>,
+    request_duration: HistogramVec,
+    responses: IntCounterVec,
+    relogins: IntCounter,
+    api_errors: IntCounterVec,
+    sink_write_duration: HistogramVec,
+    sink_write_failures: IntCounterVec,
+    buffer_backlog: IntGaugeVec,
+    sample_age: GaugeVec,
+    last_samples: Mutex<HashMap<u32, DateTime<Utc>>>,
+    last_writes: Mutex<HashMap<String, (bool, DateTime<Utc>)>>,
+    reported_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+fn register<T: Collector + Clone + 'static>(registry: &Registry, collector: T) -> Result<T, anyhow::Error> {
+    registry.register(Box::new(collector.clone()))?;
+    Ok(collector)
+}
+
+/// The sample count and sum of each label value of a single label histogram
+fn histogram_totals(histogram: &HistogramVec) -> Vec<(String, u64, f64)> {
+    histogram
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric().iter())
+        .map(|metric| {
+            let label = metric.get_label().first().map(|label| label.get_value().to_string()).unwrap_or_default();
+            let histogram = metric.get_histogram();
+            (label, histogram.get_sample_count(), histogram.get_sample_sum())
+        })
+        .collect()
+}
+
+fn average_ms(count: u64, sum: f64) -> f64 {
+    if count == 0 {
+        return 0.0;
+    }
+    (sum / count as f64 * 1000.0).round()
+}
+
+impl Monitoring {
+    fn new() -> Result<Monitoring, anyhow::Error> {
+        let registry = Registry::new();
+        let buckets = exponential_buckets(0.01, 2.0, 12)?;
+
+        Ok(Monitoring {
+            request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("melcloud_logger_request_duration_seconds", "MELCloud request latency")
+                        .buckets(buckets.clone()),
+                    &["endpoint"],
+                )?,
+            )?,
+            responses: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("melcloud_logger_responses_total", "MELCloud responses by HTTP status, error when none came"),
+                    &["endpoint", "status"],
+                )?,
+            )?,
+            relogins: register(&registry, IntCounter::new("melcloud_logger_relogins_total", "Logins after the token expired")?)?,
+            api_errors: register(
+                &registry,
+                IntCounterVec::new(Opts::new("melcloud_logger_api_errors_total", "MELCloud API errors by kind"), &["kind"])?,
+            )?,
+            sink_write_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("melcloud_logger_sink_write_duration_seconds", "Sink write latency").buckets(buckets),
+                    &["sink"],
+                )?,
+            )?,
+            sink_write_failures: register(
+                &registry,
+                IntCounterVec::new(Opts::new("melcloud_logger_sink_write_failures_total", "Failed sink writes"), &["sink"])?,
+            )?,
+            buffer_backlog: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("melcloud_logger_buffer_backlog", "Snapshots waiting in the write buffer"),
+                    &["sink"],
+                )?,
+            )?,
+            sample_age: register(
+                &registry,
+                GaugeVec::new(
+                    Opts::new("melcloud_logger_sample_age_seconds", "Time since the last successful sample"),
+                    &["device_id"],
+                )?,
+            )?,
+            registry,
+            started: Utc::now(),
+            last_samples: Mutex::new(HashMap::new()),
+            last_writes: Mutex::new(HashMap::new()),
+            reported_at: Mutex::new(None),
+        })
+    }
+
+    /// Also holds the device gauges and whatever else is served on `/metrics`
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn started(&self) -> DateTime<Utc> {
+        self.started
+    }
+
+    /// Installed as the MELCloud client's request observer
+    pub fn observe_request(outcome: &RequestOutcome) {
+        let monitoring = monitoring();
+        let status = outcome.status.map(|status| status.to_string()).unwrap_or_else(|| "error".to_string());
+        monitoring
+            .request_duration
+            .with_label_values(&[outcome.endpoint])
+            .observe(outcome.elapsed.as_secs_f64());
+        monitoring.responses.with_label_values(&[outcome.endpoint, &status]).inc();
+    }
+
+    pub fn record_relogin(&self) {
+        self.relogins.inc();
+    }
+
+    pub fn record_api_error(&self, err: &ApiError) {
+        let kind = match err {
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Other(_) => "other",
+        };
+        self.api_errors.with_label_values(&[kind]).inc();
+    }
+
+    pub fn record_sink_write(&self, sink: &str, elapsed: Duration, success: bool) {
+        self.sink_write_duration.with_label_values(&[sink]).observe(elapsed.as_secs_f64());
+        if !success {
+            self.sink_write_failures.with_label_values(&[sink]).inc();
+        }
+        self.last_writes.lock().unwrap().insert(sink.to_string(), (success, Utc::now()));
+    }
+
+    /// Whether the sink's latest write succeeded and when it was
+    pub fn last_write(&self, sink: &str) -> Option<(bool, DateTime<Utc>)> {
+        self.last_writes.lock().unwrap().get(sink).copied()
+    }
+
+    pub fn backlog(&self, sink: &str) -> i64 {
+        self.buffer_backlog.with_label_values(&[sink]).get()
+    }
+
+    pub fn set_backlog(&self, sink: &str, backlog: usize) {
+        self.buffer_backlog.with_label_values(&[sink]).set(backlog as i64);
+    }
+
+    pub fn record_sample(&self, device_id: u32, now: DateTime<Utc>) {
+        self.last_samples.lock().unwrap().insert(device_id, now);
+    }
+
+    pub fn last_sample(&self, device_id: u32) -> Option<DateTime<Utc>> {
+        self.last_samples.lock().unwrap().get(&device_id).copied()
+    }
+
+    /// Brings the sample ages up to date, called before the metrics are read
+    pub fn update_sample_ages(&self, now: DateTime<Utc>) {
+        for (device_id, time) in self.last_samples.lock().unwrap().iter() {
+            let age = (now - *time).num_milliseconds() as f64 / 1000.0;
+            self.sample_age.with_label_values(&[&device_id.to_string()]).set(age.max(0.0));
+        }
+    }
+
+    pub fn summary(&self, now: DateTime<Utc>) -> String {
+        let mut parts = Vec::new();
+
+        let requests = histogram_totals(&self.request_duration);
+        let count: u64 = requests.iter().map(|(_, count, _)| count).sum();
+        let sum: f64 = requests.iter().map(|(_, _, sum)| sum).sum();
+        let failed: u64 = self
+            .responses
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric().iter())
+            .filter(|metric| metric.get_label().iter().any(|label| label.get_name() == "status" && label.get_value() != "200"))
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum();
+        parts.push(format!("{} request(s) averaging {} ms, {} failed", count, average_ms(count, sum), failed));
+
+        let api_errors: u64 = ["unauthorized", "other"]
+            .iter()
+            .map(|kind| self.api_errors.with_label_values(&[kind]).get())
+            .sum();
+        parts.push(format!("{} relogin(s), {} API error(s)", self.relogins.get(), api_errors));
+
+        for (sink, count, sum) in histogram_totals(&self.sink_write_duration) {
+            parts.push(format!(
+                "{} {} write(s) averaging {} ms, {} failed, {} buffered",
+                sink,
+                count,
+                average_ms(count, sum),
+                self.sink_write_failures.with_label_values(&[&sink]).get(),
+                self.buffer_backlog.with_label_values(&[&sink]).get()
+            ));
+        }
+
+        let mut last_samples: Vec<(u32, DateTime<Utc>)> =
+            self.last_samples.lock().unwrap().iter().map(|(device_id, time)| (*device_id, *time)).collect();
+        last_samples.sort();
+        for (device_id, time) in last_samples {
+            parts.push(format!("device {} sampled {}s ago", device_id, (now - time).num_seconds()));
+        }
+
+        format!("up {}h: {}", (now - self.started).num_hours(), parts.join(", "))
+    }
+
+    /// The summary once per `MONITORING_SUMMARY_MINUTES`
+    pub fn report(&self, now: DateTime<Utc>) -> Option<String> {
+        let interval = summary_interval();
+        if interval <= chrono::Duration::zero() {
+            return None;
+        }
+
+        let mut reported_at = self.reported_at.lock().unwrap();
+        match *reported_at {
+            None => {
+                *reported_at = Some(now);
+                None
+            }
+            Some(time) if now - time < interval => None,
+            Some(_) => {
+                *reported_at = Some(now);
+                Some(self.summary(now))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let monitoring = Monitoring::new().unwrap();
+        let now = Utc::now();
+
+        monitoring.request_duration.with_label_values(&["Device/Get"]).observe(0.2);
+        monitoring.request_duration.with_label_values(&["User/ListDevices"]).observe(0.4);
+        monitoring.responses.with_label_values(&["Device/Get", "200"]).inc();
+        monitoring.responses.with_label_values(&["User/ListDevices", "401"]).inc();
+        monitoring.record_api_error(&ApiError::Unauthorized);
+        monitoring.record_relogin();
+        monitoring.record_sink_write("influx", Duration::from_millis(10), true);
+        monitoring.record_sink_write("influx", Duration::from_millis(30), false);
+        monitoring.set_backlog("influx", 1);
+        monitoring.record_sample(5, now - chrono::Duration::seconds(30));
+
+        assert_eq!(
+            "up 0h: 2 request(s) averaging 300 ms, 1 failed, 1 relogin(s), 1 API error(s), \
+             influx 2 write(s) averaging 20 ms, 1 failed, 1 buffered, device 5 sampled 30s ago",
+            monitoring.summary(now)
+        );
+
+        monitoring.update_sample_ages(now);
+        assert_eq!(30.0, monitoring.sample_age.with_label_values(&["5"]).get());
+    }
+}
@@ -0,0 +1,293 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::app::app::PolledDevice;
+use crate::app::command::{Commander, DeviceCommand, MAX_TEMPERATURE, MIN_TEMPERATURE};
+use crate::app::latest::LatestSnapshots;
+use crate::app::live::{LiveUpdate, LiveUpdates};
+use crate::app::prices::SpotPrices;
+use crate::app::schedule::device_setting;
+
+/// The prices are compared in steps this long, short enough for quarter-hourly prices
+const STEP_MINUTES: i64 = 15;
+/// How far ahead the current price is compared with
+const WINDOW_HOURS: i64 = 24;
+
+const HEAT: u8 = 1;
+const COOL: u8 = 3;
+
+pub fn is_enabled() -> bool {
+    dotenv::var("OPTIMIZER_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn number<T: std::str::FromStr>(name: &str, default: T) -> T
+where
+    T::Err: std::fmt::Debug,
+{
+    dotenv::var(name).map(|var| var.parse::<T>()).unwrap_or(Ok(default)).unwrap()
+}
+
+/// What the optimizer does with the setpoint in a moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plan {
+    Comfort,
+    /// An expensive period starts soon, store heat (or cold) while it's cheap
+    Preheat,
+    /// The price is among the most expensive ones, let the room drift within the band
+    Setback,
+}
+
+/// How many of the hours ahead count as expensive and how early the preheating starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Strategy {
+    pub expensive_hours: i64,
+    pub preheat_hours: i64,
+    /// How much above the cheapest price ahead an expensive one has to be
+    pub min_spread: f64,
+}
+
+impl Strategy {
+    fn from_env() -> Strategy {
+        Strategy {
+            expensive_hours: number("OPTIMIZER_EXPENSIVE_HOURS", 4),
+            preheat_hours: number("OPTIMIZER_PREHEAT_HOURS", 2),
+            min_spread: number("OPTIMIZER_MIN_SPREAD", 0.0),
+        }
+    }
+
+    /// Sets back during the `expensive_hours` most expensive hours of the next day of known
+    /// prices and preheats in the `preheat_hours` before them, `None` without a current price
+    pub fn plan(&self, price_at: impl Fn(DateTime<Utc>) -> Option<f64>, now: DateTime<Utc>) -> Option<Plan> {
+        let step = chrono::Duration::minutes(STEP_MINUTES);
+        let current = price_at(now)?;
+        let mut prices: Vec<f64> = (0..WINDOW_HOURS * 60 / STEP_MINUTES)
+            .filter_map(|index| price_at(now + step * index as i32))
+            .collect();
+        let expensive_steps = (self.expensive_hours * 60 / STEP_MINUTES) as usize;
+        // With only a few prices known, all of them would look expensive
+        if expensive_steps == 0 || prices.len() <= expensive_steps {
+            return Some(Plan::Comfort);
+        }
+        prices.sort_by(|a, b| b.total_cmp(a));
+        let threshold = prices[expensive_steps - 1];
+        let cheapest = prices[prices.len() - 1];
+        let is_expensive = |price: f64| price >= threshold && price - cheapest > self.min_spread;
+
+        if is_expensive(current) {
+            return Some(Plan::Setback);
+        }
+        let preheat = (1..=self.preheat_hours * 60 / STEP_MINUTES)
+            .filter_map(|index| price_at(now + step * index as i32))
+            .any(is_expensive);
+        Some(if preheat { Plan::Preheat } else { Plan::Comfort })
+    }
+}
+
+/// The setpoints of a device, `OPTIMIZER_TEMPERATURE`, `OPTIMIZER_MIN_TEMPERATURE` and
+/// `OPTIMIZER_MAX_TEMPERATURE`, each also by `_DEVICE_<id>` or `_BUILDING_<id>`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComfortBand {
+    pub min: f32,
+    pub comfort: f32,
+    pub max: f32,
+}
+
+impl ComfortBand {
+    fn from_env(device: &PolledDevice) -> Result<ComfortBand, anyhow::Error> {
+        let setting = |name: &str, default: f32| -> Result<f32, anyhow::Error> {
+            match device_setting(name, &device.device_id, &device.building_id).or_else(|| dotenv::var(name).ok()) {
+                Some(value) => Ok(value.parse::<f32>()?),
+                None => Ok(default),
+            }
+        };
+        let band = ComfortBand {
+            min: setting("OPTIMIZER_MIN_TEMPERATURE", 20.0)?,
+            comfort: setting("OPTIMIZER_TEMPERATURE", 21.0)?,
+            max: setting("OPTIMIZER_MAX_TEMPERATURE", 22.0)?,
+        };
+        if !(MIN_TEMPERATURE <= band.min && band.min <= band.comfort && band.comfort <= band.max && band.max <= MAX_TEMPERATURE) {
+            return Err(anyhow::anyhow!("Expected {} <= min <= comfort <= max <= {}, not {:?}", MIN_TEMPERATURE, MAX_TEMPERATURE, band));
+        }
+        Ok(band)
+    }
+
+    /// The setpoint of the plan in the operation mode, `None` in the modes without a setpoint
+    /// to move, cooling stores cold below the comfort setpoint instead
+    pub fn target(&self, plan: Plan, operation_mode: u8) -> Option<f32> {
+        let target = match (operation_mode, plan) {
+            (HEAT | COOL, Plan::Comfort) => self.comfort,
+            (HEAT, Plan::Preheat) | (COOL, Plan::Setback) => self.max,
+            (HEAT, Plan::Setback) | (COOL, Plan::Preheat) => self.min,
+            _ => return None,
+        };
+        // The units take half degrees
+        Some((target * 2.0).round() / 2.0)
+    }
+}
+
+struct OptimizedDevice {
+    device: PolledDevice,
+    band: ComfortBand,
+    /// The last setpoint the optimizer chose, a manual change holds until the plan changes
+    target: Option<f32>,
+}
+
+/// Moves the setpoints of the devices within their comfort bands by the spot prices
+pub struct Optimizer {
+    prices: SpotPrices,
+    strategy: Strategy,
+    dry_run: bool,
+    devices: Vec<OptimizedDevice>,
+    commander: Commander,
+    latest: LatestSnapshots,
+    live: LiveUpdates,
+}
+
+impl Optimizer {
+    pub fn new(
+        prices: SpotPrices,
+        devices: &[PolledDevice],
+        commander: Commander,
+        latest: LatestSnapshots,
+        live: LiveUpdates,
+    ) -> Result<Optimizer, anyhow::Error> {
+        let mut optimized = Vec::new();
+        for device in devices {
+            let band = ComfortBand::from_env(device).map_err(|err| anyhow::anyhow!("Device {}: {}", device.device_id, err))?;
+            info!("Optimizing the setpoint of device {} within {}-{}", device.device_id, band.min, band.max);
+            optimized.push(OptimizedDevice {
+                device: device.clone(),
+                band,
+                target: None,
+            });
+        }
+
+        Ok(Optimizer {
+            prices,
+            strategy: Strategy::from_env(),
+            dry_run: number("OPTIMIZER_DRY_RUN", false),
+            devices: optimized,
+            commander,
+            latest,
+            live,
+        })
+    }
+
+    /// Adjusts the setpoints every `OPTIMIZER_INTERVAL_MINUTES` in the background
+    pub fn start(mut self) {
+        let interval = Duration::from_secs(number("OPTIMIZER_INTERVAL_MINUTES", 15) * 60);
+        if self.dry_run {
+            info!("The optimizer only logs the setpoints it would set");
+        }
+        tokio::spawn(async move {
+            loop {
+                self.adjust(Utc::now()).await;
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+
+    async fn adjust(&mut self, now: DateTime<Utc>) {
+        let prices = &self.prices;
+        let plan = match self.strategy.plan(|time| prices.at(time), now) {
+            Some(plan) => plan,
+            None => {
+                warn!("No spot price for {}, leaving the setpoints as they are", now);
+                return;
+            }
+        };
+
+        let latest = &self.latest;
+        for optimized in self.devices.iter_mut() {
+            let device_id = &optimized.device.device_id;
+            // The polls fill in the devices' state
+            let snapshot = match device_id.parse::<u32>().ok().and_then(|id| latest.get(id)) {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            let target = match optimized.band.target(plan, snapshot.operation_mode) {
+                Some(target) if snapshot.power => target,
+                _ => {
+                    debug!("Not optimizing device {} that is off or not heating or cooling", device_id);
+                    optimized.target = None;
+                    continue;
+                }
+            };
+            if optimized.target == Some(target) || (optimized.target.is_none() && snapshot.set_temperature == target) {
+                optimized.target = Some(target);
+                continue;
+            }
+
+            let price = self.prices.at(now).unwrap_or_default();
+            if self.dry_run {
+                info!(
+                    "Optimizer would set device {} from {} to {} for {:?} at the price {} (dry run)",
+                    device_id, snapshot.set_temperature, target, plan, price
+                );
+                optimized.target = Some(target);
+                continue;
+            }
+            info!(
+                "Optimizer sets device {} from {} to {} for {:?} at the price {}",
+                device_id, snapshot.set_temperature, target, plan, price
+            );
+            let commands = [DeviceCommand::Temperature(target)];
+            match self.commander.run(&optimized.device, &commands, "optimizer", "optimizer").await {
+                Ok(outcome) => {
+                    optimized.target = Some(target);
+                    self.latest.update(&outcome.state);
+                    self.live.publish(LiveUpdate::Snapshot(Box::new(outcome.state)));
+                }
+                // Tried again on the next round
+                Err(err) => error!("Optimizer failed to set the temperature of device {}: {}", device_id, err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::prices::PriceSeries;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_plans_by_the_coming_prices() {
+        let mut csv = "time,price\n".to_string();
+        for hour in 0..24 {
+            let price = if (17..20).contains(&hour) { 0.30 } else { 0.05 + hour as f64 * 0.001 };
+            csv += &format!("2024-01-01T{:02}:00:00Z,{}\n", hour, price);
+        }
+        let series = PriceSeries::from_csv(&csv, 1.0).unwrap();
+        let strategy = Strategy {
+            expensive_hours: 3,
+            preheat_hours: 2,
+            min_spread: 0.01,
+        };
+        let plan = |hour, minute| strategy.plan(|time| series.at(time), Utc.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap());
+
+        assert_eq!(Some(Plan::Comfort), plan(12, 0));
+        assert_eq!(Some(Plan::Preheat), plan(15, 0));
+        assert_eq!(Some(Plan::Preheat), plan(16, 45));
+        assert_eq!(Some(Plan::Setback), plan(17, 0));
+        assert_eq!(Some(Plan::Setback), plan(19, 59));
+        // Only the last hours are known by then
+        assert_eq!(Some(Plan::Comfort), plan(22, 0));
+        let tomorrow = Utc.with_ymd_and_hms(2024, 1, 2, 1, 0, 0).unwrap();
+        assert_eq!(None, strategy.plan(|time| series.at(time), tomorrow));
+
+        let band = ComfortBand {
+            min: 19.0,
+            comfort: 21.0,
+            max: 23.0,
+        };
+        assert_eq!(Some(23.0), band.target(Plan::Preheat, HEAT));
+        assert_eq!(Some(19.0), band.target(Plan::Setback, HEAT));
+        assert_eq!(Some(19.0), band.target(Plan::Preheat, COOL));
+        assert_eq!(None, band.target(Plan::Comfort, 7));
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+/// How long a price holds after its start when no later one follows
+const MAX_SLOT_MINUTES: i64 = 60;
+
+/// A source that hangs must not hold back the first poll or the reloads
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn refresh_interval() -> Duration {
+    let minutes = dotenv::var("SPOT_PRICES_REFRESH_MINUTES")
+        .map(|var| var.parse::<u64>())
+        .unwrap_or(Ok(60))
+        .unwrap();
+    Duration::from_secs(minutes * 60)
+}
+
+/// Multiplies the prices in the source, e.g. `0.001` for prices per MWh
+fn scale() -> f64 {
+    dotenv::var("SPOT_PRICES_SCALE")
+        .map(|var| var.parse::<f64>())
+        .unwrap_or(Ok(1.0))
+        .unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+struct PriceRow {
+    time: DateTime<Utc>,
+    price: f64,
+}
+
+/// Prices per kWh by their start time, such as the hourly or quarter-hourly spot prices
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PriceSeries(BTreeMap<DateTime<Utc>, f64>);
+
+impl PriceSeries {
+    /// Parses CSV with the columns `time` (RFC 3339) and `price`
+    pub fn from_csv(text: &str, scale: f64) -> Result<PriceSeries, anyhow::Error> {
+        let mut prices = BTreeMap::new();
+        for row in ::csv::Reader::from_reader(text.as_bytes()).deserialize::<PriceRow>() {
+            let row = row?;
+            // `NaN` and `inf` parse as numbers, but would poison the costs and the optimizer
+            if !row.price.is_finite() {
+                return Err(anyhow::anyhow!("The price at {} isn't a number: {}", row.time, row.price));
+            }
+            prices.insert(row.time, row.price * scale);
+        }
+
+        Ok(PriceSeries(prices))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The price in effect at the time, `None` outside the series
+    pub fn at(&self, time: DateTime<Utc>) -> Option<f64> {
+        let (start, price) = self.0.range(..=time).next_back()?;
+        (time - *start < chrono::Duration::minutes(MAX_SLOT_MINUTES)).then_some(*price)
+    }
+}
+
+/// `SPOT_PRICES_SOURCE`, a CSV file or an HTTP URL serving one
+#[derive(Debug, Clone, PartialEq)]
+pub enum PriceSource {
+    File(PathBuf),
+    Http(String),
+}
+
+impl PriceSource {
+    pub fn from_env() -> Option<PriceSource> {
+        let source = dotenv::var("SPOT_PRICES_SOURCE").ok()?;
+        if source.starts_with("http://") || source.starts_with("https://") {
+            Some(PriceSource::Http(source))
+        } else {
+            Some(PriceSource::File(PathBuf::from(source)))
+        }
+    }
+
+    pub async fn load(&self) -> Result<PriceSeries, anyhow::Error> {
+        let text = match self {
+            PriceSource::File(path) => tokio::fs::read_to_string(path).await?,
+            PriceSource::Http(url) => {
+                let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+                client.get(url).send().await?.error_for_status()?.text().await?
+            }
+        };
+        PriceSeries::from_csv(&text, scale())
+    }
+}
+
+/// The spot prices, reloaded from the source in the background so that the next day's prices
+/// show up once they are published
+#[derive(Debug, Clone, Default)]
+pub struct SpotPrices {
+    series: Arc<RwLock<PriceSeries>>,
+}
+
+impl SpotPrices {
+    /// Loads the prices once and keeps reloading them, a source that fails keeps the old prices
+    pub async fn start(source: PriceSource) -> SpotPrices {
+        let prices = SpotPrices::default();
+        prices.reload(&source).await;
+
+        let reloaded = prices.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(refresh_interval()).await;
+                reloaded.reload(&source).await;
+            }
+        });
+
+        prices
+    }
+
+    async fn reload(&self, source: &PriceSource) {
+        match source.load().await {
+            Ok(series) => {
+                debug!("Loaded {} spot prices from {:?}", series.len(), source);
+                *self.series.write().unwrap() = series;
+            }
+            Err(err) => error!("Failed to load the spot prices from {:?}: {}", source, err),
+        }
+    }
+
+    pub fn at(&self, time: DateTime<Utc>) -> Option<f64> {
+        self.series.read().unwrap().at(time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parses_and_looks_up_prices() {
+        let csv = "time,price\n2024-01-01T00:00:00Z,52.5\n2024-01-01T01:00:00+00:00,-1.0\n2024-01-01T03:00:00Z,10\n";
+        let prices = PriceSeries::from_csv(csv, 0.5).unwrap();
+        let time = |hour, minute| Utc.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap();
+
+        assert_eq!(3, prices.len());
+        assert_eq!(None, prices.at(time(0, 0) - chrono::Duration::seconds(1)));
+        assert_eq!(Some(26.25), prices.at(time(0, 59)));
+        assert_eq!(Some(-0.5), prices.at(time(1, 30)));
+        // Nothing for the hour in between
+        assert_eq!(None, prices.at(time(2, 15)));
+        assert_eq!(Some(5.0), prices.at(time(3, 0)));
+
+        assert!(PriceSeries::from_csv("time,price\nyesterday,1\n", 1.0).is_err());
+        assert!(PriceSeries::from_csv("time,price\n2024-01-01T00:00:00Z,NaN\n", 1.0).is_err());
+        assert!(PriceSeries::from_csv("time,price\n2024-01-01T00:00:00Z,inf\n", 1.0).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::app::schedule::device_setting;
+
+/// How often the summary of the refresh requests is logged
+const REPORT_INTERVAL_MINUTES: i64 = 60;
+
+/// When to send `Device/RequestRefresh` before reading a device
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefreshPolicy {
+    Always,
+    Never,
+    /// At most once per the given time
+    Every(Duration),
+    /// Only when the latest sample is older than the given time
+    Stale(Duration),
+}
+
+impl FromStr for RefreshPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let minutes = |minutes: &str| -> Result<Duration, anyhow::Error> { Ok(Duration::minutes(minutes.parse::<i64>()?)) };
+
+        match value.split_once(':') {
+            None if value == "always" => Ok(RefreshPolicy::Always),
+            None if value == "never" => Ok(RefreshPolicy::Never),
+            Some(("every", value)) => Ok(RefreshPolicy::Every(minutes(value)?)),
+            Some(("stale", value)) => Ok(RefreshPolicy::Stale(minutes(value)?)),
+            _ => Err(anyhow::anyhow!("Expected always, never, every:<minutes> or stale:<minutes>, not {}", value)),
+        }
+    }
+}
+
+/// Applies the refresh policy of a device and counts how the refresh requests went
+#[derive(Debug)]
+pub struct RefreshTracker {
+    policy: RefreshPolicy,
+    last_refresh: Option<DateTime<Utc>>,
+    last_sample: Option<DateTime<Utc>>,
+    succeeded: u64,
+    rejected: u64,
+    failed: u64,
+    skipped: u64,
+    reported_at: Option<DateTime<Utc>>,
+}
+
+impl RefreshTracker {
+    pub fn new(policy: RefreshPolicy) -> RefreshTracker {
+        RefreshTracker {
+            policy,
+            last_refresh: None,
+            last_sample: None,
+            succeeded: 0,
+            rejected: 0,
+            failed: 0,
+            skipped: 0,
+            reported_at: None,
+        }
+    }
+
+    /// Reads `REFRESH_POLICY_DEVICE_<id>`, `REFRESH_POLICY_BUILDING_<id>` or `REFRESH_POLICY`
+    pub fn from_env(device_id: &str, building_id: &str) -> RefreshTracker {
+        let policy = device_setting("REFRESH_POLICY", device_id, building_id)
+            .or_else(|| dotenv::var("REFRESH_POLICY").ok())
+            .map(|policy| match policy.parse::<RefreshPolicy>() {
+                Ok(policy) => policy,
+                Err(err) => panic!("Invalid refresh policy for device {}: {}", device_id, err),
+            })
+            .unwrap_or(RefreshPolicy::Always);
+
+        RefreshTracker::new(policy)
+    }
+
+    pub fn should_refresh(&self, now: DateTime<Utc>) -> bool {
+        match &self.policy {
+            RefreshPolicy::Always => true,
+            RefreshPolicy::Never => false,
+            RefreshPolicy::Every(interval) => self.last_refresh.is_none_or(|last_refresh| now - last_refresh >= *interval),
+            RefreshPolicy::Stale(age) => self.last_sample.is_none_or(|last_sample| now - last_sample >= *age),
+        }
+    }
+
+    pub fn record_sample(&mut self, time: DateTime<Utc>) {
+        if self.last_sample.is_none_or(|last_sample| time > last_sample) {
+            self.last_sample = Some(time);
+        }
+    }
+
+    pub fn record_skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// `accepted` is what MELCloud answered, `None` when the request failed
+    pub fn record_refresh(&mut self, now: DateTime<Utc>, accepted: Option<bool>) {
+        self.last_refresh = Some(now);
+        match accepted {
+            Some(true) => self.succeeded += 1,
+            Some(false) => self.rejected += 1,
+            None => self.failed += 1,
+        }
+    }
+
+    pub fn success_rate(&self) -> Option<f64> {
+        let requested = self.succeeded + self.rejected + self.failed;
+        if requested == 0 {
+            return None;
+        }
+        Some(self.succeeded as f64 / requested as f64)
+    }
+
+    /// A summary of the refresh requests once per report interval
+    pub fn report(&mut self, now: DateTime<Utc>) -> Option<String> {
+        let reported_at = *self.reported_at.get_or_insert(now);
+        if now - reported_at < Duration::minutes(REPORT_INTERVAL_MINUTES) {
+            return None;
+        }
+        self.reported_at = Some(now);
+
+        Some(format!(
+            "{} succeeded, {} rejected, {} failed, {} skipped, success rate {}",
+            self.succeeded,
+            self.rejected,
+            self.failed,
+            self.skipped,
+            self.success_rate()
+                .map(|rate| format!("{:.1}%", rate * 100.0))
+                .unwrap_or_else(|| "n/a".to_string())
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_policies() {
+        assert_eq!(RefreshPolicy::Every(Duration::minutes(15)), "every:15".parse().unwrap());
+        assert!("sometimes".parse::<RefreshPolicy>().is_err());
+
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+
+        let mut every = RefreshTracker::new("every:15".parse().unwrap());
+        assert!(every.should_refresh(now));
+        every.record_refresh(now, Some(true));
+        assert!(!every.should_refresh(now + Duration::minutes(10)));
+        assert!(every.should_refresh(now + Duration::minutes(15)));
+
+        let mut stale = RefreshTracker::new("stale:10".parse().unwrap());
+        assert!(stale.should_refresh(now));
+        stale.record_sample(now - Duration::minutes(5));
+        assert!(!stale.should_refresh(now));
+        assert!(stale.should_refresh(now + Duration::minutes(5)));
+
+        assert!(!RefreshTracker::new(RefreshPolicy::Never).should_refresh(now));
+    }
+
+    #[test]
+    fn test_success_rate_report() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let mut tracker = RefreshTracker::new(RefreshPolicy::Always);
+        assert_eq!(None, tracker.report(now));
+
+        tracker.record_refresh(now, Some(true));
+        tracker.record_refresh(now, Some(true));
+        tracker.record_refresh(now, Some(false));
+        tracker.record_refresh(now, None);
+        tracker.record_skipped();
+        assert_eq!(Some(0.5), tracker.success_rate());
+
+        assert_eq!(None, tracker.report(now + Duration::minutes(30)));
+        assert_eq!(
+            Some("2 succeeded, 1 rejected, 1 failed, 1 skipped, success rate 50.0%".to_string()),
+            tracker.report(now + Duration::minutes(60))
+        );
+    }
+}
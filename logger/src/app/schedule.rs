@@ -0,0 +1,281 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+use crate::app::cron::Cron;
+use crate::app::snapshot::DeviceSnapshot;
+
+/// How often MELCloud units report on their own
+const DEFAULT_COMMUNICATION_INTERVAL: Duration = Duration::from_secs(300);
+
+pub fn is_adaptive() -> bool {
+    dotenv::var("POLL_ADAPTIVE")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn millis(name: &str, default: u64) -> Duration {
+    let millis = dotenv::var(name)
+        .map(|var| var.parse::<u64>())
+        .unwrap_or(Ok(default))
+        .unwrap();
+    Duration::from_millis(millis)
+}
+
+/// A device specific setting such as `POLL_SCHEDULE_DEVICE_<id>`, falling back to the building's
+pub fn device_setting(name: &str, device_id: &str, building_id: &str) -> Option<String> {
+    dotenv::var(format!("{}_DEVICE_{}", name, device_id))
+        .or_else(|_| dotenv::var(format!("{}_BUILDING_{}", name, building_id)))
+        .ok()
+}
+
+/// Local time ranges such as `22:00-07:00,12:00-13:00`, a range may wrap over midnight
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuietHours(Vec<(NaiveTime, NaiveTime)>);
+
+impl FromStr for QuietHours {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for range in value.split(',') {
+            let (start, end) = range
+                .split_once('-')
+                .ok_or_else(|| anyhow::anyhow!("Expected start-end in {}", range))?;
+            ranges.push((
+                NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+                NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+            ));
+        }
+
+        Ok(QuietHours(ranges))
+    }
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        self.0.iter().any(|(start, end)| {
+            if start <= end {
+                *start <= time && time < *end
+            } else {
+                time >= *start || time < *end
+            }
+        })
+    }
+}
+
+/// Decides how long to wait before polling a device again. A cron schedule sets the poll times
+/// directly; otherwise adaptive polling targets the device's next expected communication, and
+/// without it the fixed interval is used.
+pub struct PollSchedule {
+    timezone: Tz,
+    cron: Option<Cron>,
+    quiet_hours: Option<QuietHours>,
+    adaptive: bool,
+    interval: Duration,
+    margin: Duration,
+    min_interval: Duration,
+    max_interval: Duration,
+    pending_interval: Duration,
+    offline_max_interval: Duration,
+    offline_polls: u32,
+    overdue_polls: u32,
+    communication_interval: Duration,
+    previous_time: Option<DateTime<Utc>>,
+}
+
+impl PollSchedule {
+    /// Reads `POLL_SCHEDULE_*` and `QUIET_HOURS*` for the device, `interval` is the default one
+    pub fn from_env(device_id: &str, building_id: &str, interval: Duration, timezone: Tz) -> PollSchedule {
+        let mut cron = None;
+        let mut interval = interval;
+        if let Some(schedule) = device_setting("POLL_SCHEDULE", device_id, building_id) {
+            match schedule.parse::<u64>() {
+                Ok(millis) => interval = Duration::from_millis(millis),
+                Err(_) => match schedule.parse::<Cron>() {
+                    Ok(schedule) => cron = Some(schedule),
+                    Err(err) => panic!("Invalid poll schedule {} for device {}: {}", schedule, device_id, err),
+                },
+            }
+        }
+
+        let quiet_hours = match device_setting("QUIET_HOURS", device_id, building_id).or_else(|| dotenv::var("QUIET_HOURS").ok()) {
+            None => None,
+            Some(value) if value == "off" => None,
+            Some(value) => match value.parse::<QuietHours>() {
+                Ok(quiet_hours) => Some(quiet_hours),
+                Err(err) => panic!("Invalid quiet hours {} for device {}: {}", value, device_id, err),
+            },
+        };
+
+        PollSchedule {
+            timezone,
+            cron,
+            quiet_hours,
+            adaptive: is_adaptive(),
+            interval,
+            margin: millis("POLL_MARGIN", 15_000),
+            min_interval: millis("POLL_MIN_INTERVAL", 30_000),
+            max_interval: millis("POLL_MAX_INTERVAL", 600_000),
+            pending_interval: millis("POLL_PENDING_INTERVAL", 10_000),
+            offline_max_interval: millis("POLL_OFFLINE_MAX_INTERVAL", 3_600_000),
+            offline_polls: 0,
+            overdue_polls: 0,
+            communication_interval: DEFAULT_COMMUNICATION_INTERVAL,
+            previous_time: None,
+        }
+    }
+
+    /// Whether `request_refresh` should be left out because of the quiet hours
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        self.quiet_hours
+            .as_ref()
+            .is_some_and(|quiet_hours| quiet_hours.contains(now.with_timezone(&self.timezone).time()))
+    }
+
+    /// The delay before the next poll, given the snapshot of this poll if there was one
+    pub fn next_delay(&mut self, snapshot: Option<&DeviceSnapshot>, now: DateTime<Utc>) -> Duration {
+        if let Some(cron) = &self.cron {
+            match cron.next_after(&now.with_timezone(&self.timezone)) {
+                Some(next) => return (next.with_timezone(&Utc) - now).to_std().unwrap_or_default(),
+                None => warn!("Poll schedule never matches, using the interval instead"),
+            }
+        }
+
+        let snapshot = match snapshot {
+            Some(snapshot) if self.adaptive => snapshot,
+            _ => return self.interval,
+        };
+
+        if snapshot.offline {
+            self.offline_polls += 1;
+            let exponent = (self.offline_polls - 1).min(16);
+            let delay = (self.interval * 2u32.pow(exponent)).min(self.offline_max_interval);
+            info!("Device {} is offline, polling again in {}s", snapshot.device_id, delay.as_secs());
+            return delay;
+        }
+        self.offline_polls = 0;
+
+        self.observe(snapshot.time);
+
+        if snapshot.has_pending_command == Some(true) {
+            debug!("Device {} has a pending command", snapshot.device_id);
+            return self.pending_interval;
+        }
+
+        // ListDevices doesn't tell the next communication, so it is estimated from the previous ones
+        let next_communication = snapshot.next_communication.unwrap_or_else(|| {
+            snapshot.time + chrono::Duration::from_std(self.communication_interval).unwrap()
+        });
+        let target = next_communication + chrono::Duration::from_std(self.margin).unwrap();
+
+        // A device that is late to communicate is polled less and less often, as when it's offline
+        let delay = match (target - now).to_std() {
+            Ok(delay) if delay > Duration::from_secs(0) => {
+                self.overdue_polls = 0;
+                delay.max(self.min_interval).min(self.max_interval)
+            }
+            _ => {
+                self.overdue_polls += 1;
+                let exponent = (self.overdue_polls - 1).min(16);
+                (self.min_interval * 2u32.pow(exponent)).min(self.max_interval)
+            }
+        };
+        debug!("Device {} expected to communicate at {}, polling again in {}s", snapshot.device_id, next_communication, delay.as_secs());
+
+        delay
+    }
+
+    fn observe(&mut self, time: DateTime<Utc>) {
+        if let Some(previous_time) = self.previous_time {
+            if let Ok(interval) = (time - previous_time).to_std() {
+                if interval > Duration::from_secs(0) && interval <= self.max_interval {
+                    self.communication_interval = interval;
+                }
+            }
+        }
+        if self.previous_time.is_none_or(|previous_time| time > previous_time) {
+            self.previous_time = Some(time);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use chrono_tz::Europe::Helsinki;
+
+    fn schedule() -> PollSchedule {
+        PollSchedule {
+            timezone: Helsinki,
+            cron: None,
+            quiet_hours: None,
+            adaptive: true,
+            interval: Duration::from_secs(60),
+            margin: Duration::from_secs(15),
+            min_interval: Duration::from_secs(30),
+            max_interval: Duration::from_secs(600),
+            pending_interval: Duration::from_secs(10),
+            offline_max_interval: Duration::from_secs(3600),
+            offline_polls: 0,
+            overdue_polls: 0,
+            communication_interval: DEFAULT_COMMUNICATION_INTERVAL,
+            previous_time: None,
+        }
+    }
+
+    #[test]
+    fn test_targets_next_communication() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let mut schedule = schedule();
+
+        let mut snapshot = DeviceSnapshot::for_test(1, time);
+        snapshot.next_communication = Some(time + chrono::Duration::seconds(120));
+        assert_eq!(Duration::from_secs(95), schedule.next_delay(Some(&snapshot), time + chrono::Duration::seconds(40)));
+
+        // Overdue devices are polled again after the minimum interval, then less often
+        let overdue = time + chrono::Duration::seconds(200);
+        let delays: Vec<u64> = (0..7).map(|_| schedule.next_delay(Some(&snapshot), overdue).as_secs()).collect();
+        assert_eq!(vec![30, 60, 120, 240, 480, 600, 600], delays);
+        assert_eq!(Duration::from_secs(95), schedule.next_delay(Some(&snapshot), time + chrono::Duration::seconds(40)));
+
+        snapshot.has_pending_command = Some(true);
+        assert_eq!(Duration::from_secs(10), schedule.next_delay(Some(&snapshot), time));
+    }
+
+    #[test]
+    fn test_estimates_interval_and_backs_off_offline() {
+        let time = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let mut schedule = schedule();
+
+        schedule.next_delay(Some(&DeviceSnapshot::for_test(1, time)), time);
+        let next = time + chrono::Duration::seconds(180);
+        assert_eq!(Duration::from_secs(195), schedule.next_delay(Some(&DeviceSnapshot::for_test(1, next)), next));
+
+        let mut offline = DeviceSnapshot::for_test(1, next);
+        offline.offline = true;
+        let delays: Vec<u64> = (0..8).map(|_| schedule.next_delay(Some(&offline), next).as_secs()).collect();
+        assert_eq!(vec![60, 120, 240, 480, 960, 1920, 3600, 3600], delays);
+
+        assert_eq!(Duration::from_secs(60), schedule.next_delay(None, next));
+    }
+
+    #[test]
+    fn test_cron_and_quiet_hours() {
+        let mut schedule = schedule();
+        schedule.cron = Some("0 */2 * * *".parse().unwrap());
+        schedule.quiet_hours = Some("22:00-07:00, 12:00-12:30".parse().unwrap());
+
+        // 11:15 in Helsinki
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 9, 15, 0).unwrap();
+        assert_eq!(Duration::from_secs(45 * 60), schedule.next_delay(None, now));
+        assert!(!schedule.is_quiet(now));
+        assert!(schedule.is_quiet(now + chrono::Duration::minutes(50)));
+        assert!(schedule.is_quiet(Utc.with_ymd_and_hms(2024, 1, 1, 3, 0, 0).unwrap()));
+        assert!(!schedule.is_quiet(Utc.with_ymd_and_hms(2024, 1, 1, 5, 0, 0).unwrap()));
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use crate::app::app::get_access_token;
+use crate::app::monitoring::monitoring;
+
+/// The MELCloud access token shared by the polling loop and the device commands
+#[derive(Clone)]
+pub struct Session {
+    token: Arc<RwLock<String>>,
+}
+
+impl Session {
+    pub fn new(token: String) -> Session {
+        Session {
+            token: Arc::new(RwLock::new(token)),
+        }
+    }
+
+    pub async fn token(&self) -> String {
+        self.token.read().await.clone()
+    }
+
+    /// A failed login leaves the token empty until the next attempt succeeds
+    pub async fn is_logged_in(&self) -> bool {
+        !self.token.read().await.is_empty()
+    }
+
+    /// Logs in again unless someone else already replaced the expired token
+    pub async fn relogin(&self, expired: &str) -> String {
+        let mut token = self.token.write().await;
+        if *token == expired {
+            monitoring().record_relogin();
+            *token = get_access_token().await;
+        }
+        token.clone()
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// How long the polls and writes in progress and the flushing of the sinks may take after a
+/// signal, `SHUTDOWN_TIMEOUT_SECONDS`
+pub fn timeout() -> Duration {
+    let seconds = dotenv::var("SHUTDOWN_TIMEOUT_SECONDS")
+        .map(|var| var.parse::<u64>())
+        .unwrap_or(Ok(8))
+        .unwrap();
+    Duration::from_secs(seconds)
+}
+
+#[cfg(unix)]
+async fn signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> &'static str {
+    tokio::signal::ctrl_c().await.unwrap();
+    "Ctrl-C"
+}
+
+/// The deadline of the shutdown once SIGTERM or SIGINT has arrived
+#[derive(Clone)]
+pub struct Shutdown {
+    deadline: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn listen() -> Shutdown {
+        let (sender, deadline) = watch::channel(None);
+        let timeout = timeout();
+        tokio::spawn(async move {
+            let signal = signal().await;
+            info!("Received {}, shutting down within {}s", signal, timeout.as_secs());
+            let _ = sender.send(Some(Instant::now() + timeout));
+        });
+
+        Shutdown { deadline }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.borrow()
+    }
+
+    /// Waits for the signal, returns the deadline
+    pub async fn requested(&self) -> Instant {
+        let mut deadline = self.deadline.clone();
+        let requested = deadline.wait_for(Option::is_some).await.map(|deadline| deadline.unwrap());
+        match requested {
+            Ok(deadline) => deadline,
+            // The listener never stops before sending
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use api::{CurrentDataResponse, ListDevicesResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::app::energy::EnergyDeltas;
+
+/// One sample of a device, independent of which MELCloud endpoint it came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+    pub time: DateTime<Utc>,
+    pub device_id: u32,
+    pub device_name: Option<String>,
+    pub building_id: Option<u32>,
+    pub building_name: Option<String>,
+
+    pub device_type: u8,
+    pub power: bool,
+    pub offline: bool,
+    pub room_temperature: f32,
+    pub set_temperature: f32,
+    pub last_communication: String,
+    pub next_communication: Option<DateTime<Utc>>,
+    pub has_pending_command: Option<bool>,
+
+    pub actual_fan_speed: u8,
+    pub fan_speed: u8,
+    pub automatic_fan_speed: Option<bool>,
+    pub vane_vertical_direction: u8,
+    pub vane_vertical_swing: Option<bool>,
+    pub vane_horizontal_direction: u8,
+    pub vane_horizontal_swing: Option<bool>,
+    pub operation_mode: u8,
+    pub in_standby_mode: bool,
+
+    pub heating_energy_consumed_rate1: Option<f32>,
+    pub heating_energy_consumed_rate2: Option<f32>,
+    pub cooling_energy_consumed_rate1: Option<f32>,
+    pub cooling_energy_consumed_rate2: Option<f32>,
+    pub auto_energy_consumed_rate1: Option<f32>,
+    pub auto_energy_consumed_rate2: Option<f32>,
+    pub dry_energy_consumed_rate1: Option<f32>,
+    pub dry_energy_consumed_rate2: Option<f32>,
+    pub fan_energy_consumed_rate1: Option<f32>,
+    pub fan_energy_consumed_rate2: Option<f32>,
+    pub other_energy_consumed_rate1: Option<f32>,
+    pub other_energy_consumed_rate2: Option<f32>,
+
+    pub current_energy_consumed: Option<f32>,
+    pub current_energy_mode: Option<u8>,
+    pub energy_correction_model: Option<f32>,
+    pub energy_correction_active: Option<bool>,
+
+    pub wifi_signal_strength: Option<f32>,
+    pub wifi_adapter_status: Option<String>,
+
+    pub has_error: Option<bool>,
+
+    /// Filled in by the logger for the samples it writes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy: Option<EnergyDeltas>,
+}
+
+impl DeviceSnapshot {
+    /// Picks the device from the list devices response, falling back to the first device
+    pub fn from_list_devices(data: &[ListDevicesResponse], device_id: &str) -> Result<DeviceSnapshot, anyhow::Error> {
+        let entry = data
+            .iter()
+            .flat_map(|list_devices| list_devices.structure.devices.iter())
+            .find(|entry| entry.device_iD.to_string() == device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {} is missing from the device list", device_id))?;
+        let device = &entry.device;
+
+        let system_time = device
+            .last_time_stamp_to_utc_datetime()
+            .ok_or_else(|| anyhow::anyhow!("Skipping logging because system time couldn't be parsed"))?;
+        info!("System Time UTC: {:?}", system_time);
+
+        Ok(DeviceSnapshot {
+            time: system_time,
+            device_id: device.device_iD,
+            device_name: entry.device_name.clone(),
+            building_id: Some(entry.building_iD),
+            building_name: entry.building_name.clone(),
+
+            device_type: device.device_type,
+            power: device.power,
+            offline: device.offline,
+            room_temperature: device.room_temperature,
+            set_temperature: device.set_temperature,
+            last_communication: device.last_time_stamp.clone(),
+            next_communication: None,
+            has_pending_command: device.has_pending_command,
+
+            actual_fan_speed: device.actual_fan_speed,
+            fan_speed: device.fan_speed,
+            automatic_fan_speed: device.automatic_fan_speed,
+            vane_vertical_direction: device.vane_vertical_direction,
+            vane_vertical_swing: device.vane_vertical_swing,
+            vane_horizontal_direction: device.vane_horizontal_direction,
+            vane_horizontal_swing: device.vane_horizontal_swing,
+            operation_mode: device.operation_mode,
+            in_standby_mode: device.in_standby_mode,
+
+            heating_energy_consumed_rate1: device.heating_energy_consumed_rate1,
+            heating_energy_consumed_rate2: device.heating_energy_consumed_rate2,
+            cooling_energy_consumed_rate1: device.cooling_energy_consumed_rate1,
+            cooling_energy_consumed_rate2: device.cooling_energy_consumed_rate2,
+            auto_energy_consumed_rate1: device.auto_energy_consumed_rate1,
+            auto_energy_consumed_rate2: device.auto_energy_consumed_rate2,
+            dry_energy_consumed_rate1: device.dry_energy_consumed_rate1,
+            dry_energy_consumed_rate2: device.dry_energy_consumed_rate2,
+            fan_energy_consumed_rate1: device.fan_energy_consumed_rate1,
+            fan_energy_consumed_rate2: device.fan_energy_consumed_rate2,
+            other_energy_consumed_rate1: device.other_energy_consumed_rate1,
+            other_energy_consumed_rate2: device.other_energy_consumed_rate2,
+
+            current_energy_consumed: device.current_energy_consumed,
+            current_energy_mode: device.current_energy_mode,
+            energy_correction_model: device.energy_correction_model,
+            energy_correction_active: device.energy_correction_active,
+
+            wifi_signal_strength: device.wifi_signal_strength,
+            wifi_adapter_status: device.wifi_adapter_status.clone(),
+
+            has_error: device.has_error,
+
+            energy: None,
+        })
+    }
+
+    pub fn from_current_data(data: &CurrentDataResponse, building_id: &str) -> Result<DeviceSnapshot, anyhow::Error> {
+        let system_time = data
+            .last_communication_to_utc_datetime()
+            .ok_or_else(|| anyhow::anyhow!("Skipping logging because system time couldn't be parsed"))?;
+        info!("System Time UTC: {:?}", system_time);
+
+        Ok(DeviceSnapshot {
+            time: system_time,
+            device_id: data.device_iD,
+            device_name: None,
+            building_id: building_id.parse().ok(),
+            building_name: None,
+
+            device_type: data.device_type,
+            power: data.power,
+            offline: data.offline,
+            room_temperature: data.room_temperature,
+            set_temperature: data.set_temperature,
+            last_communication: data.last_communication.clone(),
+            next_communication: data.next_communication_to_utc_datetime(),
+            has_pending_command: Some(data.has_pending_command),
+
+            actual_fan_speed: data.set_fan_speed,
+            fan_speed: data.set_fan_speed,
+            automatic_fan_speed: None,
+            vane_vertical_direction: data.vane_vertical,
+            vane_vertical_swing: None,
+            vane_horizontal_direction: data.vane_horizontal,
+            vane_horizontal_swing: None,
+            operation_mode: data.operation_mode,
+            in_standby_mode: data.in_standby_mode,
+
+            heating_energy_consumed_rate1: None,
+            heating_energy_consumed_rate2: None,
+            cooling_energy_consumed_rate1: None,
+            cooling_energy_consumed_rate2: None,
+            auto_energy_consumed_rate1: None,
+            auto_energy_consumed_rate2: None,
+            dry_energy_consumed_rate1: None,
+            dry_energy_consumed_rate2: None,
+            fan_energy_consumed_rate1: None,
+            fan_energy_consumed_rate2: None,
+            other_energy_consumed_rate1: None,
+            other_energy_consumed_rate2: None,
+
+            current_energy_consumed: None,
+            current_energy_mode: None,
+            energy_correction_model: None,
+            energy_correction_active: None,
+
+            wifi_signal_strength: None,
+            wifi_adapter_status: None,
+
+            has_error: None,
+
+            energy: None,
+        })
+    }
+}
+
+#[cfg(test)]
+impl DeviceSnapshot {
+    pub fn for_test(device_id: u32, time: DateTime<Utc>) -> DeviceSnapshot {
+        DeviceSnapshot {
+            time,
+            device_id,
+            device_name: Some(format!("Device {}", device_id)),
+            building_id: Some(1),
+            building_name: Some("Home".to_string()),
+
+            device_type: 0,
+            power: true,
+            offline: false,
+            room_temperature: 21.0,
+            set_temperature: 21.0,
+            last_communication: time.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            next_communication: None,
+            has_pending_command: Some(false),
+
+            actual_fan_speed: 0,
+            fan_speed: 0,
+            automatic_fan_speed: Some(true),
+            vane_vertical_direction: 0,
+            vane_vertical_swing: Some(false),
+            vane_horizontal_direction: 0,
+            vane_horizontal_swing: Some(false),
+            operation_mode: 1,
+            in_standby_mode: false,
+
+            heating_energy_consumed_rate1: None,
+            heating_energy_consumed_rate2: None,
+            cooling_energy_consumed_rate1: None,
+            cooling_energy_consumed_rate2: None,
+            auto_energy_consumed_rate1: None,
+            auto_energy_consumed_rate2: None,
+            dry_energy_consumed_rate1: None,
+            dry_energy_consumed_rate2: None,
+            fan_energy_consumed_rate1: None,
+            fan_energy_consumed_rate2: None,
+            other_energy_consumed_rate1: None,
+            other_energy_consumed_rate2: None,
+
+            current_energy_consumed: None,
+            current_energy_mode: None,
+            energy_correction_model: None,
+            energy_correction_active: None,
+
+            wifi_signal_strength: Some(-60.0),
+            wifi_adapter_status: Some("NORMAL".to_string()),
+
+            has_error: Some(false),
+
+            energy: None,
+        }
+    }
+}
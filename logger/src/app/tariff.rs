@@ -0,0 +1,199 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+use crate::app::prices::SpotPrices;
+use crate::app::schedule::QuietHours;
+
+/// The tariffs change at most every quarter of an hour, the energy of an interval is priced in
+/// pieces that short
+const SLOT_SECONDS: i64 = 15 * 60;
+
+pub fn is_enabled() -> bool {
+    dotenv::var("TARIFF_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn price(name: &str) -> Option<f64> {
+    dotenv::var(name).ok().map(|var| var.parse::<f64>().unwrap())
+}
+
+/// A price per kWh that can be different at night and on weekends, the night price wins
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TimeOfUse {
+    pub base: f64,
+    pub night: Option<f64>,
+    pub weekend: Option<f64>,
+}
+
+impl TimeOfUse {
+    /// `<prefix>_PRICE`, `<prefix>_NIGHT_PRICE` and `<prefix>_WEEKEND_PRICE`
+    fn from_env(prefix: &str) -> TimeOfUse {
+        TimeOfUse {
+            base: price(&format!("{}_PRICE", prefix)).unwrap_or(0.0),
+            night: price(&format!("{}_NIGHT_PRICE", prefix)),
+            weekend: price(&format!("{}_WEEKEND_PRICE", prefix)),
+        }
+    }
+
+    fn at(&self, night: bool, weekend: bool) -> f64 {
+        match (night, weekend) {
+            (true, _) if self.night.is_some() => self.night.unwrap(),
+            (_, true) if self.weekend.is_some() => self.weekend.unwrap(),
+            _ => self.base,
+        }
+    }
+}
+
+/// What an interval's energy cost, with VAT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cost {
+    /// The energy, the transfer and the share of the monthly fees
+    pub total: f64,
+    /// The average price per kWh of the energy and its transfer
+    pub price: f64,
+}
+
+/// Prices the energy, the prices are without VAT and in the same currency, such as EUR per kWh
+#[derive(Debug, Clone)]
+pub struct Tariff {
+    /// The price of the energy, or the margin on top of the spot price
+    pub energy: TimeOfUse,
+    pub transfer: TimeOfUse,
+    pub night_hours: QuietHours,
+    /// Each device's share of the monthly fees, spread over the month
+    pub monthly_fee: f64,
+    /// In percent
+    pub vat: f64,
+    pub timezone: Tz,
+    pub spot: Option<SpotPrices>,
+}
+
+impl Tariff {
+    /// `spot` are the prices of a spot price contract, `TARIFF_ENERGY_PRICE` is then the margin.
+    /// `devices` share the monthly fees evenly.
+    pub fn from_env(timezone: Tz, spot: Option<SpotPrices>, devices: usize) -> Tariff {
+        Tariff {
+            energy: TimeOfUse::from_env("TARIFF_ENERGY"),
+            transfer: TimeOfUse::from_env("TARIFF_TRANSFER"),
+            night_hours: dotenv::var("TARIFF_NIGHT_HOURS")
+                .unwrap_or_else(|_| "22:00-07:00".to_string())
+                .parse()
+                .unwrap(),
+            monthly_fee: price("TARIFF_MONTHLY_FEE").unwrap_or(0.0) / devices.max(1) as f64,
+            vat: price("TARIFF_VAT").unwrap_or(0.0),
+            timezone,
+            spot,
+        }
+    }
+
+    /// The price per kWh of the energy and its transfer without VAT, `None` without a spot price
+    pub fn unit_price(&self, time: DateTime<Utc>) -> Option<f64> {
+        let local = time.with_timezone(&self.timezone);
+        let night = self.night_hours.contains(local.time());
+        let weekend = matches!(local.weekday(), Weekday::Sat | Weekday::Sun);
+
+        let spot = match &self.spot {
+            Some(spot) => spot.at(time)?,
+            None => 0.0,
+        };
+        Some(spot + self.energy.at(night, weekend) + self.transfer.at(night, weekend))
+    }
+
+    fn month_seconds(&self, time: DateTime<Utc>) -> f64 {
+        let local = time.with_timezone(&self.timezone);
+        let first = NaiveDate::from_ymd_opt(local.year(), local.month(), 1).unwrap();
+        let next = first.checked_add_months(chrono::Months::new(1)).unwrap();
+        (next - first).num_seconds() as f64
+    }
+
+    fn with_vat(&self, amount: f64) -> f64 {
+        amount * (1.0 + self.vat / 100.0)
+    }
+
+    /// Prices the kWh used in the seconds before `end`, spreading them evenly over the interval
+    pub fn cost(&self, end: DateTime<Utc>, seconds: i64, kwh: f64) -> Option<Cost> {
+        if seconds <= 0 {
+            let price = self.with_vat(self.unit_price(end)?);
+            return Some(Cost { total: price * kwh, price });
+        }
+
+        let mut energy = 0.0;
+        let mut fees = 0.0;
+        let mut time = end - Duration::seconds(seconds);
+        while time < end {
+            let slot_end = Utc
+                .timestamp_opt((time.timestamp() / SLOT_SECONDS + 1) * SLOT_SECONDS, 0)
+                .unwrap()
+                .min(end);
+            let share = (slot_end - time).num_milliseconds() as f64 / (seconds * 1000) as f64;
+            energy += kwh * share * self.unit_price(time)?;
+            fees += self.monthly_fee * (slot_end - time).num_milliseconds() as f64 / 1000.0 / self.month_seconds(time);
+            time = slot_end;
+        }
+
+        let price = if kwh > 0.0 {
+            self.with_vat(energy / kwh)
+        } else {
+            self.with_vat(self.unit_price(end)?)
+        };
+        Some(Cost {
+            total: self.with_vat(energy + fees),
+            price,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tariff() -> Tariff {
+        Tariff {
+            energy: TimeOfUse {
+                base: 0.10,
+                night: Some(0.06),
+                weekend: Some(0.08),
+            },
+            transfer: TimeOfUse {
+                base: 0.04,
+                night: None,
+                weekend: None,
+            },
+            night_hours: "22:00-07:00".parse().unwrap(),
+            monthly_fee: 0.0,
+            vat: 25.0,
+            timezone: chrono_tz::Europe::Helsinki,
+            spot: None,
+        }
+    }
+
+    #[test]
+    fn test_prices_by_time_of_use() {
+        let tariff = tariff();
+        // Monday 2024-01-01 is a holiday but still a weekday, Helsinki is UTC+2 in January
+        let monday_noon = Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap();
+        let saturday_noon = Utc.with_ymd_and_hms(2024, 1, 6, 10, 0, 0).unwrap();
+        let saturday_night = Utc.with_ymd_and_hms(2024, 1, 6, 21, 0, 0).unwrap();
+
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(0.14, tariff.unit_price(monday_noon).unwrap()));
+        assert!(close(0.12, tariff.unit_price(saturday_noon).unwrap()));
+        assert!(close(0.10, tariff.unit_price(saturday_night).unwrap()));
+
+        // Half of the kWh before 22:00 local time and half after it
+        let cost = tariff.cost(Utc.with_ymd_and_hms(2024, 1, 1, 20, 10, 0).unwrap(), 20 * 60, 2.0).unwrap();
+        assert!(close((0.14 + 0.10) * 1.25, cost.total));
+        assert!(close((0.14 + 0.10) / 2.0 * 1.25, cost.price));
+
+        // A 31 day month of fees over a day
+        let tariff = Tariff {
+            monthly_fee: 31.0,
+            vat: 0.0,
+            ..tariff
+        };
+        let cost = tariff.cost(monday_noon, 24 * 60 * 60, 0.0).unwrap();
+        assert!(close(1.0, cost.total));
+    }
+}
@@ -1,4 +1,4 @@
-use flexi_logger::{Level, style, Criterion, Age, Naming, Cleanup};
+use flexi_logger::{Level, style, Criterion, Age, Naming, Cleanup, LoggerHandle};
 
 fn reduced_colored_format(
     w: &mut dyn std::io::Write,
@@ -68,7 +68,8 @@ fn file_format(
     )
 }
 
-pub fn init_logging() {
+/// The handle has to be shut down at the end to flush the log files
+pub fn init_logging() -> LoggerHandle {
     let handle = flexi_logger::Logger::with_env_or_str("info")
         .print_message()
         .log_to_file()
@@ -88,9 +89,12 @@ pub fn init_logging() {
         .directory("logs")
         .start().unwrap();
 
-    // also log panics
+    // also log panics, flushing since the release build aborts right after the hook
+    let panic_handle = handle.clone();
     std::panic::set_hook(Box::new(move |panic_info| {
         error!(target: "PANIC", "{}", panic_info);
-        handle.shutdown();
+        panic_handle.flush();
     }));
+
+    handle
 }
\ No newline at end of file
@@ -2,19 +2,22 @@
 extern crate log;
 
 use api::errors::ApiError;
+use chrono::{DateTime, Utc};
 use chrono_tz::Tz;
 use std::time::Duration;
 
 use dotenv::dotenv;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout_at};
 
 use crate::{
-    app::app::{fetch_and_log_new_entry, get_access_token, refresh_device, get_device},
-    storage::{influxdb::influx::{self}, timescaledb::timescale::{self}},
+    app::{app::{fetch_and_log_new_entries, get_access_token, get_devices, refresh_device, PolledDevice}, command::Commander, dedup::SampleTracker, events::EventDetector, latest::LatestSnapshots, live::{LiveUpdate, LiveUpdates}, monitoring::{monitoring, Monitoring}, optimizer::{self, Optimizer}, prices::{PriceSource, SpotPrices}, refresh::RefreshTracker, schedule::PollSchedule, snapshot::DeviceSnapshot, session::Session, shutdown::Shutdown, tariff::{self, Tariff}},
+    server::{auth::ApiKeys, health, server::{self as http, ServerState}},
+    storage::{buffer::BufferedSink, files::csv, influxdb::influx::{self}, mqtt::client as mqtt, prometheus::exporter::PrometheusSink, sqlite::sqlite, timescaledb::{pool::TimescalePool, timescale::{self}}},
 };
 
 mod app;
 mod logging;
+mod server;
 mod storage;
 
 fn validate_configs() {
@@ -27,17 +30,146 @@ fn get_timezone() -> Tz {
     timezone.parse().unwrap()
 }
 
-#[tokio::main]
-async fn main() {
-    dotenv().ok();
+/// Polling state of a single device
+struct DevicePoll {
+    device: PolledDevice,
+    schedule: PollSchedule,
+    refresh: RefreshTracker,
+    next_poll: DateTime<Utc>,
+}
 
-    logging::init_logging();
+async fn migrate() -> i32 {
+    let result = match TimescalePool::new(&timescale::connection_string()) {
+        Ok(pool) => pool.migrate().await,
+        Err(err) => Err(err),
+    };
 
-    info!("MELCloud Logger starting");
-    info!("Using time zone: {}", get_timezone().name());
+    match result {
+        Ok(_) => 0,
+        Err(err) => {
+            error!("Failed to migrate timescale database: {}", err);
+            1
+        }
+    }
+}
+
+/// What the polls remember and share besides the sinks
+struct PollState {
+    tracker: SampleTracker,
+    events: EventDetector,
+    latest: LatestSnapshots,
+    live: LiveUpdates,
+}
+
+impl PollState {
+    /// Keeps the new sample and stores and streams the changes since the previous one
+    async fn record(&mut self, sinks: &[BufferedSink], snapshot: &DeviceSnapshot) {
+        self.latest.update(snapshot);
+        self.live.publish(LiveUpdate::Snapshot(Box::new(snapshot.clone())));
 
+        let events = self.events.detect(snapshot);
+        if events.is_empty() {
+            return;
+        }
+        for event in events.iter() {
+            info!("Device {}: {}", event.device_id, event.description());
+        }
+        for sink in sinks {
+            sink.write_events(&events).await;
+        }
+        for event in events {
+            self.live.publish(LiveUpdate::Event(event));
+        }
+    }
+}
+
+/// Refreshes and reads the devices that are due, and schedules their next polls
+async fn poll_due_devices(polls: &mut [DevicePoll], sinks: &[BufferedSink], state: &mut PollState, session: &Session, fetch_interval: u64) {
+    let now = Utc::now();
+    let due: Vec<usize> = (0..polls.len()).filter(|&index| polls[index].next_poll <= now).collect();
+    let devices: Vec<PolledDevice> = due.iter().map(|&index| polls[index].device.clone()).collect();
+    let mut access_token = session.token().await;
+
+    let mut refreshed = false;
+    for &index in due.iter() {
+        let poll = &mut polls[index];
+        let device_id = poll.device.device_id.clone();
+        if poll.schedule.is_quiet(now) {
+            debug!("Not refreshing the device {} during quiet hours", device_id);
+            poll.refresh.record_skipped();
+            continue;
+        }
+        if !poll.refresh.should_refresh(now) {
+            debug!("Not refreshing the device {} because of the refresh policy", device_id);
+            poll.refresh.record_skipped();
+            continue;
+        }
+        refreshed = true;
+
+        let accepted = match refresh_device(access_token.to_string(), device_id.clone()).await {
+            Err(ApiError::Unauthorized) => {
+                error!("Failed to request a device refresh because of unauthorized");
+                // Fetch a new access token and try refreshing again
+                access_token = session.relogin(&access_token).await;
+                refresh_device(access_token.to_string(), device_id).await.ok()
+            }
+            Err(ApiError::Other(err)) => {
+                error!("Failed to request a device refresh {}", err);
+                None
+            }
+            Ok(accepted) => Some(accepted),
+        };
+        poll.refresh.record_refresh(now, accepted);
+    }
+    if refreshed {
+        sleep(Duration::from_millis(fetch_interval)).await;
+    }
+
+    let snapshots = match fetch_and_log_new_entries(sinks, &mut state.tracker, access_token.to_string(), &devices).await {
+        Err(ApiError::Unauthorized) => {
+            error!("Failed to request new entries because of unauthorized");
+            // Fetch a new access token and try fetching again
+            access_token = session.relogin(&access_token).await;
+            fetch_and_log_new_entries(sinks, &mut state.tracker, access_token.to_string(), &devices)
+                .await
+                .unwrap_or_else(|_| vec![None; devices.len()])
+        }
+        Err(ApiError::Other(err)) => {
+            error!("Failed to request new entries {}", err);
+            vec![None; devices.len()]
+        }
+        Ok(snapshots) => snapshots,
+    };
+
+    let now = Utc::now();
+    for (&index, snapshot) in due.iter().zip(snapshots) {
+        let poll = &mut polls[index];
+        if let Some(snapshot) = &snapshot {
+            poll.refresh.record_sample(snapshot.time);
+            state.record(sinks, snapshot).await;
+        }
+        if let Some(report) = poll.refresh.report(now) {
+            info!("Refresh requests for device {}: {}", poll.device.device_id, report);
+        }
+        poll.next_poll = now + chrono::Duration::from_std(poll.schedule.next_delay(snapshot.as_ref(), now)).unwrap();
+    }
+}
+
+/// What the logging loop works with once the logger is up
+struct Startup {
+    session: Session,
+    sinks: Vec<BufferedSink>,
+    state: PollState,
+    polls: Vec<DevicePoll>,
+    fetch_interval: u64,
+}
+
+/// Logs in, connects the sinks and starts the servers and background tasks
+async fn start() -> Startup {
     validate_configs();
 
+    api::observer::set_observer(Monitoring::observe_request);
+
     let refresh_interval: u64 = dotenv::var("REFRESH_INTERVAL")
         .map(|var| var.parse::<u64>())
         .unwrap_or(Ok(60_000))
@@ -53,74 +185,256 @@ async fn main() {
     if access_token.is_none() {
         access_token = Some(get_access_token().await);
     }
-    let mut access_token = access_token.unwrap();
+    let session = Session::new(access_token.unwrap());
 
     // Get the device information
-    let (building_id, device) = get_device(access_token.to_string()).await.unwrap();
-    let building_id = dotenv::var("BUILDING_ID").unwrap_or_else(|_| building_id.to_string());
-    let device_id = dotenv::var("DEVICE_ID").unwrap_or_else(|_| device.device_iD.to_string());
+    let devices = get_devices(session.token().await).await.unwrap();
+
+    let mut sinks: Vec<BufferedSink> = Vec::new();
 
     // Connect to influx database
-    let mut influx_client: Option<influxdb::Client> = None;
     if influx::is_enabled() {
-        influx_client = Some(influx::connect_to_db().await);
+        match influx::connect_to_db().await {
+            Ok(client) => {
+                sinks.push(BufferedSink::new(Box::new(client)));
+            }
+            Err(err) => {
+                error!("Failed to configure influx database: {}", err);
+            }
+        }
     }
 
     // Connect to timescale database
-    let mut timescale_client: Option<tokio_postgres::Client> = None;
+    let mut timescale_pool = None;
     if timescale::is_enabled() {
         match timescale::connect_to_db().await {
+            Ok(pool) => {
+                timescale_pool = Some(pool.clone());
+                sinks.push(BufferedSink::new(Box::new(pool)));
+            }
+            Err(err) => {
+                error!("Failed to configure timescale database: {}", err);
+            }
+        }
+    }
+
+    let commander = Commander::new(session.clone(), timescale_pool.clone());
+
+    // Write csv and parquet files
+    if csv::is_enabled() {
+        match csv::connect() {
+            Ok(sink) => {
+                sinks.push(BufferedSink::new(Box::new(sink)));
+            }
+            Err(err) => {
+                error!("Failed to configure csv files: {}", err);
+            }
+        }
+    }
+
+    // Publish into mqtt broker
+    if mqtt::is_enabled() {
+        match mqtt::connect(commander.clone(), devices.clone()) {
             Ok(client) => {
-                timescale_client = Some(client);
+                sinks.push(BufferedSink::new(Box::new(client)));
             }
             Err(err) => {
-                error!("Failed to connect to timescale database: {}", err);
+                error!("Failed to configure mqtt: {}", err);
             }
         }
     }
 
-    // Logging loop
-    loop {
-        match refresh_device(access_token.to_string(), device_id.clone()).await {
-            Err(ApiError::Unauthorized) => {
-                error!("Failed to request a device refresh because of unauthorized");
-                // Fetch a new access token and try refreshing again
-                access_token = get_access_token().await;
-                let _ = refresh_device(access_token.to_string(), device_id.clone()).await;
+    // Open sqlite database
+    if sqlite::is_enabled() {
+        match sqlite::connect_to_db() {
+            Ok(sink) => {
+                sinks.push(BufferedSink::new(Box::new(sink)));
             }
-            Err(ApiError::Other(err)) => {
-                error!("Failed to request a device refresh {}", err);
+            Err(err) => {
+                error!("Failed to open sqlite database: {}", err);
             }
-            Ok(_) => {}
         }
-        sleep(Duration::from_millis(fetch_interval)).await;
-        match fetch_and_log_new_entry(
-            &influx_client,
-            &timescale_client,
-            access_token.to_string(),
-            device_id.clone(),
-            building_id.clone(),
-        )
-        .await
-        {
-            Err(ApiError::Unauthorized) => {
-                error!("Failed to request a new entry because of unauthorized");
-                // Fetch a new access token and try fetching again
-                access_token = get_access_token().await;
-                let _ = fetch_and_log_new_entry(
-                    &influx_client,
-                    &timescale_client,
-                    access_token.to_string(),
-                    device_id.clone(),
-                    building_id.clone(),
-                )
-                .await;
+    }
+
+    // Serve the metrics and the api over http
+    let latest = LatestSnapshots::default();
+    let live = LiveUpdates::default();
+    if http::is_enabled() {
+        let registry = monitoring().registry().clone();
+        match PrometheusSink::new(&registry) {
+            Ok(sink) => {
+                sinks.push(BufferedSink::new(Box::new(sink)));
             }
-            Err(ApiError::Other(err)) => {
-                error!("Failed to request a new entry {}", err);
+            Err(err) => {
+                error!("Failed to configure prometheus metrics: {}", err);
+            }
+        }
+        let state = ServerState {
+            registry,
+            session: session.clone(),
+            devices: devices.clone(),
+            sinks: sinks.iter().map(|sink| sink.name()).collect(),
+            latest: latest.clone(),
+            live: live.clone(),
+            timescale: timescale_pool,
+            api_keys: ApiKeys::from_env(),
+            commander: commander.clone(),
+        };
+        if let Err(err) = http::start(state).await {
+            error!("Failed to start http server: {}", err);
+        }
+    }
+
+    let spot = match PriceSource::from_env() {
+        Some(source) if tariff::is_enabled() || optimizer::is_enabled() => Some(SpotPrices::start(source).await),
+        _ => None,
+    };
+
+    // Move the setpoints by the spot prices
+    if optimizer::is_enabled() {
+        match &spot {
+            Some(prices) => match Optimizer::new(prices.clone(), &devices, commander, latest.clone(), live.clone()) {
+                Ok(optimizer) => optimizer.start(),
+                Err(err) => error!("Failed to configure the optimizer: {}", err),
+            },
+            None => error!("The optimizer needs the spot prices from SPOT_PRICES_SOURCE"),
+        }
+    }
+
+    let tariff = if tariff::is_enabled() {
+        Some(Tariff::from_env(get_timezone(), spot, devices.len()))
+    } else {
+        None
+    };
+    let mut state = PollState {
+        tracker: SampleTracker::new(tariff),
+        events: EventDetector::default(),
+        latest,
+        live,
+    };
+    state.tracker.seed_from(&sinks).await;
+    state.events.seed_from(&sinks).await;
+
+    let timezone = get_timezone();
+    let polls: Vec<DevicePoll> = devices
+        .into_iter()
+        .map(|device| {
+            info!("Polling device {} in building {}", device.device_id, device.building_id);
+            DevicePoll {
+                schedule: PollSchedule::from_env(
+                    &device.device_id,
+                    &device.building_id,
+                    Duration::from_millis(refresh_interval),
+                    timezone,
+                ),
+                refresh: RefreshTracker::from_env(&device.device_id, &device.building_id),
+                device,
+                next_poll: Utc::now(),
+            }
+        })
+        .collect();
+
+    Startup {
+        session,
+        sinks,
+        state,
+        polls,
+        fetch_interval,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+
+    let logger = logging::init_logging();
+    // Before anything slow, so that a signal during the startup also stops the logger cleanly
+    let shutdown = Shutdown::listen();
+
+    info!("MELCloud Logger starting");
+    info!("Using time zone: {}", get_timezone().name());
+
+    let command = std::env::args().nth(1);
+    if matches!(command.as_deref(), Some("migrate") | Some("healthcheck")) {
+        let run = async {
+            match command.as_deref() {
+                Some("migrate") => migrate().await,
+                _ => health::probe().await,
             }
-            Ok(_) => {}
         };
-        sleep(Duration::from_millis(refresh_interval)).await;
+        let code = tokio::select! {
+            code = run => code,
+            _ = shutdown.requested() => 1,
+        };
+        logger.shutdown();
+        std::process::exit(code);
+    }
+
+    let Startup {
+        session,
+        sinks,
+        mut state,
+        mut polls,
+        fetch_interval,
+    } = tokio::select! {
+        startup = start() => startup,
+        _ = shutdown.requested() => {
+            info!("MELCloud Logger stopped before it started polling");
+            logger.shutdown();
+            std::process::exit(0);
+        }
+    };
+
+    // Logging loop
+    let mut code = 0;
+    while shutdown.deadline().is_none() {
+        let next_poll = polls.iter().map(|poll| poll.next_poll).min().unwrap();
+        if let Ok(delay) = (next_poll - Utc::now()).to_std() {
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = shutdown.requested() => break,
+            }
+        }
+
+        let poll = poll_due_devices(&mut polls, &sinks, &mut state, &session, fetch_interval);
+        tokio::pin!(poll);
+        tokio::select! {
+            _ = &mut poll => {}
+            deadline = shutdown.requested() => {
+                // Let the MELCloud calls and writes in progress finish
+                if timeout_at(deadline, poll).await.is_err() {
+                    error!("Stopped polling the devices in the middle");
+                    code = 1;
+                }
+                break;
+            }
+        }
+
+        if let Some(summary) = monitoring().report(Utc::now()) {
+            info!("Logger {}", summary);
+        }
     }
+
+    let flush = async {
+        let mut flushed = true;
+        for sink in sinks.iter() {
+            if let Err(err) = sink.flush().await {
+                error!("Failed to flush {}: {}", sink.name(), err);
+                flushed = false;
+            }
+        }
+        flushed
+    };
+    match timeout_at(shutdown.deadline().unwrap(), flush).await {
+        Ok(true) => {}
+        Ok(false) => code = 1,
+        Err(_) => {
+            error!("Timed out flushing the sinks");
+            code = 1;
+        }
+    }
+
+    info!("MELCloud Logger stopped");
+    logger.shutdown();
+    std::process::exit(code);
 }
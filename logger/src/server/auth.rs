@@ -0,0 +1,145 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use crate::server::devices::error;
+
+/// What an API key is allowed to do, control also allows reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Read,
+    Control,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ApiKey {
+    name: String,
+    key: String,
+    scope: Scope,
+}
+
+/// The keys from `API_KEYS`, a list of `name:key:scope` such as `alice:s3cret:control,grafana:0ther:read`
+#[derive(Debug, Clone, Default)]
+pub struct ApiKeys(Arc<Vec<ApiKey>>);
+
+impl FromStr for ApiKeys {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut keys = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let parts: Vec<&str> = entry.split(':').collect();
+            let (name, key, scope) = match parts.as_slice() {
+                [name, key, scope] if !name.is_empty() && !key.is_empty() => (name, key, scope),
+                _ => return Err(anyhow::anyhow!("Expected name:key:scope, not {}", entry)),
+            };
+            let scope = match *scope {
+                "read" => Scope::Read,
+                "control" => Scope::Control,
+                _ => return Err(anyhow::anyhow!("The scope of {} must be read or control, not {}", name, scope)),
+            };
+            keys.push(ApiKey {
+                name: name.to_string(),
+                key: key.to_string(),
+                scope,
+            });
+        }
+
+        Ok(ApiKeys(Arc::new(keys)))
+    }
+}
+
+/// Compares all of the bytes so that the time doesn't tell how much of a key matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// Control needs at least one key, and TimescaleDB for the audit log
+    ControlDisabled,
+    Missing,
+    Invalid,
+    Forbidden,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthError::ControlDisabled => error(StatusCode::FORBIDDEN, "Control needs API_KEYS and TimescaleDB for the audit log"),
+            AuthError::Missing => error(StatusCode::UNAUTHORIZED, "Missing API key"),
+            AuthError::Invalid => error(StatusCode::UNAUTHORIZED, "Invalid API key"),
+            AuthError::Forbidden => error(StatusCode::FORBIDDEN, "The API key isn't allowed to control devices"),
+        }
+    }
+}
+
+impl ApiKeys {
+    pub fn from_env() -> ApiKeys {
+        dotenv::var("API_KEYS")
+            .map(|var| var.parse::<ApiKeys>().unwrap())
+            .unwrap_or_default()
+    }
+
+    /// The name of the key in `Authorization: Bearer <key>` or `X-API-Key`. Without any keys
+    /// configured reading is open to everyone.
+    pub fn authorize(&self, headers: &HeaderMap, scope: Scope) -> Result<String, AuthError> {
+        if self.0.is_empty() {
+            return match scope {
+                Scope::Read => Ok("anonymous".to_string()),
+                Scope::Control => Err(AuthError::ControlDisabled),
+            };
+        }
+
+        let key = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| headers.get("X-API-Key").and_then(|value| value.to_str().ok()))
+            .ok_or(AuthError::Missing)?;
+
+        let api_key = self
+            .0
+            .iter()
+            .find(|api_key| constant_time_eq(api_key.key.as_bytes(), key.trim().as_bytes()))
+            .ok_or(AuthError::Invalid)?;
+        if api_key.scope < scope {
+            return Err(AuthError::Forbidden);
+        }
+
+        Ok(api_key.name.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_authorize() {
+        let keys: ApiKeys = "alice:secret:control, grafana:other:read".parse().unwrap();
+        let alice = headers("authorization", "Bearer secret");
+        let grafana = headers("x-api-key", "other");
+
+        assert_eq!(Ok("alice".to_string()), keys.authorize(&alice, Scope::Control));
+        assert_eq!(Ok("grafana".to_string()), keys.authorize(&grafana, Scope::Read));
+        assert_eq!(Err(AuthError::Forbidden), keys.authorize(&grafana, Scope::Control));
+        assert_eq!(Err(AuthError::Invalid), keys.authorize(&headers("x-api-key", "secre"), Scope::Read));
+        assert_eq!(Err(AuthError::Missing), keys.authorize(&HeaderMap::new(), Scope::Read));
+
+        let open = ApiKeys::default();
+        assert!(open.authorize(&HeaderMap::new(), Scope::Read).is_ok());
+        assert_eq!(Err(AuthError::ControlDisabled), open.authorize(&alice, Scope::Control));
+
+        assert!("alice:secret".parse::<ApiKeys>().is_err());
+        assert!("alice:secret:admin".parse::<ApiKeys>().is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::app::command::DeviceCommand;
+use crate::app::live::LiveUpdate;
+use crate::server::auth::{AuthError, Scope};
+use crate::server::server::ServerState;
+use crate::storage::timescaledb::history::{select_history_from_timescaledb, Resolution, MAX_POINTS};
+
+pub fn error(status: StatusCode, message: impl std::fmt::Display) -> Response {
+    (status, Json(json!({ "error": message.to_string() }))).into_response()
+}
+
+/// The numeric id of a polled device, only those are served
+pub fn polled_device_id(state: &ServerState, device_id: &str) -> Option<u32> {
+    state
+        .devices
+        .iter()
+        .find(|device| device.device_id == device_id)
+        .and_then(|device| device.device_id.parse::<u32>().ok())
+}
+
+pub fn not_polled(device_id: &str) -> Response {
+    error(StatusCode::NOT_FOUND, format!("Device {} isn't polled", device_id))
+}
+
+pub async fn list_devices(State(state): State<ServerState>, headers: HeaderMap) -> Response {
+    if let Err(err) = state.api_keys.authorize(&headers, Scope::Read) {
+        return err.into_response();
+    }
+
+    let devices: Vec<_> = state
+        .devices
+        .iter()
+        .map(|device| {
+            let snapshot = device.device_id.parse::<u32>().ok().and_then(|device_id| state.latest.get(device_id));
+            json!({
+                "device_id": device.device_id,
+                "building_id": device.building_id,
+                "device_name": snapshot.as_ref().and_then(|snapshot| snapshot.device_name.clone()),
+                "latest": snapshot,
+            })
+        })
+        .collect();
+
+    Json(devices).into_response()
+}
+
+pub async fn get_device(State(state): State<ServerState>, Path(device_id): Path<String>, headers: HeaderMap) -> Response {
+    if let Err(err) = state.api_keys.authorize(&headers, Scope::Read) {
+        return err.into_response();
+    }
+    let id = match polled_device_id(&state, &device_id) {
+        Some(id) => id,
+        None => return not_polled(&device_id),
+    };
+
+    match state.latest.get(id) {
+        Some(snapshot) => Json(snapshot).into_response(),
+        None => error(StatusCode::NOT_FOUND, format!("No samples from device {} yet", device_id)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    /// Defaults to a day before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// Defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Defaults to `raw`
+    pub resolution: Option<String>,
+}
+
+pub async fn get_history(
+    State(state): State<ServerState>,
+    Path(device_id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(err) = state.api_keys.authorize(&headers, Scope::Read) {
+        return err.into_response();
+    }
+    let id = match polled_device_id(&state, &device_id) {
+        Some(id) => id,
+        None => return not_polled(&device_id),
+    };
+    let pool = match &state.timescale {
+        Some(pool) => pool,
+        None => return error(StatusCode::SERVICE_UNAVAILABLE, "History needs TimescaleDB to be enabled"),
+    };
+
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::days(1));
+    if from >= to {
+        return error(StatusCode::BAD_REQUEST, "from must be before to");
+    }
+    let resolution = match query.resolution.as_deref().unwrap_or("raw").parse::<Resolution>() {
+        Ok(resolution) => resolution,
+        Err(err) => return error(StatusCode::BAD_REQUEST, err),
+    };
+    if resolution.bucket_count(from, to).is_some_and(|count| count > MAX_POINTS) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            format!("The range has more than {} buckets, use a coarser resolution or a shorter range", MAX_POINTS),
+        );
+    }
+
+    let client = match pool.get().await {
+        Ok(client) => client,
+        Err(err) => return error(StatusCode::SERVICE_UNAVAILABLE, err),
+    };
+    match select_history_from_timescaledb(&client, id, from, to, resolution).await {
+        Ok(points) if points.len() as i64 > MAX_POINTS => error(
+            StatusCode::BAD_REQUEST,
+            format!("The range has more than {} samples, use a resolution or a shorter range", MAX_POINTS),
+        ),
+        Ok(points) => Json(points).into_response(),
+        Err(err) => {
+            error!("Failed to query the history of device {}: {}", device_id, err);
+            error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to query the history")
+        }
+    }
+}
+
+/// Parses a body such as `{"mode": "heat", "temperature": 21.5}` with the same fields and values
+/// as the MQTT command topics
+pub fn parse_commands(body: &Map<String, Value>) -> Result<Vec<DeviceCommand>, anyhow::Error> {
+    if body.is_empty() {
+        return Err(anyhow::anyhow!("No fields to change"));
+    }
+
+    let mut commands = Vec::new();
+    for (field, value) in body {
+        let payload = match value {
+            Value::Bool(value) => value.to_string(),
+            Value::Number(value) => value.to_string(),
+            Value::String(value) => value.clone(),
+            _ => return Err(anyhow::anyhow!("The value of {} must be a string, number or boolean", field)),
+        };
+        commands.extend(DeviceCommand::parse(field, &payload)?);
+    }
+
+    Ok(commands)
+}
+
+pub async fn post_command(
+    State(state): State<ServerState>,
+    Path(device_id): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<Map<String, Value>>,
+) -> Response {
+    let actor = match state.api_keys.authorize(&headers, Scope::Control) {
+        Ok(actor) => actor,
+        Err(err) => return err.into_response(),
+    };
+    if !state.commander.is_audited() {
+        return AuthError::ControlDisabled.into_response();
+    }
+    let device = match state.devices.iter().find(|device| device.device_id == device_id) {
+        Some(device) => device,
+        None => return not_polled(&device_id),
+    };
+    let commands = match parse_commands(&body) {
+        Ok(commands) => commands,
+        Err(err) => return error(StatusCode::BAD_REQUEST, err),
+    };
+
+    match state.commander.run(device, &commands, &actor, "api").await {
+        Ok(outcome) => {
+            state.latest.update(&outcome.state);
+            state.live.publish(LiveUpdate::Snapshot(Box::new(outcome.state.clone())));
+            Json(outcome).into_response()
+        }
+        Err(err) => error(StatusCode::BAD_GATEWAY, format!("MELCloud failed the command: {}", err)),
+    }
+}
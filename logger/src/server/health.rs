@@ -0,0 +1,199 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::app::app::PolledDevice;
+use crate::app::monitoring::monitoring;
+use crate::app::schedule::device_setting;
+use crate::server::server::{self, ServerState};
+use crate::storage::timescaledb::pool::MigrationState;
+
+/// How old the latest sample of a device may be, `HEALTH_MAX_SAMPLE_AGE_MINUTES` or its
+/// device or building specific variant
+fn max_sample_age(device: &PolledDevice) -> Duration {
+    let minutes = device_setting("HEALTH_MAX_SAMPLE_AGE_MINUTES", &device.device_id, &device.building_id)
+        .or_else(|| dotenv::var("HEALTH_MAX_SAMPLE_AGE_MINUTES").ok())
+        .map(|var| var.parse::<i64>().unwrap())
+        .unwrap_or(30);
+    Duration::minutes(minutes)
+}
+
+#[derive(Debug, Serialize)]
+pub struct SinkHealth {
+    pub name: &'static str,
+    /// Whether the latest write succeeded, `None` before the first one
+    pub connected: Option<bool>,
+    pub last_write: Option<DateTime<Utc>>,
+    pub backlog: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceHealth {
+    pub device_id: String,
+    pub last_sample: Option<DateTime<Utc>>,
+    /// Counted from the start until the first sample
+    pub age_seconds: i64,
+    pub max_age_seconds: i64,
+    pub fresh: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub logged_in: bool,
+    /// The TimescaleDB schema migrations, when TimescaleDB is enabled
+    pub migrations: Option<MigrationState>,
+    pub sinks: Vec<SinkHealth>,
+    pub devices: Vec<DeviceHealth>,
+}
+
+impl Health {
+    pub async fn check(state: &ServerState, now: DateTime<Utc>) -> Health {
+        let monitoring = monitoring();
+
+        let sinks = state
+            .sinks
+            .iter()
+            .map(|&name| {
+                let last_write = monitoring.last_write(name);
+                SinkHealth {
+                    name,
+                    connected: last_write.map(|(success, _)| success),
+                    last_write: last_write.map(|(_, time)| time),
+                    backlog: monitoring.backlog(name),
+                }
+            })
+            .collect();
+
+        let devices = state
+            .devices
+            .iter()
+            .map(|device| {
+                let last_sample = device.device_id.parse::<u32>().ok().and_then(|device_id| monitoring.last_sample(device_id));
+                let age = now - last_sample.unwrap_or_else(|| monitoring.started());
+                let max_age = max_sample_age(device);
+                DeviceHealth {
+                    device_id: device.device_id.clone(),
+                    last_sample,
+                    age_seconds: age.num_seconds(),
+                    max_age_seconds: max_age.num_seconds(),
+                    fresh: age <= max_age,
+                }
+            })
+            .collect();
+
+        Health {
+            logged_in: state.session.is_logged_in().await,
+            migrations: state.timescale.as_ref().map(|pool| pool.migration_state()),
+            sinks,
+            devices,
+        }
+    }
+
+    /// Logged in and getting data from at least one device, otherwise a restart might help
+    pub fn is_alive(&self) -> bool {
+        self.logged_in && self.devices.iter().any(|device| device.fresh)
+    }
+
+    /// Alive, migrated, every device is fresh and no sink is failing
+    pub fn is_ready(&self) -> bool {
+        self.is_alive()
+            && !matches!(self.migrations, Some(MigrationState::Pending) | Some(MigrationState::Failed(_)))
+            && self.devices.iter().all(|device| device.fresh)
+            && self.sinks.iter().all(|sink| sink.connected != Some(false))
+    }
+}
+
+fn respond(ok: bool, health: &Health) -> (StatusCode, Json<Value>) {
+    let (status, text) = if ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "failing")
+    };
+    (status, Json(json!({ "status": text, "health": health })))
+}
+
+pub async fn healthz(State(state): State<ServerState>) -> (StatusCode, Json<Value>) {
+    let health = Health::check(&state, Utc::now()).await;
+    respond(health.is_alive(), &health)
+}
+
+pub async fn readyz(State(state): State<ServerState>) -> (StatusCode, Json<Value>) {
+    let health = Health::check(&state, Utc::now()).await;
+    respond(health.is_ready(), &health)
+}
+
+/// Asks the running logger for `/healthz`, for the container health check since the image has no curl
+pub async fn probe() -> i32 {
+    let mut address = match server::address().parse::<std::net::SocketAddr>() {
+        Ok(address) => address,
+        Err(err) => {
+            error!("Invalid HTTP_ADDRESS: {}", err);
+            return 1;
+        }
+    };
+    if address.ip().is_unspecified() {
+        address.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+    }
+
+    match reqwest::get(format!("http://{}/healthz", address)).await {
+        Ok(response) if response.status().is_success() => 0,
+        Ok(response) => {
+            error!("Health check failed with {}: {}", response.status(), response.text().await.unwrap_or_default());
+            1
+        }
+        Err(err) => {
+            error!("Health check failed: {}", err);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(fresh: bool) -> DeviceHealth {
+        DeviceHealth {
+            device_id: "1".to_string(),
+            last_sample: None,
+            age_seconds: 0,
+            max_age_seconds: 1800,
+            fresh,
+        }
+    }
+
+    #[test]
+    fn test_alive_and_ready() {
+        let mut health = Health {
+            logged_in: true,
+            migrations: Some(MigrationState::Applied),
+            sinks: vec![SinkHealth {
+                name: "influx",
+                connected: None,
+                last_write: None,
+                backlog: 0,
+            }],
+            devices: vec![device(true), device(false)],
+        };
+        assert!(health.is_alive());
+        assert!(!health.is_ready());
+
+        health.devices.pop();
+        assert!(health.is_ready());
+
+        health.migrations = Some(MigrationState::Failed("relation exists".to_string()));
+        assert!(health.is_alive());
+        assert!(!health.is_ready());
+        health.migrations = Some(MigrationState::Applied);
+
+        health.sinks[0].connected = Some(false);
+        assert!(health.is_alive());
+        assert!(!health.is_ready());
+
+        health.logged_in = false;
+        assert!(!health.is_alive());
+    }
+}
@@ -0,0 +1,26 @@
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use prometheus::{Encoder, Registry, TextEncoder};
+
+use crate::app::monitoring::monitoring;
+use crate::server::server::ServerState;
+
+/// The registry in Prometheus' text format
+pub fn render(registry: &Registry) -> Result<String, anyhow::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+pub async fn metrics(State(state): State<ServerState>) -> Response {
+    monitoring().update_sample_ages(Utc::now());
+    match render(&state.registry) {
+        Ok(text) => ([(header::CONTENT_TYPE, TextEncoder::new().format_type().to_string())], text).into_response(),
+        Err(err) => {
+            error!("Failed to render the metrics: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
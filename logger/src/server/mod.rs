@@ -0,0 +1,7 @@
+pub mod auth;
+pub mod devices;
+pub mod health;
+pub mod metrics;
+pub mod stream;
+#[allow(clippy::module_inception)]
+pub mod server;
@@ -0,0 +1,167 @@
+use axum::routing::{get, post};
+use axum::Router;
+use prometheus::Registry;
+use tokio::net::TcpListener;
+
+use crate::app::app::PolledDevice;
+use crate::app::command::Commander;
+use crate::app::latest::LatestSnapshots;
+use crate::app::live::LiveUpdates;
+use crate::app::session::Session;
+use crate::server::auth::ApiKeys;
+use crate::server::{devices, health, metrics, stream};
+use crate::storage::timescaledb::pool::TimescalePool;
+
+pub fn is_enabled() -> bool {
+    dotenv::var("HTTP_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+pub fn address() -> String {
+    dotenv::var("HTTP_ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string())
+}
+
+/// What the HTTP handlers share with the polling loop
+#[derive(Clone)]
+pub struct ServerState {
+    pub registry: Registry,
+    pub session: Session,
+    pub devices: Vec<PolledDevice>,
+    pub sinks: Vec<&'static str>,
+    pub latest: LatestSnapshots,
+    pub live: LiveUpdates,
+    /// For the migration state and the device history, when TimescaleDB is enabled
+    pub timescale: Option<TimescalePool>,
+    pub api_keys: ApiKeys,
+    pub commander: Commander,
+}
+
+pub fn router(state: ServerState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics::metrics))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/devices", get(devices::list_devices))
+        .route("/devices/:id", get(devices::get_device))
+        .route("/devices/:id/history", get(devices::get_history))
+        .route("/devices/:id/command", post(devices::post_command))
+        .route("/stream", get(stream::stream))
+        .with_state(state)
+}
+
+/// Binds the HTTP server and serves it in the background
+pub async fn start(state: ServerState) -> Result<(), anyhow::Error> {
+    let address = address();
+    let listener = TcpListener::bind(&address).await?;
+    info!("Serving HTTP on {}", listener.local_addr()?);
+
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, router(state)).await {
+            error!("HTTP server stopped: {}", err);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::live::LiveUpdate;
+    use crate::app::snapshot::DeviceSnapshot;
+    use crate::storage::prometheus::exporter::PrometheusSink;
+    use crate::storage::sink::Sink;
+    use chrono::Utc;
+
+    fn test_state(registry: Registry) -> ServerState {
+        ServerState {
+            registry,
+            session: Session::new("token".to_string()),
+            devices: vec![PolledDevice {
+                device_id: "3".to_string(),
+                building_id: "1".to_string(),
+            }],
+            sinks: Vec::new(),
+            latest: LatestSnapshots::default(),
+            live: LiveUpdates::default(),
+            timescale: None,
+            api_keys: "reader:secret:read".parse().unwrap(),
+            commander: Commander::new(Session::new("token".to_string()), None),
+        }
+    }
+
+    // Serves the router on a free port, returns the base url
+    async fn serve(state: ServerState) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = router(state);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics() {
+        let registry = Registry::new();
+        let sink = PrometheusSink::new(&registry).unwrap();
+        sink.write(&DeviceSnapshot::for_test(3, Utc::now())).await.unwrap();
+        let url = serve(test_state(registry)).await;
+
+        let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        assert!(response.text().await.unwrap().contains(r#"melcloud_set_temperature_celsius{building="Home",device_id="3",device_name="Device 3"} 21"#));
+    }
+
+    #[tokio::test]
+    async fn test_serves_devices() {
+        let state = test_state(Registry::new());
+        state.latest.update(&DeviceSnapshot::for_test(3, Utc::now()));
+        let url = serve(state).await;
+
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{}{}", url, path)).header("X-API-Key", "secret").send();
+
+        assert_eq!(401, reqwest::get(format!("{}/devices", url)).await.unwrap().status().as_u16());
+        let devices: serde_json::Value = get("/devices").await.unwrap().json().await.unwrap();
+        assert_eq!("Device 3", devices[0]["device_name"]);
+        assert_eq!(3, devices[0]["latest"]["device_id"]);
+
+        let device: serde_json::Value = get("/devices/3").await.unwrap().json().await.unwrap();
+        assert_eq!(21.0, device["room_temperature"]);
+
+        assert_eq!(404, get("/devices/4").await.unwrap().status().as_u16());
+        assert_eq!(503, get("/devices/3/history").await.unwrap().status().as_u16());
+
+        let command = client
+            .post(format!("{}/devices/3/command", url))
+            .header("X-API-Key", "secret")
+            .json(&serde_json::json!({ "mode": "heat" }))
+            .send();
+        assert_eq!(403, command.await.unwrap().status().as_u16());
+    }
+
+    #[tokio::test]
+    async fn test_streams_updates() {
+        let state = test_state(Registry::new());
+        state.latest.update(&DeviceSnapshot::for_test(3, Utc::now()));
+        let live = state.live.clone();
+        let url = serve(state).await;
+
+        assert_eq!(404, reqwest::get(format!("{}/stream?devices=4&api_key=secret", url)).await.unwrap().status().as_u16());
+
+        let mut response = reqwest::get(format!("{}/stream?devices=3&api_key=secret", url)).await.unwrap();
+        assert_eq!(200, response.status().as_u16());
+        let chunk = response.chunk().await.unwrap().unwrap();
+        let text = String::from_utf8_lossy(&chunk);
+        assert!(text.starts_with("event: snapshot\ndata: {"));
+        assert!(text.contains(r#""device_id":3,"#));
+
+        let mut snapshot = DeviceSnapshot::for_test(3, Utc::now());
+        snapshot.set_temperature = 23.5;
+        live.publish(LiveUpdate::Snapshot(Box::new(DeviceSnapshot::for_test(4, Utc::now()))));
+        live.publish(LiveUpdate::Snapshot(Box::new(snapshot)));
+        let chunk = response.chunk().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&chunk).contains(r#""set_temperature":23.5"#));
+    }
+}
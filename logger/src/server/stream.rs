@@ -0,0 +1,81 @@
+use std::convert::Infallible;
+
+use axum::extract::{Query, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::app::live::LiveUpdate;
+use crate::server::auth::Scope;
+use crate::server::devices::{error, not_polled, polled_device_id};
+use crate::server::server::ServerState;
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    /// A comma separated list of device ids, every polled device by default
+    pub devices: Option<String>,
+    /// For clients such as `EventSource` that can't set headers
+    pub api_key: Option<String>,
+}
+
+fn event(update: &LiveUpdate) -> Event {
+    let event = match update {
+        LiveUpdate::Snapshot(snapshot) => Event::default().event("snapshot").json_data(snapshot),
+        LiveUpdate::Event(device_event) => Event::default().event("event").json_data(device_event),
+    };
+    event.unwrap_or_else(|err| Event::default().event("error").data(err.to_string()))
+}
+
+/// Server-Sent Events of the devices' updates, starting with their latest snapshots
+pub async fn stream(State(state): State<ServerState>, Query(query): Query<StreamQuery>, mut headers: HeaderMap) -> Response {
+    if let Some(api_key) = query.api_key.as_deref().and_then(|key| HeaderValue::from_str(key).ok()) {
+        headers.insert("X-API-Key", api_key);
+    }
+    if let Err(err) = state.api_keys.authorize(&headers, Scope::Read) {
+        return err.into_response();
+    }
+
+    let mut devices = Vec::new();
+    match query.devices.as_deref() {
+        Some(list) => {
+            for device_id in list.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+                match polled_device_id(&state, device_id) {
+                    Some(id) => devices.push(id),
+                    None => return not_polled(device_id),
+                }
+            }
+            if devices.is_empty() {
+                return error(StatusCode::BAD_REQUEST, "No devices to stream");
+            }
+        }
+        None => devices.extend(state.devices.iter().filter_map(|device| device.device_id.parse::<u32>().ok())),
+    }
+
+    // Subscribe before reading the latest snapshots so that nothing falls in between
+    let receiver = state.live.subscribe();
+    let latest: Vec<_> = devices
+        .iter()
+        .filter_map(|&id| state.latest.get(id))
+        .map(|snapshot| Ok::<_, Infallible>(event(&LiveUpdate::Snapshot(Box::new(snapshot)))))
+        .collect();
+
+    let updates = stream::unfold((receiver, devices), |(mut receiver, devices)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(update) if devices.contains(&update.device_id()) => {
+                    return Some((Ok(event(&update)), (receiver, devices)));
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => warn!("A live stream client missed {} updates", skipped),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(stream::iter(latest).chain(updates))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
@@ -0,0 +1,643 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::task;
+
+use crate::app::events::{DeviceEvent, DeviceState};
+use crate::app::monitoring::monitoring;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::sink::{is_rejected, Sink};
+
+pub fn is_enabled() -> bool {
+    dotenv::var("BUFFER_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn buffer_directory() -> PathBuf {
+    PathBuf::from(dotenv::var("BUFFER_DIRECTORY").unwrap_or_else(|_| "buffer".to_string()))
+}
+
+fn max_entries() -> usize {
+    dotenv::var("BUFFER_MAX_ENTRIES")
+        .map(|var| var.parse::<usize>())
+        .unwrap_or(Ok(100_000))
+        .unwrap()
+}
+
+fn max_age() -> Duration {
+    let hours = dotenv::var("BUFFER_MAX_AGE_HOURS")
+        .map(|var| var.parse::<i64>())
+        .unwrap_or(Ok(7 * 24))
+        .unwrap();
+    Duration::hours(hours)
+}
+
+/// What a `DiskBuffer` can hold, the time is for the age limit
+pub trait Buffered: Serialize + DeserializeOwned + Send + 'static {
+    fn time(&self) -> DateTime<Utc>;
+}
+
+impl Buffered for DeviceSnapshot {
+    fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+impl Buffered for DeviceEvent {
+    fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+}
+
+/// Undelivered entries, such as the snapshots of a single sink, kept in a JSON lines file so that
+/// they survive restarts
+pub struct DiskBuffer<T = DeviceSnapshot> {
+    /// `None` keeps the entries in memory only
+    path: Option<PathBuf>,
+    entries: VecDeque<T>,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+/// The buffer `name` in `BUFFER_DIRECTORY` when `BUFFER_ENABLED` is set, otherwise or when it can't
+/// be opened one that lasts until a restart
+pub fn open_or_in_memory<T: Buffered>(name: &str) -> DiskBuffer<T> {
+    if is_enabled() {
+        match DiskBuffer::open(&buffer_directory(), name, max_entries(), max_age()) {
+            Ok(buffer) => return buffer,
+            Err(err) => error!("Failed to open the buffer {}, keeping it in memory: {}", name, err),
+        }
+    }
+    DiskBuffer::in_memory(max_entries(), max_age())
+}
+
+impl<T: Buffered> DiskBuffer<T> {
+    pub fn in_memory(max_entries: usize, max_age: Duration) -> DiskBuffer<T> {
+        DiskBuffer {
+            path: None,
+            entries: VecDeque::new(),
+            max_entries,
+            max_age,
+        }
+    }
+
+    pub fn open(directory: &Path, name: &str, max_entries: usize, max_age: Duration) -> Result<DiskBuffer<T>, anyhow::Error> {
+        fs::create_dir_all(directory)?;
+        let path = directory.join(format!("{}.jsonl", name));
+
+        let mut entries = VecDeque::new();
+        if path.exists() {
+            for (index, line) in fs::read_to_string(&path)?.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<T>(line) {
+                    Ok(entry) => entries.push_back(entry),
+                    Err(err) => warn!("Skipping unreadable line {} in {}: {}", index + 1, path.display(), err),
+                }
+            }
+        }
+
+        let mut buffer = DiskBuffer {
+            path: Some(path),
+            entries,
+            max_entries,
+            max_age,
+        };
+        if buffer.prune() {
+            write_lines(&buffer.file_path(), &buffer.lines()?)?;
+        }
+        if !buffer.is_empty() {
+            info!("Loaded {} buffered entries from {}", buffer.len(), buffer.file_path().display());
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.entries.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.entries.pop_front()
+    }
+
+    fn file_path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| PathBuf::from("memory"))
+    }
+
+    pub async fn push(&mut self, entry: T) -> Result<(), anyhow::Error> {
+        self.entries.push_back(entry);
+
+        if self.prune() {
+            return self.persist().await;
+        }
+
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let line = serde_json::to_string(self.entries.back().unwrap())?;
+        task::spawn_blocking(move || append_line(&path, &line)).await?
+    }
+
+    /// Keeps an entry the sink refused next to the buffer so that it can be inspected or fixed by hand
+    pub async fn reject(&self, entry: &T) -> Result<PathBuf, anyhow::Error> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The buffer is only in memory"))?
+            .with_extension("rejected.jsonl");
+        let line = serde_json::to_string(entry)?;
+        let rejected_path = path.clone();
+        task::spawn_blocking(move || append_line(&path, &line)).await??;
+
+        Ok(rejected_path)
+    }
+
+    /// Drops the entries that are too old or don't fit, returns whether anything was dropped
+    fn prune(&mut self) -> bool {
+        let before = self.entries.len();
+
+        let oldest_allowed = Utc::now() - self.max_age;
+        self.entries.retain(|entry| entry.time() >= oldest_allowed);
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+
+        let dropped = before - self.entries.len();
+        if dropped > 0 {
+            warn!("Dropped {} buffered entries from {} over the size or age limit", dropped, self.file_path().display());
+        }
+
+        dropped > 0
+    }
+
+    fn lines(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self.entries.iter().map(serde_json::to_string).collect::<Result<_, _>>()?)
+    }
+
+    /// Rewrites the whole file off the async runtime
+    pub async fn persist(&self) -> Result<(), anyhow::Error> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+        let lines = self.lines()?;
+        task::spawn_blocking(move || write_lines(&path, &lines)).await?
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Writes through a temporary file so that a crash never leaves the buffer half written
+fn write_lines(path: &Path, lines: &[String]) -> Result<(), anyhow::Error> {
+    let temp_path = path.with_extension("jsonl.tmp");
+
+    let mut file = fs::File::create(&temp_path)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_data()?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+/// A sink that queues the snapshots and events it fails to write and replays them in order once the
+/// sink recovers
+pub struct BufferedSink {
+    sink: Box<dyn Sink>,
+    buffer: Option<Mutex<DiskBuffer>>,
+    /// In `<sink>.events.jsonl`, apart from the snapshots since most sinks don't store events
+    events: Option<Mutex<DiskBuffer<DeviceEvent>>>,
+}
+
+impl BufferedSink {
+    pub fn new(sink: Box<dyn Sink>) -> BufferedSink {
+        let mut buffer = None;
+        let mut events = None;
+        if is_enabled() {
+            match DiskBuffer::open(&buffer_directory(), sink.name(), max_entries(), max_age()) {
+                Ok(disk_buffer) => {
+                    monitoring().set_backlog(sink.name(), disk_buffer.len());
+                    buffer = Some(Mutex::new(disk_buffer));
+                }
+                Err(err) => error!("Failed to open the write buffer for {}: {}", sink.name(), err),
+            }
+            match DiskBuffer::open(&buffer_directory(), &format!("{}.events", sink.name()), max_entries(), max_age()) {
+                Ok(disk_buffer) => events = Some(Mutex::new(disk_buffer)),
+                Err(err) => error!("Failed to open the event buffer for {}: {}", sink.name(), err),
+            }
+        }
+
+        BufferedSink { sink, buffer, events }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.sink.name()
+    }
+
+    /// Writes into the sink, timing it for the metrics
+    async fn write_to_sink(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        let started = Instant::now();
+        let result = self.sink.write(snapshot).await;
+        monitoring().record_sink_write(self.name(), started.elapsed(), result.is_ok());
+        result
+    }
+
+    pub async fn write(&self, snapshot: &DeviceSnapshot) {
+        let buffer = match &self.buffer {
+            Some(buffer) => buffer,
+            None => {
+                if let Err(err) = self.write_to_sink(snapshot).await {
+                    error!("Failed to log snapshot into {}: {}", self.name(), err);
+                }
+                return;
+            }
+        };
+
+        let mut buffer = buffer.lock().await;
+        if !buffer.is_empty() {
+            self.replay(&mut buffer).await;
+        }
+
+        // Newer snapshots wait behind the older ones to keep the order
+        if buffer.is_empty() {
+            match self.write_to_sink(snapshot).await {
+                Ok(_) => return,
+                Err(err) if is_rejected(&err) => {
+                    self.reject(&buffer, snapshot, &err).await;
+                    return;
+                }
+                Err(err) => error!("Failed to log snapshot into {}: {}", self.name(), err),
+            }
+        }
+
+        match buffer.push(snapshot.clone()).await {
+            Ok(_) => info!("Buffered snapshot for {}, {} pending", self.name(), buffer.len()),
+            Err(err) => error!("Failed to buffer snapshot for {}: {}", self.name(), err),
+        }
+        monitoring().set_backlog(self.name(), buffer.len());
+    }
+
+    /// Queues the events behind the pending ones the same way as the snapshots
+    pub async fn write_events(&self, events: &[DeviceEvent]) {
+        let buffer = match &self.events {
+            Some(buffer) => buffer,
+            None => {
+                if let Err(err) = self.sink.write_events(events).await {
+                    error!("Failed to log {} event(s) into {}: {}", events.len(), self.name(), err);
+                }
+                return;
+            }
+        };
+
+        let mut buffer = buffer.lock().await;
+        if !buffer.is_empty() {
+            self.replay_events(&mut buffer).await;
+        }
+
+        for event in events {
+            if buffer.is_empty() {
+                match self.sink.write_events(std::slice::from_ref(event)).await {
+                    Ok(_) => continue,
+                    Err(err) if is_rejected(&err) => {
+                        self.reject_event(&buffer, event, &err).await;
+                        continue;
+                    }
+                    Err(err) => error!("Failed to log event into {}: {}", self.name(), err),
+                }
+            }
+
+            match buffer.push(event.clone()).await {
+                Ok(_) => info!("Buffered event for {}, {} pending", self.name(), buffer.len()),
+                Err(err) => error!("Failed to buffer event for {}: {}", self.name(), err),
+            }
+        }
+    }
+
+    async fn replay_events(&self, buffer: &mut DiskBuffer<DeviceEvent>) {
+        let mut replayed = 0;
+        let mut rejected = 0;
+        while let Some(event) = buffer.front() {
+            match self.sink.write_events(std::slice::from_ref(event)).await {
+                Ok(_) => replayed += 1,
+                Err(err) if is_rejected(&err) => {
+                    self.reject_event(buffer, event, &err).await;
+                    rejected += 1;
+                }
+                Err(err) => {
+                    warn!("{} is still unavailable, {} event(s) pending: {}", self.name(), buffer.len(), err);
+                    break;
+                }
+            }
+            buffer.pop_front();
+        }
+
+        if replayed > 0 {
+            info!("Replayed {} buffered event(s) into {}", replayed, self.name());
+        }
+        if replayed > 0 || rejected > 0 {
+            if let Err(err) = buffer.persist().await {
+                error!("Failed to persist the event buffer for {}: {}", self.name(), err);
+            }
+        }
+    }
+
+    async fn reject_event(&self, buffer: &DiskBuffer<DeviceEvent>, event: &DeviceEvent, err: &anyhow::Error) {
+        match buffer.reject(event).await {
+            Ok(path) => error!("{} rejected the event from {}, moved it to {}: {}", self.name(), event.time, path.display(), err),
+            Err(write_err) => error!("{} rejected the event from {}, dropping it: {} (failed to keep it: {})", self.name(), event.time, err, write_err),
+        }
+    }
+
+    /// The state of the latest sample per device, the buffered ones included
+    pub async fn last_states(&self) -> Result<Option<Vec<DeviceState>>, anyhow::Error> {
+        let mut last_states: HashMap<u32, DeviceState> = match self.sink.last_states().await? {
+            Some(last_states) => last_states.into_iter().map(|state| (state.device_id, state)).collect(),
+            None => return Ok(None),
+        };
+
+        if let Some(buffer) = &self.buffer {
+            for snapshot in buffer.lock().await.entries.iter() {
+                match last_states.get(&snapshot.device_id) {
+                    Some(state) if state.time >= snapshot.time => {}
+                    _ => {
+                        last_states.insert(snapshot.device_id, DeviceState::from(snapshot));
+                    }
+                }
+            }
+        }
+
+        Ok(Some(last_states.into_values().collect()))
+    }
+
+    /// The latest sample per device, counting the buffered ones as written since they will be
+    pub async fn last_written(&self) -> Result<Option<HashMap<u32, DateTime<Utc>>>, anyhow::Error> {
+        let mut last_written = match self.sink.last_written().await? {
+            Some(last_written) => last_written,
+            None => return Ok(None),
+        };
+
+        if let Some(buffer) = &self.buffer {
+            for snapshot in buffer.lock().await.entries.iter() {
+                let time = last_written.entry(snapshot.device_id).or_insert(snapshot.time);
+                if snapshot.time > *time {
+                    *time = snapshot.time;
+                }
+            }
+        }
+
+        Ok(Some(last_written))
+    }
+
+    /// Tries the queued snapshots and events once more, keeps the rest on disk and flushes the sink
+    pub async fn flush(&self) -> Result<(), anyhow::Error> {
+        if let Some(buffer) = &self.buffer {
+            let mut buffer = buffer.lock().await;
+            if !buffer.is_empty() {
+                self.replay(&mut buffer).await;
+            }
+            buffer.persist().await?;
+        }
+        if let Some(events) = &self.events {
+            let mut events = events.lock().await;
+            if !events.is_empty() {
+                self.replay_events(&mut events).await;
+            }
+            events.persist().await?;
+        }
+
+        self.sink.flush().await
+    }
+
+    async fn replay(&self, buffer: &mut DiskBuffer) {
+        let mut replayed = 0;
+        let mut rejected = 0;
+        while let Some(snapshot) = buffer.front() {
+            match self.write_to_sink(snapshot).await {
+                Ok(_) => replayed += 1,
+                // A snapshot the sink refuses must not hold back the ones queued after it
+                Err(err) if is_rejected(&err) => {
+                    self.reject(buffer, snapshot, &err).await;
+                    rejected += 1;
+                }
+                Err(err) => {
+                    warn!("{} is still unavailable, {} snapshot(s) pending: {}", self.name(), buffer.len(), err);
+                    break;
+                }
+            }
+            buffer.pop_front();
+        }
+
+        if replayed > 0 {
+            info!("Replayed {} buffered snapshot(s) into {}", replayed, self.name());
+        }
+        if replayed > 0 || rejected > 0 {
+            monitoring().set_backlog(self.name(), buffer.len());
+            if let Err(err) = buffer.persist().await {
+                error!("Failed to persist the write buffer for {}: {}", self.name(), err);
+            }
+        }
+    }
+
+    async fn reject(&self, buffer: &DiskBuffer, snapshot: &DeviceSnapshot, err: &anyhow::Error) {
+        match buffer.reject(snapshot).await {
+            Ok(path) => error!("{} rejected the snapshot from {}, moved it to {}: {}", self.name(), snapshot.time, path.display(), err),
+            Err(write_err) => error!("{} rejected the snapshot from {}, dropping it: {} (failed to keep it: {})", self.name(), snapshot.time, err, write_err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::events::EventDetector;
+    use crate::storage::sink::Rejected;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn snapshot(minutes_ago: i64, room_temperature: f32) -> DeviceSnapshot {
+        let mut snapshot = DeviceSnapshot::for_test(1, Utc::now() - Duration::minutes(minutes_ago));
+        snapshot.room_temperature = room_temperature;
+        snapshot
+    }
+
+    fn temp_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("melcloud-buffer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    struct FlakySink {
+        available: Arc<AtomicBool>,
+        written: Arc<std::sync::Mutex<Vec<f32>>>,
+    }
+
+    fn flaky_sink(directory: &Path, available: &Arc<AtomicBool>, written: &Arc<std::sync::Mutex<Vec<f32>>>) -> BufferedSink {
+        BufferedSink {
+            sink: Box::new(FlakySink {
+                available: available.clone(),
+                written: written.clone(),
+            }),
+            buffer: Some(Mutex::new(DiskBuffer::open(directory, "flaky", 10, Duration::hours(1)).unwrap())),
+            events: Some(Mutex::new(DiskBuffer::open(directory, "flaky.events", 10, Duration::hours(1)).unwrap())),
+        }
+    }
+
+    #[async_trait]
+    impl Sink for FlakySink {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+            if !self.available.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("down"));
+            }
+            if snapshot.room_temperature < 0.0 {
+                return Err(Rejected("below zero".to_string()).into());
+            }
+            self.written.lock().unwrap().push(snapshot.room_temperature);
+            Ok(())
+        }
+
+        async fn write_events(&self, events: &[DeviceEvent]) -> Result<(), anyhow::Error> {
+            if !self.available.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("down"));
+            }
+            self.written.lock().unwrap().extend(events.iter().map(|event| event.device_id as f32));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffer_survives_reopen_and_prunes() {
+        let directory = temp_directory("reopen");
+
+        let mut buffer = DiskBuffer::<DeviceSnapshot>::open(&directory, "sink", 2, Duration::hours(1)).unwrap();
+        buffer.push(snapshot(120, 1.0)).await.unwrap();
+        buffer.push(snapshot(2, 2.0)).await.unwrap();
+        buffer.push(snapshot(1, 3.0)).await.unwrap();
+        assert_eq!(2, buffer.len());
+
+        let mut buffer = DiskBuffer::<DeviceSnapshot>::open(&directory, "sink", 2, Duration::hours(1)).unwrap();
+        assert_eq!(Some(2.0), buffer.pop_front().map(|s| s.room_temperature));
+        assert_eq!(Some(3.0), buffer.pop_front().map(|s| s.room_temperature));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_buffered_sink_replays_in_order() {
+        let directory = temp_directory("replay");
+        let available = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = flaky_sink(&directory, &available, &written);
+
+        sink.write(&snapshot(3, 1.0)).await;
+        sink.write(&snapshot(2, 2.0)).await;
+        assert!(written.lock().unwrap().is_empty());
+
+        available.store(true, Ordering::SeqCst);
+        sink.write(&snapshot(1, 3.0)).await;
+        assert_eq!(vec![1.0, 2.0, 3.0], *written.lock().unwrap());
+
+        let buffer = DiskBuffer::<DeviceSnapshot>::open(&directory, "flaky", 10, Duration::hours(1)).unwrap();
+        assert!(buffer.is_empty());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_snapshot_does_not_block_replay() {
+        let directory = temp_directory("rejected");
+        let available = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = flaky_sink(&directory, &available, &written);
+
+        sink.write(&snapshot(3, -1.0)).await;
+        sink.write(&snapshot(2, 2.0)).await;
+
+        available.store(true, Ordering::SeqCst);
+        sink.write(&snapshot(1, 3.0)).await;
+        assert_eq!(vec![2.0, 3.0], *written.lock().unwrap());
+
+        let rejected = fs::read_to_string(directory.join("flaky.rejected.jsonl")).unwrap();
+        assert_eq!(1, rejected.lines().count());
+        assert!(DiskBuffer::<DeviceSnapshot>::open(&directory, "flaky", 10, Duration::hours(1)).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_events_are_buffered_apart() {
+        let directory = temp_directory("events");
+        let available = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = flaky_sink(&directory, &available, &written);
+
+        let mut detector = EventDetector::default();
+        let mut first = DeviceSnapshot::for_test(7, Utc::now() - Duration::minutes(2));
+        detector.detect(&first);
+        first.time += Duration::minutes(1);
+        first.power = false;
+        let events = detector.detect(&first);
+        assert_eq!(1, events.len());
+
+        sink.write_events(&events).await;
+        assert_eq!(1, DiskBuffer::<DeviceEvent>::open(&directory, "flaky.events", 10, Duration::hours(1)).unwrap().len());
+
+        available.store(true, Ordering::SeqCst);
+        sink.flush().await.unwrap();
+        assert_eq!(vec![7.0], *written.lock().unwrap());
+        assert!(DiskBuffer::<DeviceEvent>::open(&directory, "flaky.events", 10, Duration::hours(1)).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_flush_replays_and_keeps_the_rest() {
+        let directory = temp_directory("flush");
+        let available = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = flaky_sink(&directory, &available, &written);
+
+        sink.write(&snapshot(2, 1.0)).await;
+        sink.flush().await.unwrap();
+        assert_eq!(1, DiskBuffer::<DeviceSnapshot>::open(&directory, "flaky", 10, Duration::hours(1)).unwrap().len());
+
+        available.store(true, Ordering::SeqCst);
+        sink.flush().await.unwrap();
+        assert_eq!(vec![1.0], *written.lock().unwrap());
+        assert!(DiskBuffer::<DeviceSnapshot>::open(&directory, "flaky", 10, Duration::hours(1)).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{NaiveDate, Utc};
+
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::files::parquet::{self, ParquetCompression};
+use crate::storage::influxdb::current_data::CurrentData;
+use crate::storage::sink::Sink;
+
+pub fn is_enabled() -> bool {
+    dotenv::var("CSV_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn directory() -> PathBuf {
+    PathBuf::from(dotenv::var("CSV_DIRECTORY").unwrap_or_else(|_| "data".to_string()))
+}
+
+pub fn daily_path(directory: &Path, date: NaiveDate, extension: &str) -> PathBuf {
+    directory.join(format!("melcloud-{}.{}", date.format("%Y-%m-%d"), extension))
+}
+
+/// The date of a `melcloud-<date>.csv` file
+pub fn file_date(path: &Path) -> Option<NaiveDate> {
+    let stem = path.file_stem()?.to_str()?.strip_prefix("melcloud-")?;
+    NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok()
+}
+
+/// The header and the row of the sample
+fn csv_row(snapshot: &DeviceSnapshot) -> Result<(::csv::StringRecord, ::csv::StringRecord), anyhow::Error> {
+    let mut writer = ::csv::Writer::from_writer(Vec::new());
+    writer.serialize(CurrentData::from(snapshot))?;
+    let data = writer.into_inner()?;
+
+    let mut reader = ::csv::Reader::from_reader(data.as_slice());
+    let header = reader.headers()?.clone();
+    let row = reader
+        .records()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No CSV row for the sample"))??;
+    Ok((header, row))
+}
+
+fn append_snapshot(directory: &Path, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+    let path = daily_path(directory, snapshot.time.date_naive(), "csv");
+    let (header, row) = csv_row(snapshot)?;
+
+    // A file started by an older version keeps its columns, the new ones start from the next day
+    let file_header = match fs::metadata(&path) {
+        Ok(metadata) if metadata.len() > 0 => Some(::csv::Reader::from_path(&path)?.headers()?.clone()),
+        _ => None,
+    };
+
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut writer = ::csv::WriterBuilder::new().has_headers(false).from_writer(file);
+    match file_header {
+        Some(file_header) if file_header != header => {
+            let fields = file_header
+                .iter()
+                .map(|column| header.iter().position(|name| name == column).map_or("", |index| &row[index]));
+            writer.write_record(fields)?;
+        }
+        Some(_) => writer.write_record(&row)?,
+        None => {
+            writer.write_record(&header)?;
+            writer.write_record(&row)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Appends the samples into one CSV file per UTC day, and converts the finished days into
+/// Parquet when it is enabled
+pub struct CsvSink {
+    directory: PathBuf,
+    parquet: Option<ParquetCompression>,
+    current_date: Arc<Mutex<Option<NaiveDate>>>,
+}
+
+impl CsvSink {
+    pub fn open(directory: PathBuf, parquet: Option<ParquetCompression>) -> Result<CsvSink, anyhow::Error> {
+        fs::create_dir_all(&directory)?;
+        if let Some(compression) = parquet {
+            parquet::convert_finished_days(&directory, Utc::now().date_naive(), compression)?;
+        }
+
+        Ok(CsvSink {
+            directory,
+            parquet,
+            current_date: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for CsvSink {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        let directory = self.directory.clone();
+        let parquet = self.parquet;
+        let current_date = self.current_date.clone();
+        let snapshot = snapshot.clone();
+
+        tokio::task::spawn_blocking(move || {
+            append_snapshot(&directory, &snapshot)?;
+
+            let date = snapshot.time.date_naive();
+            let mut current_date = current_date.lock().unwrap();
+            if current_date.is_some_and(|current_date| date > current_date) {
+                if let Some(compression) = parquet {
+                    parquet::convert_finished_days(&directory, date, compression)?;
+                }
+            }
+            if current_date.is_none_or(|current_date| date > current_date) {
+                *current_date = Some(date);
+            }
+
+            Ok(())
+        })
+        .await?
+    }
+}
+
+pub fn connect() -> Result<CsvSink, anyhow::Error> {
+    let directory = directory();
+    let parquet = parquet::compression();
+    info!(
+        "Writing daily CSV files into {}{}",
+        directory.display(),
+        parquet.map(|compression| format!(" and Parquet files with {:?}", compression)).unwrap_or_default()
+    );
+
+    CsvSink::open(directory, parquet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::energy::EnergyDeltas;
+    use chrono::{Duration, TimeZone};
+
+    #[tokio::test]
+    async fn test_rotates_daily_and_converts_to_parquet() {
+        let directory = std::env::temp_dir().join(format!("melcloud-csv-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let sink = CsvSink::open(directory.clone(), Some(ParquetCompression::Zstd)).unwrap();
+
+        // The first day was started by a version without the energy deltas
+        let time = Utc.with_ymd_and_hms(2024, 1, 1, 23, 50, 0).unwrap();
+        let first_day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let (header, _) = csv_row(&DeviceSnapshot::for_test(1, time)).unwrap();
+        let legacy: Vec<&str> = header.iter().take_while(|&column| column != "heating_energy_delta").collect();
+        fs::write(daily_path(&directory, first_day, "csv"), legacy.join(",") + "\n").unwrap();
+
+        let mut snapshot = DeviceSnapshot::for_test(1, time);
+        snapshot.heating_energy_consumed_rate1 = Some(1.5);
+        snapshot.energy = Some(EnergyDeltas {
+            total: Some(0.25),
+            ..Default::default()
+        });
+        sink.write(&snapshot).await.unwrap();
+        sink.write(&DeviceSnapshot::for_test(2, time)).await.unwrap();
+        sink.write(&DeviceSnapshot::for_test(1, time + Duration::minutes(20))).await.unwrap();
+
+        let csv = fs::read_to_string(daily_path(&directory, first_day, "csv")).unwrap();
+        assert_eq!(3, csv.lines().count());
+        assert!(csv.starts_with("time,device_id,device_type,power"));
+        assert!(csv.lines().all(|line| line.split(',').count() == legacy.len()));
+
+        let rows = parquet::read_rows(&daily_path(&directory, first_day, "parquet")).unwrap();
+        assert_eq!(2, rows);
+        assert!(daily_path(&directory, first_day + Duration::days(1), "csv").exists());
+        assert!(!daily_path(&directory, first_day + Duration::days(1), "parquet").exists());
+        let csv = fs::read_to_string(daily_path(&directory, first_day + Duration::days(1), "csv")).unwrap();
+        assert!(csv.lines().next().unwrap().contains(",total_energy_delta,"));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
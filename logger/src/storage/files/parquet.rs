@@ -0,0 +1,167 @@
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Float32Array, Int64Array, RecordBatch, StringArray, TimestampMicrosecondArray, UInt32Array, UInt8Array,
+};
+use chrono::NaiveDate;
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::{Compression, ZstdLevel};
+use ::parquet::file::properties::WriterProperties;
+
+use crate::storage::files::csv::{daily_path, file_date};
+use crate::storage::influxdb::current_data::CurrentData;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParquetCompression {
+    Uncompressed,
+    Snappy,
+    Zstd,
+}
+
+impl From<ParquetCompression> for Compression {
+    fn from(compression: ParquetCompression) -> Self {
+        match compression {
+            ParquetCompression::Uncompressed => Compression::UNCOMPRESSED,
+            ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
+        }
+    }
+}
+
+/// The compression for the Parquet files, `None` when they are not written
+pub fn compression() -> Option<ParquetCompression> {
+    let enabled = dotenv::var("PARQUET_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap();
+    if !enabled {
+        return None;
+    }
+
+    match dotenv::var("PARQUET_COMPRESSION").as_deref() {
+        Ok("snappy") | Err(_) => Some(ParquetCompression::Snappy),
+        Ok("zstd") => Some(ParquetCompression::Zstd),
+        Ok("none") => Some(ParquetCompression::Uncompressed),
+        Ok(value) => panic!("PARQUET_COMPRESSION must be snappy, zstd or none, not {}", value),
+    }
+}
+
+macro_rules! column {
+    ($rows:expr, $array:ty, $field:ident) => {
+        Arc::new(<$array>::from($rows.iter().map(|row| row.$field).collect::<Vec<_>>())) as ArrayRef
+    };
+    ($rows:expr, $array:ty, $field:ident, clone) => {
+        Arc::new(<$array>::from($rows.iter().map(|row| row.$field.clone()).collect::<Vec<_>>())) as ArrayRef
+    };
+}
+
+fn record_batch(rows: &[CurrentData]) -> Result<RecordBatch, anyhow::Error> {
+    let time = TimestampMicrosecondArray::from(rows.iter().map(|row| row.time.timestamp_micros()).collect::<Vec<_>>())
+        .with_timezone("UTC");
+
+    Ok(RecordBatch::try_from_iter(vec![
+        ("time", Arc::new(time) as ArrayRef),
+        ("device_id", column!(rows, UInt32Array, device_id)),
+        ("device_type", column!(rows, UInt8Array, device_type)),
+        ("power", column!(rows, BooleanArray, power)),
+        ("offline", column!(rows, BooleanArray, offline)),
+        ("room_temperature", column!(rows, Float32Array, room_temperature)),
+        ("set_temperature", column!(rows, Float32Array, set_temperature)),
+        ("last_communication", column!(rows, StringArray, last_communication, clone)),
+        ("actual_fan_speed", column!(rows, UInt8Array, actual_fan_speed)),
+        ("fan_speed", column!(rows, UInt8Array, fan_speed)),
+        ("automatic_fan_speed", column!(rows, BooleanArray, automatic_fan_speed)),
+        ("vane_vertical_direction", column!(rows, UInt8Array, vane_vertical_direction)),
+        ("vane_vertical_swing", column!(rows, BooleanArray, vane_vertical_swing)),
+        ("vane_horizontal_direction", column!(rows, UInt8Array, vane_horizontal_direction)),
+        ("vane_horizontal_swing", column!(rows, BooleanArray, vane_horizontal_swing)),
+        ("operation_mode", column!(rows, UInt8Array, operation_mode)),
+        ("in_standby_mode", column!(rows, BooleanArray, in_standby_mode)),
+        ("heating_energy_consumed_rate1", column!(rows, Float32Array, heating_energy_consumed_rate1)),
+        ("heating_energy_consumed_rate2", column!(rows, Float32Array, heating_energy_consumed_rate2)),
+        ("cooling_energy_consumed_rate1", column!(rows, Float32Array, cooling_energy_consumed_rate1)),
+        ("cooling_energy_consumed_rate2", column!(rows, Float32Array, cooling_energy_consumed_rate2)),
+        ("auto_energy_consumed_rate1", column!(rows, Float32Array, auto_energy_consumed_rate1)),
+        ("auto_energy_consumed_rate2", column!(rows, Float32Array, auto_energy_consumed_rate2)),
+        ("dry_energy_consumed_rate1", column!(rows, Float32Array, dry_energy_consumed_rate1)),
+        ("dry_energy_consumed_rate2", column!(rows, Float32Array, dry_energy_consumed_rate2)),
+        ("fan_energy_consumed_rate1", column!(rows, Float32Array, fan_energy_consumed_rate1)),
+        ("fan_energy_consumed_rate2", column!(rows, Float32Array, fan_energy_consumed_rate2)),
+        ("other_energy_consumed_rate1", column!(rows, Float32Array, other_energy_consumed_rate1)),
+        ("other_energy_consumed_rate2", column!(rows, Float32Array, other_energy_consumed_rate2)),
+        ("current_energy_consumed", column!(rows, Float32Array, current_energy_consumed)),
+        ("current_energy_mode", column!(rows, UInt8Array, current_energy_mode)),
+        ("energy_correction_model", column!(rows, Float32Array, energy_correction_model)),
+        ("energy_correction_active", column!(rows, BooleanArray, energy_correction_active)),
+        ("wifi_signal_strength", column!(rows, Float32Array, wifi_signal_strength)),
+        ("wifi_adapter_status", column!(rows, StringArray, wifi_adapter_status, clone)),
+        ("has_error", column!(rows, BooleanArray, has_error)),
+        ("heating_energy_delta", column!(rows, Float32Array, heating_energy_delta)),
+        ("cooling_energy_delta", column!(rows, Float32Array, cooling_energy_delta)),
+        ("auto_energy_delta", column!(rows, Float32Array, auto_energy_delta)),
+        ("dry_energy_delta", column!(rows, Float32Array, dry_energy_delta)),
+        ("fan_energy_delta", column!(rows, Float32Array, fan_energy_delta)),
+        ("other_energy_delta", column!(rows, Float32Array, other_energy_delta)),
+        ("total_energy_delta", column!(rows, Float32Array, total_energy_delta)),
+        ("energy_delta_seconds", column!(rows, Int64Array, energy_delta_seconds)),
+        ("energy_counter_reset", column!(rows, BooleanArray, energy_counter_reset)),
+        ("energy_gap", column!(rows, BooleanArray, energy_gap)),
+        ("energy_cost", column!(rows, Float32Array, energy_cost)),
+        ("energy_price", column!(rows, Float32Array, energy_price)),
+    ])?)
+}
+
+/// Converts a daily CSV file into Parquet, returns the number of rows
+fn convert(csv_path: &Path, parquet_path: &Path, compression: ParquetCompression) -> Result<usize, anyhow::Error> {
+    let rows = ::csv::Reader::from_path(csv_path)?
+        .deserialize()
+        .collect::<Result<Vec<CurrentData>, _>>()?;
+    let batch = record_batch(&rows)?;
+
+    // Written aside and renamed so that readers never see a half written file
+    let temp_path = parquet_path.with_extension("parquet.tmp");
+    let properties = WriterProperties::builder().set_compression(compression.into()).build();
+    let mut writer = ArrowWriter::try_new(File::create(&temp_path)?, batch.schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    fs::rename(&temp_path, parquet_path)?;
+
+    Ok(rows.len())
+}
+
+/// Converts the CSV files of the days before `today` that have no Parquet file yet, or got
+/// late samples after the conversion
+pub fn convert_finished_days(directory: &Path, today: NaiveDate, compression: ParquetCompression) -> Result<(), anyhow::Error> {
+    for entry in fs::read_dir(directory)? {
+        let csv_path = entry?.path();
+        if csv_path.extension().is_none_or(|extension| extension != "csv") {
+            continue;
+        }
+        let date = match file_date(&csv_path) {
+            Some(date) if date < today => date,
+            _ => continue,
+        };
+
+        let parquet_path = daily_path(directory, date, "parquet");
+        if parquet_path.exists() && fs::metadata(&parquet_path)?.modified()? >= fs::metadata(&csv_path)?.modified()? {
+            continue;
+        }
+
+        match convert(&csv_path, &parquet_path, compression) {
+            Ok(rows) => info!("Wrote {} row(s) into {}", rows, parquet_path.display()),
+            Err(err) => error!("Failed to convert {} into Parquet: {}", csv_path.display(), err),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn read_rows(path: &Path) -> Result<i64, anyhow::Error> {
+    use ::parquet::file::reader::{FileReader, SerializedFileReader};
+
+    let reader = SerializedFileReader::new(File::open(path)?)?;
+    Ok(reader.metadata().file_metadata().num_rows())
+}
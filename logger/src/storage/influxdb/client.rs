@@ -0,0 +1,293 @@
+use http::header::AUTHORIZATION;
+use influxdb::{Query, WriteQuery};
+
+use crate::storage::sink::Rejected;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InfluxVersion {
+    V1,
+    V2,
+    V3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    fn parse(value: &str) -> Result<Precision, anyhow::Error> {
+        match value {
+            "ns" | "n" => Ok(Precision::Nanoseconds),
+            "us" | "u" => Ok(Precision::Microseconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "s" => Ok(Precision::Seconds),
+            _ => Err(anyhow::anyhow!("Unsupported InfluxDB precision {}", value)),
+        }
+    }
+
+    fn as_query_param(&self, version: InfluxVersion) -> &'static str {
+        match (self, version) {
+            (Precision::Nanoseconds, _) => "ns",
+            (Precision::Microseconds, InfluxVersion::V1) => "u",
+            (Precision::Microseconds, _) => "us",
+            (Precision::Milliseconds, _) => "ms",
+            (Precision::Seconds, _) => "s",
+        }
+    }
+
+    fn nanos_per_unit(&self) -> u128 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Auth {
+    None,
+    Basic { username: String, password: String },
+    Token(String),
+}
+
+/// Writes line protocol either to the 1.x `/write` endpoint or to the `/api/v2/write`
+/// endpoint shared by InfluxDB 2.x and 3.x.
+#[derive(Debug, Clone)]
+pub struct InfluxClient {
+    http: reqwest::Client,
+    url: String,
+    version: InfluxVersion,
+    database: String,
+    org: Option<String>,
+    auth: Auth,
+    precision: Precision,
+}
+
+impl InfluxClient {
+    pub fn from_env() -> Result<InfluxClient, anyhow::Error> {
+        let url = dotenv::var("INFLUXDB_CONNECTION_STRING").unwrap_or_else(|_| "http://localhost:8086".to_string());
+        let token = dotenv::var("INFLUXDB_TOKEN").ok();
+
+        let version = match dotenv::var("INFLUXDB_VERSION").ok().as_deref() {
+            Some("1") => InfluxVersion::V1,
+            Some("2") => InfluxVersion::V2,
+            Some("3") => InfluxVersion::V3,
+            Some(version) => return Err(anyhow::anyhow!("Unsupported InfluxDB version {}", version)),
+            None if token.is_some() => InfluxVersion::V2,
+            None => InfluxVersion::V1,
+        };
+
+        let precision = Precision::parse(&dotenv::var("INFLUXDB_PRECISION").unwrap_or_else(|_| "ns".to_string()))?;
+
+        let client = match version {
+            InfluxVersion::V1 => {
+                let auth = match (dotenv::var("INFLUXDB_USERNAME"), dotenv::var("INFLUXDB_PASSWORD")) {
+                    (Ok(username), Ok(password)) => Auth::Basic { username, password },
+                    _ => Auth::None,
+                };
+                let database = dotenv::var("INFLUXDB_DATABASE_NAME").unwrap_or_else(|_| "melcloud".to_string());
+
+                InfluxClient::new(&url, version, &database, None, auth, precision)
+            }
+            InfluxVersion::V2 | InfluxVersion::V3 => {
+                let token = token.ok_or_else(|| anyhow::anyhow!("INFLUXDB_TOKEN is required for InfluxDB 2.x and 3.x"))?;
+                let org = dotenv::var("INFLUXDB_ORG").ok();
+                if version == InfluxVersion::V2 && org.is_none() {
+                    return Err(anyhow::anyhow!("INFLUXDB_ORG is required for InfluxDB 2.x"));
+                }
+                let bucket = dotenv::var("INFLUXDB_BUCKET")
+                    .or_else(|_| dotenv::var("INFLUXDB_DATABASE_NAME"))
+                    .unwrap_or_else(|_| "melcloud".to_string());
+
+                InfluxClient::new(&url, version, &bucket, org, Auth::Token(token), precision)
+            }
+        };
+
+        Ok(client)
+    }
+
+    fn new(url: &str, version: InfluxVersion, database: &str, org: Option<String>, auth: Auth, precision: Precision) -> InfluxClient {
+        InfluxClient {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            version,
+            database: database.to_string(),
+            org,
+            auth,
+            precision,
+        }
+    }
+
+    pub fn version(&self) -> InfluxVersion {
+        self.version
+    }
+
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    fn write_request(&self) -> reqwest::RequestBuilder {
+        let precision = self.precision.as_query_param(self.version);
+        let request = match self.version {
+            InfluxVersion::V1 => self
+                .http
+                .post(format!("{}/write", self.url))
+                .query(&[("db", self.database.as_str()), ("precision", precision)]),
+            InfluxVersion::V2 | InfluxVersion::V3 => {
+                let mut params = vec![("bucket", self.database.as_str()), ("precision", precision)];
+                if let Some(org) = &self.org {
+                    params.push(("org", org.as_str()));
+                }
+                self.http.post(format!("{}/api/v2/write", self.url)).query(&params)
+            }
+        };
+
+        self.authorize(request)
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.auth {
+            Auth::None => request,
+            Auth::Basic { username, password } => request.basic_auth(username, Some(password)),
+            Auth::Token(token) => request.header(AUTHORIZATION, format!("Token {}", token)),
+        }
+    }
+
+    /// Converts the nanosecond timestamp produced by `InfluxDbWriteable` into the configured precision
+    fn line_with_precision(&self, line: &str) -> Result<String, anyhow::Error> {
+        let (rest, timestamp) = line
+            .rsplit_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Line protocol without a timestamp: {}", line))?;
+        let timestamp: u128 = timestamp.parse()?;
+
+        Ok(format!("{} {}", rest, timestamp / self.precision.nanos_per_unit()))
+    }
+
+    /// Runs an InfluxQL query through `/query`, which 2.x and 3.x serve for 1.x compatibility
+    pub async fn query(&self, query: &str) -> Result<serde_json::Value, anyhow::Error> {
+        let request = self
+            .http
+            .get(format!("{}/query", self.url))
+            .query(&[("db", self.database.as_str()), ("q", query), ("epoch", "s")]);
+
+        let res = self.authorize(request).send().await?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("InfluxDB responded with {}: {}", status, body));
+        }
+
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    pub async fn write(&self, query: WriteQuery) -> Result<(), anyhow::Error> {
+        let line = query.build()?.get();
+        let body = self.line_with_precision(&line)?;
+
+        let res = self.write_request().body(body).send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            let message = res.text().await.unwrap_or_default();
+            // Malformed points or field type conflicts, the rest may clear up on a retry
+            if matches!(status.as_u16(), 400 | 413 | 422) {
+                return Err(Rejected(format!("InfluxDB rejected the write with {}: {}", status, message)).into());
+            }
+            return Err(anyhow::anyhow!("InfluxDB responded with {}: {}", status, message));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use influxdb::{InfluxDbWriteable, Timestamp};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Accepts a single request and answers it with 204 No Content
+    async fn spawn_stand_in() -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(|v| v.parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        (url, handle)
+    }
+
+    fn query() -> WriteQuery {
+        let time = Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+        Timestamp::from(time)
+            .into_query("melCloudDeviceData")
+            .add_tag("device_id", 42)
+            .add_field("room_temperature", 21.5)
+    }
+
+    #[tokio::test]
+    async fn test_write_v2() {
+        let (url, handle) = spawn_stand_in().await;
+        let client = InfluxClient::new(
+            &url,
+            InfluxVersion::V2,
+            "melcloud",
+            Some("home".to_string()),
+            Auth::Token("secret".to_string()),
+            Precision::Seconds,
+        );
+
+        client.write(query()).await.unwrap();
+
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("POST /api/v2/write?bucket=melcloud&precision=s&org=home "));
+        assert!(request.to_ascii_lowercase().contains("authorization: token secret"));
+        assert!(request.ends_with("melCloudDeviceData,device_id=42 room_temperature=21.5 1704164645"));
+    }
+
+    #[tokio::test]
+    async fn test_write_v1_basic_auth() {
+        let (url, handle) = spawn_stand_in().await;
+        let auth = Auth::Basic {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        };
+        let client = InfluxClient::new(&url, InfluxVersion::V1, "melcloud", None, auth, Precision::Microseconds);
+
+        client.write(query()).await.unwrap();
+
+        let request = handle.await.unwrap();
+        assert!(request.starts_with("POST /write?db=melcloud&precision=u "));
+        assert!(request.to_ascii_lowercase().contains("authorization: basic dxnlcjpwyxnz"));
+        assert!(request.ends_with(" 1704164645000000"));
+    }
+}
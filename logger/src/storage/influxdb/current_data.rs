@@ -2,6 +2,8 @@ use chrono::{DateTime, Utc};
 use influxdb::InfluxDbWriteable;
 use serde::{Deserialize, Serialize};
 
+use crate::app::snapshot::DeviceSnapshot;
+
 #[derive(Debug, InfluxDbWriteable, Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct CurrentData {
@@ -48,4 +50,81 @@ pub struct CurrentData {
     pub wifi_adapter_status: Option<String>,
 
     pub has_error: Option<bool>,
+
+    // Missing from the files written before the energy deltas and their cost
+    pub heating_energy_delta: Option<f32>,
+    pub cooling_energy_delta: Option<f32>,
+    pub auto_energy_delta: Option<f32>,
+    pub dry_energy_delta: Option<f32>,
+    pub fan_energy_delta: Option<f32>,
+    pub other_energy_delta: Option<f32>,
+    pub total_energy_delta: Option<f32>,
+    pub energy_delta_seconds: Option<i64>,
+    pub energy_counter_reset: Option<bool>,
+    pub energy_gap: Option<bool>,
+    pub energy_cost: Option<f32>,
+    pub energy_price: Option<f32>,
+}
+
+impl From<&DeviceSnapshot> for CurrentData {
+    fn from(snapshot: &DeviceSnapshot) -> Self {
+        CurrentData {
+            time: snapshot.time,
+
+            device_id: snapshot.device_id,
+
+            device_type: snapshot.device_type,
+            power: snapshot.power,
+            offline: snapshot.offline,
+            room_temperature: snapshot.room_temperature,
+            set_temperature: snapshot.set_temperature,
+            last_communication: snapshot.last_communication.clone(),
+
+            actual_fan_speed: snapshot.actual_fan_speed,
+            fan_speed: snapshot.fan_speed,
+            automatic_fan_speed: snapshot.automatic_fan_speed,
+            vane_vertical_direction: snapshot.vane_vertical_direction,
+            vane_vertical_swing: snapshot.vane_vertical_swing,
+            vane_horizontal_direction: snapshot.vane_horizontal_direction,
+            vane_horizontal_swing: snapshot.vane_horizontal_swing,
+            operation_mode: snapshot.operation_mode,
+            in_standby_mode: snapshot.in_standby_mode,
+
+            heating_energy_consumed_rate1: snapshot.heating_energy_consumed_rate1,
+            heating_energy_consumed_rate2: snapshot.heating_energy_consumed_rate2,
+            cooling_energy_consumed_rate1: snapshot.cooling_energy_consumed_rate1,
+            cooling_energy_consumed_rate2: snapshot.cooling_energy_consumed_rate2,
+            auto_energy_consumed_rate1: snapshot.auto_energy_consumed_rate1,
+            auto_energy_consumed_rate2: snapshot.auto_energy_consumed_rate2,
+            dry_energy_consumed_rate1: snapshot.dry_energy_consumed_rate1,
+            dry_energy_consumed_rate2: snapshot.dry_energy_consumed_rate2,
+            fan_energy_consumed_rate1: snapshot.fan_energy_consumed_rate1,
+            fan_energy_consumed_rate2: snapshot.fan_energy_consumed_rate2,
+            other_energy_consumed_rate1: snapshot.other_energy_consumed_rate1,
+            other_energy_consumed_rate2: snapshot.other_energy_consumed_rate2,
+
+            current_energy_consumed: snapshot.current_energy_consumed,
+            current_energy_mode: snapshot.current_energy_mode,
+            energy_correction_model: snapshot.energy_correction_model,
+            energy_correction_active: snapshot.energy_correction_active,
+
+            wifi_signal_strength: snapshot.wifi_signal_strength,
+            wifi_adapter_status: snapshot.wifi_adapter_status.clone(),
+
+            has_error: snapshot.has_error,
+
+            heating_energy_delta: snapshot.energy.and_then(|energy| energy.heating),
+            cooling_energy_delta: snapshot.energy.and_then(|energy| energy.cooling),
+            auto_energy_delta: snapshot.energy.and_then(|energy| energy.auto),
+            dry_energy_delta: snapshot.energy.and_then(|energy| energy.dry),
+            fan_energy_delta: snapshot.energy.and_then(|energy| energy.fan),
+            other_energy_delta: snapshot.energy.and_then(|energy| energy.other),
+            total_energy_delta: snapshot.energy.and_then(|energy| energy.total),
+            energy_delta_seconds: snapshot.energy.map(|energy| energy.seconds),
+            energy_counter_reset: snapshot.energy.map(|energy| energy.reset),
+            energy_gap: snapshot.energy.map(|energy| energy.gap),
+            energy_cost: snapshot.energy.and_then(|energy| energy.cost),
+            energy_price: snapshot.energy.and_then(|energy| energy.price),
+        }
+    }
 }
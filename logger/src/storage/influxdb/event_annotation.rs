@@ -0,0 +1,34 @@
+use chrono::{DateTime, Utc};
+use influxdb::InfluxDbWriteable;
+
+use crate::app::events::DeviceEvent;
+
+/// A state change for Grafana's annotations, `text` is the description and the tags are
+/// available as annotation tags
+#[derive(Debug, InfluxDbWriteable)]
+pub struct EventAnnotation {
+    pub time: DateTime<Utc>,
+    #[influxdb(tag)]
+    pub device_id: u32,
+    #[influxdb(tag)]
+    pub kind: String,
+
+    pub title: String,
+    pub text: String,
+    pub from_value: Option<String>,
+    pub to_value: Option<String>,
+}
+
+impl From<&DeviceEvent> for EventAnnotation {
+    fn from(event: &DeviceEvent) -> Self {
+        EventAnnotation {
+            time: event.time,
+            device_id: event.device_id,
+            kind: event.kind.as_str().to_string(),
+            title: event.device_name.clone().unwrap_or_else(|| event.device_id.to_string()),
+            text: event.description(),
+            from_value: event.from.clone(),
+            to_value: event.to.clone(),
+        }
+    }
+}
@@ -1,7 +1,13 @@
-use api::{CurrentDataResponse, ListDevicesResponse};
-use influxdb::{Client, InfluxDbWriteable};
+use std::collections::HashMap;
 
-use crate::storage::influxdb::current_data::CurrentData;
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use influxdb::InfluxDbWriteable;
+
+use crate::app::events::DeviceEvent;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::influxdb::{client::InfluxClient, current_data::CurrentData, event_annotation::EventAnnotation};
+use crate::storage::sink::{is_rejected, Sink};
 
 pub fn is_enabled() -> bool {
     dotenv::var("INFLUXDB_ENABLED")
@@ -10,152 +16,89 @@ pub fn is_enabled() -> bool {
         .unwrap()
 }
 
-pub async fn upsert_device_list_entry_into_influxdb(client: &Client, data: &Vec<ListDevicesResponse>) -> Result<(), anyhow::Error> {
+pub async fn upsert_snapshot_into_influxdb(client: &InfluxClient, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
     if !is_enabled() {
         return Ok(());
     }
 
-    let list_devices = &data[0];
-    let structure = &list_devices.structure;
-    let devices = &structure.devices;
-    let device = &devices[0].device;
-
-    let system_time = device.last_time_stamp_to_utc_datetime();
-    if system_time.is_none() {
-        return Err(anyhow::anyhow!("Skipping logging because system time couldn't be parsed"));
-    }
-
-    let system_time = system_time.unwrap();
-    info!("System Time UTC: {:?}", system_time);
-    let current_data = CurrentData {
-        time: system_time,
-
-        device_id: device.device_iD,
-
-        device_type: device.device_type,
-        power: device.power,
-        offline: device.offline,
-        room_temperature: device.room_temperature,
-        set_temperature: device.set_temperature,
-        last_communication: device.last_time_stamp.clone(),
-
-        actual_fan_speed: device.actual_fan_speed,
-        fan_speed: device.fan_speed,
-        automatic_fan_speed: device.automatic_fan_speed,
-        vane_vertical_direction: device.vane_vertical_direction,
-        vane_vertical_swing: device.vane_vertical_swing,
-        vane_horizontal_direction: device.vane_horizontal_direction,
-        vane_horizontal_swing: device.vane_horizontal_swing,
-        operation_mode: device.operation_mode,
-        in_standby_mode: device.in_standby_mode,
-    
-        heating_energy_consumed_rate1: device.heating_energy_consumed_rate1,
-        heating_energy_consumed_rate2: device.heating_energy_consumed_rate2,
-        cooling_energy_consumed_rate1: device.cooling_energy_consumed_rate1,
-        cooling_energy_consumed_rate2: device.cooling_energy_consumed_rate2,
-        auto_energy_consumed_rate1: device.auto_energy_consumed_rate1,
-        auto_energy_consumed_rate2: device.auto_energy_consumed_rate2,
-        dry_energy_consumed_rate1: device.dry_energy_consumed_rate1,
-        dry_energy_consumed_rate2: device.dry_energy_consumed_rate2,
-        fan_energy_consumed_rate1: device.fan_energy_consumed_rate1,
-        fan_energy_consumed_rate2: device.fan_energy_consumed_rate2,
-        other_energy_consumed_rate1: device.other_energy_consumed_rate1,
-        other_energy_consumed_rate2: device.other_energy_consumed_rate2,
-    
-        current_energy_consumed: device.current_energy_consumed,
-        current_energy_mode: device.current_energy_mode,
-        energy_correction_model: device.energy_correction_model,
-        energy_correction_active: device.energy_correction_active,
-    
-        wifi_signal_strength: device.wifi_signal_strength,
-        wifi_adapter_status: device.wifi_adapter_status.clone(),
-    
-        has_error: device.has_error,
-    };
+    let current_data = CurrentData::from(snapshot);
 
     let write_result = client
-        .query(&current_data.into_query("melCloudDeviceData"))
+        .write(current_data.into_query("melCloudDeviceData"))
         .await;
 
     if let Err(err) = write_result {
+        if is_rejected(&err) {
+            return Err(err);
+        }
         return Err(anyhow::anyhow!("Error writing to db: {}", err));
     }
 
     Ok(())
 }
 
-pub async fn upsert_current_data_into_influxdb(client: &Client, data: &CurrentDataResponse) -> Result<(), anyhow::Error> {
-    if !is_enabled() {
-        return Ok(());
+pub async fn insert_events_into_influxdb(client: &InfluxClient, events: &[DeviceEvent]) -> Result<(), anyhow::Error> {
+    for event in events {
+        client
+            .write(EventAnnotation::from(event).into_query("melCloudEvents"))
+            .await
+            .map_err(|err| if is_rejected(&err) { err } else { anyhow::anyhow!("Error writing to db: {}", err) })?;
+    }
+
+    Ok(())
+}
+
+pub async fn select_last_written_from_influxdb(client: &InfluxClient) -> Result<HashMap<u32, DateTime<Utc>>, anyhow::Error> {
+    let response = client
+        .query(r#"SELECT last("power") FROM "melCloudDeviceData" WHERE time > now() - 7d GROUP BY "device_id""#)
+        .await?;
+
+    if let Some(error) = response.pointer("/results/0/error").and_then(|error| error.as_str()) {
+        return Err(anyhow::anyhow!("Query failed: {}", error));
     }
 
-    let system_time = data.last_communication_to_utc_datetime();
-    if system_time.is_none() {
-        return Err(anyhow::anyhow!("Skipping logging because system time couldn't be parsed"));
+    let mut last_written = HashMap::new();
+    let series = response.pointer("/results/0/series").and_then(|series| series.as_array());
+    for serie in series.into_iter().flatten() {
+        let device_id = serie
+            .pointer("/tags/device_id")
+            .and_then(|device_id| device_id.as_str())
+            .and_then(|device_id| device_id.parse::<u32>().ok());
+        let time = serie
+            .pointer("/values/0/0")
+            .and_then(|time| time.as_i64())
+            .and_then(|time| Utc.timestamp_opt(time, 0).single());
+
+        if let (Some(device_id), Some(time)) = (device_id, time) {
+            last_written.insert(device_id, time);
+        }
     }
 
-    let system_time = system_time.unwrap();
-    info!("System Time UTC: {:?}", system_time);
-    let current_data = CurrentData {
-        time: system_time,
-
-        device_id: data.device_iD,
-
-        device_type: data.device_type,
-        power: data.power,
-        offline: data.offline,
-        room_temperature: data.room_temperature,
-        set_temperature: data.set_temperature,
-        last_communication: data.last_communication.clone(),
-
-        actual_fan_speed: data.set_fan_speed,
-        fan_speed: data.set_fan_speed,
-        automatic_fan_speed: None,
-        vane_vertical_direction: data.vane_vertical,
-        vane_vertical_swing: None,
-        vane_horizontal_direction: data.vane_horizontal,
-        vane_horizontal_swing: None,
-        operation_mode: data.operation_mode,
-        in_standby_mode: data.in_standby_mode,
-    
-        heating_energy_consumed_rate1: None,
-        heating_energy_consumed_rate2: None,
-        cooling_energy_consumed_rate1: None,
-        cooling_energy_consumed_rate2: None,
-        auto_energy_consumed_rate1: None,
-        auto_energy_consumed_rate2: None,
-        dry_energy_consumed_rate1: None,
-        dry_energy_consumed_rate2: None,
-        fan_energy_consumed_rate1: None,
-        fan_energy_consumed_rate2: None,
-        other_energy_consumed_rate1: None,
-        other_energy_consumed_rate2: None,
-    
-        current_energy_consumed: None,
-        current_energy_mode: None,
-        energy_correction_model: None,
-        energy_correction_active: None,
-    
-        wifi_signal_strength: None,
-        wifi_adapter_status: None,
-    
-        has_error: None,
-    };
+    Ok(last_written)
+}
 
-    let write_result = client
-        .query(&current_data.into_query("melCloudDeviceData"))
-        .await;
+#[async_trait]
+impl Sink for InfluxClient {
+    fn name(&self) -> &'static str {
+        "influxdb"
+    }
 
-    if let Err(err) = write_result {
-        return Err(anyhow::anyhow!("Error writing to db: {}", err));
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        upsert_snapshot_into_influxdb(self, snapshot).await
     }
 
-    Ok(())
+    async fn write_events(&self, events: &[DeviceEvent]) -> Result<(), anyhow::Error> {
+        insert_events_into_influxdb(self, events).await
+    }
+
+    async fn last_written(&self) -> Result<Option<HashMap<u32, DateTime<Utc>>>, anyhow::Error> {
+        select_last_written_from_influxdb(self).await.map(Some)
+    }
 }
 
-pub async fn connect_to_db() -> Client {
-    let database_url = dotenv::var("INFLUXDB_CONNECTION_STRING").unwrap_or("http://localhost:8086".to_string());
-    let database_name = dotenv::var("INFLUXDB_DATABASE_NAME").unwrap_or("entsoe".to_string());
+pub async fn connect_to_db() -> Result<InfluxClient, anyhow::Error> {
+    let client = InfluxClient::from_env()?;
+    info!("Writing to InfluxDB {:?} database {}", client.version(), client.database());
 
-    Client::new(&database_url, &database_name)
+    Ok(client)
 }
@@ -1,2 +1,4 @@
+pub mod client;
 pub mod influx;
 pub mod current_data;
+pub mod event_annotation;
@@ -1,2 +1,8 @@
+pub mod buffer;
+pub mod files;
 pub mod influxdb;
-pub mod timescaledb;
\ No newline at end of file
+pub mod mqtt;
+pub mod prometheus;
+pub mod sink;
+pub mod sqlite;
+pub mod timescaledb;
@@ -0,0 +1,300 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use tokio::sync::Notify;
+
+use crate::app::app::PolledDevice;
+use crate::app::command::Commander;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::mqtt::commands::{self, CommandHandler};
+use crate::storage::mqtt::discovery::{discovery_messages, DeviceTopics};
+use crate::storage::mqtt::state::MqttState;
+use crate::storage::sink::Sink;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long the shutdown waits for the broker to take the disconnect
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn is_enabled() -> bool {
+    dotenv::var("MQTT_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn discovery_prefix() -> Option<String> {
+    let enabled = dotenv::var("MQTT_DISCOVERY")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(true))
+        .unwrap();
+
+    enabled.then(|| dotenv::var("MQTT_DISCOVERY_PREFIX").unwrap_or_else(|_| "homeassistant".to_string()))
+}
+
+/// Publishes the device's availability, state JSON and per-field topics as retained messages
+pub fn publish_state(client: &AsyncClient, prefix: &str, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+    let topics = DeviceTopics::new(prefix, snapshot.device_id);
+    let state = MqttState::from(snapshot);
+    let availability = if snapshot.offline { "offline" } else { "online" };
+    client.try_publish(&topics.availability, QoS::AtLeastOnce, true, availability)?;
+    client.try_publish(&topics.state, QoS::AtLeastOnce, true, serde_json::to_string(&state)?)?;
+    for (field, value) in state.fields()? {
+        client.try_publish(topics.field(&field), QoS::AtLeastOnce, true, value)?;
+    }
+
+    Ok(())
+}
+
+/// Publishes the device states into an MQTT broker as retained messages, and announces the
+/// devices to Home Assistant through MQTT discovery. With commands enabled the devices can also
+/// be controlled through the `<prefix>/<device_id>/set/<field>` topics.
+pub struct MqttClient {
+    client: AsyncClient,
+    prefix: String,
+    discovery_prefix: Option<String>,
+    commands: bool,
+    announced: Mutex<HashSet<u32>>,
+    connected: Arc<AtomicBool>,
+    /// Notified once the disconnect has been sent
+    stopped: Arc<Notify>,
+}
+
+impl MqttClient {
+    pub fn from_env(commander: Commander, devices: Vec<PolledDevice>) -> Result<MqttClient, anyhow::Error> {
+        let host = dotenv::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let port = dotenv::var("MQTT_PORT")
+            .map(|var| var.parse::<u16>())
+            .unwrap_or(Ok(1883))?;
+        let client_id = dotenv::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "melcloud-logger".to_string());
+        let prefix = dotenv::var("MQTT_PREFIX").unwrap_or_else(|_| "melcloud".to_string());
+
+        let mut options = MqttOptions::new(client_id, &host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Ok(username), Ok(password)) = (dotenv::var("MQTT_USERNAME"), dotenv::var("MQTT_PASSWORD")) {
+            options.set_credentials(username, password);
+        }
+        info!("Publishing into MQTT broker {}:{} under {}", host, port, prefix);
+
+        // Only audited commands are accepted
+        if commands::is_enabled() && !commander.is_audited() {
+            warn!("Ignoring MQTT_COMMANDS, control needs TimescaleDB to record the commands");
+        }
+        let commands = (commands::is_enabled() && commander.is_audited()).then_some((commander, devices));
+        if commands.is_some() {
+            info!("Accepting device commands from {}", commands::subscription(&prefix));
+        }
+
+        Ok(MqttClient::new(options, prefix, discovery_prefix(), commands))
+    }
+
+    fn new(
+        mut options: MqttOptions,
+        prefix: String,
+        discovery_prefix: Option<String>,
+        commands: Option<(Commander, Vec<PolledDevice>)>,
+    ) -> MqttClient {
+        let status_topic = format!("{}/status", prefix);
+        options.set_last_will(LastWill::new(&status_topic, "offline", QoS::AtLeastOnce, true));
+
+        // Room for a whole snapshot with the discovery messages
+        let (client, mut event_loop) = AsyncClient::new(options, 256);
+        let connected = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(Notify::new());
+
+        let accepts_commands = commands.is_some();
+        let command_sender = commands.map(|(commander, devices)| {
+            CommandHandler {
+                client: client.clone(),
+                prefix: prefix.clone(),
+                commander,
+                devices,
+            }
+            .spawn()
+        });
+        let command_topic = commands::subscription(&prefix);
+
+        let status_client = client.clone();
+        let status_connected = connected.clone();
+        let status_stopped = stopped.clone();
+        tokio::spawn(async move {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        status_connected.store(true, Ordering::SeqCst);
+                        if let Err(err) = status_client.try_publish(&status_topic, QoS::AtLeastOnce, true, "online") {
+                            error!("Failed to publish the MQTT status: {}", err);
+                        }
+                        // Subscribed on every connect since a clean session forgets the subscriptions
+                        if command_sender.is_some() {
+                            if let Err(err) = status_client.try_subscribe(&command_topic, QoS::AtLeastOnce) {
+                                error!("Failed to subscribe to the MQTT commands: {}", err);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        if let Some(sender) = &command_sender {
+                            let _ = sender.send(publish);
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                        info!("Disconnected from MQTT broker");
+                        status_connected.store(false, Ordering::SeqCst);
+                        status_stopped.notify_one();
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        if status_connected.swap(false, Ordering::SeqCst) {
+                            error!("Disconnected from MQTT broker: {}", err);
+                        } else {
+                            warn!("Failed to connect to MQTT broker, retrying in {}s: {}", RECONNECT_DELAY.as_secs(), err);
+                        }
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        MqttClient {
+            client,
+            prefix,
+            discovery_prefix,
+            commands: accepts_commands,
+            announced: Mutex::new(HashSet::new()),
+            connected,
+            stopped,
+        }
+    }
+
+    fn publish(&self, topic: &str, payload: String) -> Result<(), anyhow::Error> {
+        self.client.try_publish(topic, QoS::AtLeastOnce, true, payload)?;
+        Ok(())
+    }
+
+    fn announce(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        let discovery_prefix = match &self.discovery_prefix {
+            Some(discovery_prefix) => discovery_prefix,
+            None => return Ok(()),
+        };
+        if self.announced.lock().unwrap().contains(&snapshot.device_id) {
+            return Ok(());
+        }
+
+        for (topic, config) in discovery_messages(discovery_prefix, &self.prefix, snapshot, self.commands) {
+            self.publish(&topic, config.to_string())?;
+        }
+        self.announced.lock().unwrap().insert(snapshot.device_id);
+        info!("Announced device {} to Home Assistant", snapshot.device_id);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for MqttClient {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(anyhow::anyhow!("Not connected to MQTT broker"));
+        }
+
+        self.announce(snapshot)?;
+        publish_state(&self.client, &self.prefix, snapshot)
+    }
+
+    /// Marks the logger offline, the broker only sends the last will when the connection breaks
+    async fn flush(&self) -> Result<(), anyhow::Error> {
+        if !self.connected.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // The connection may have broken with the event loop waiting to reconnect
+        let disconnect = async {
+            self.client
+                .publish(format!("{}/status", self.prefix), QoS::AtLeastOnce, true, "offline")
+                .await?;
+            self.client.disconnect().await?;
+            self.stopped.notified().await;
+            Ok::<_, anyhow::Error>(())
+        };
+        tokio::time::timeout(DISCONNECT_TIMEOUT, disconnect)
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out disconnecting from MQTT broker"))?
+    }
+}
+
+pub fn connect(commander: Commander, devices: Vec<PolledDevice>) -> Result<MqttClient, anyhow::Error> {
+    MqttClient::from_env(commander, devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Reads one MQTT packet, returns its type and body
+    async fn read_packet(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = socket.read_u8().await.unwrap();
+        let mut length = 0usize;
+        for shift in 0.. {
+            let byte = socket.read_u8().await.unwrap();
+            length |= ((byte & 0x7f) as usize) << (7 * shift);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        socket.read_exact(&mut body).await.unwrap();
+        (header >> 4, body)
+    }
+
+    #[tokio::test]
+    async fn test_publishes_state_and_discovery() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let broker = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            assert_eq!(1, read_packet(&mut socket).await.0);
+            socket.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+            let mut topics = Vec::new();
+            while !topics.iter().any(|topic: &String| topic == "melcloud/7/room_temperature") {
+                let (packet_type, body) = read_packet(&mut socket).await;
+                if packet_type == 3 {
+                    let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    topics.push(String::from_utf8(body[2..2 + length].to_vec()).unwrap());
+                }
+            }
+            topics
+        });
+
+        let client = MqttClient::new(
+            MqttOptions::new("test", "127.0.0.1", port),
+            "melcloud".to_string(),
+            Some("homeassistant".to_string()),
+            None,
+        );
+        while !client.connected.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client.write(&DeviceSnapshot::for_test(7, Utc::now())).await.unwrap();
+
+        let topics = broker.await.unwrap();
+        assert_eq!("melcloud/status", topics[0]);
+        assert!(topics.contains(&"homeassistant/climate/melcloud_7/config".to_string()));
+        assert!(topics.contains(&"melcloud/7/availability".to_string()));
+        assert!(topics.contains(&"melcloud/7/state".to_string()));
+        assert!(topics.contains(&"melcloud/7/room_temperature".to_string()));
+    }
+}
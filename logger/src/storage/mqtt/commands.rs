@@ -0,0 +1,102 @@
+use rumqttc::{AsyncClient, Publish, QoS};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+use crate::app::app::PolledDevice;
+use crate::app::command::{Commander, DeviceCommand};
+use crate::storage::mqtt::client::publish_state;
+use crate::storage::mqtt::discovery::DeviceTopics;
+
+pub fn is_enabled() -> bool {
+    dotenv::var("MQTT_COMMANDS")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+/// The topic filter for the commands of every device
+pub fn subscription(prefix: &str) -> String {
+    format!("{}/+/set/+", prefix)
+}
+
+/// Splits `<prefix>/<device_id>/set/<field>` into the device id and the field
+pub fn parse_topic<'a>(prefix: &str, topic: &'a str) -> Option<(&'a str, &'a str)> {
+    let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    match rest.split('/').collect::<Vec<_>>().as_slice() {
+        [device_id, "set", field] if !device_id.is_empty() && !field.is_empty() => Some((device_id, field)),
+        _ => None,
+    }
+}
+
+/// Turns the messages on the command topics into SetAta calls, and publishes the resulting state
+pub struct CommandHandler {
+    pub client: AsyncClient,
+    pub prefix: String,
+    pub commander: Commander,
+    pub devices: Vec<PolledDevice>,
+}
+
+impl CommandHandler {
+    /// Handles the commands one at a time, so that two quick changes don't overwrite each other
+    pub fn spawn(self) -> mpsc::UnboundedSender<Publish> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Publish>();
+        tokio::spawn(async move {
+            while let Some(publish) = receiver.recv().await {
+                self.handle(&publish).await;
+            }
+        });
+        sender
+    }
+
+    async fn handle(&self, publish: &Publish) {
+        let (device_id, field) = match parse_topic(&self.prefix, &publish.topic) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let payload = String::from_utf8_lossy(&publish.payload);
+
+        let result = self.execute(device_id, field, &payload).await;
+        if let Err(err) = &result {
+            error!("Failed to handle the command {}={} for device {}: {}", field, payload, device_id, err);
+        }
+
+        // Not retained, it's only for whoever is watching when the command is sent
+        let topics = DeviceTopics::new(&self.prefix, device_id);
+        let response = json!({
+            "field": field,
+            "payload": payload,
+            "success": result.is_ok(),
+            "error": result.err().map(|err| err.to_string()),
+        });
+        if let Err(err) = self.client.try_publish(topics.field("command"), QoS::AtLeastOnce, false, response.to_string()) {
+            error!("Failed to publish the command result: {}", err);
+        }
+    }
+
+    async fn execute(&self, device_id: &str, field: &str, payload: &str) -> Result<(), anyhow::Error> {
+        let device = self
+            .devices
+            .iter()
+            .find(|device| device.device_id == device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {} isn't polled", device_id))?;
+        let commands = DeviceCommand::parse(field, payload)?;
+
+        let outcome = self.commander.run(device, &commands, "mqtt", "mqtt").await?;
+        publish_state(&self.client, &self.prefix, &outcome.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_topic() {
+        assert_eq!(Some(("42", "mode")), parse_topic("melcloud", "melcloud/42/set/mode"));
+        assert_eq!(Some(("42", "temperature")), parse_topic("home/ac", "home/ac/42/set/temperature"));
+        assert_eq!(None, parse_topic("melcloud", "melcloud/42/state"));
+        assert_eq!(None, parse_topic("melcloud", "melcloud/42/set/mode/extra"));
+        assert_eq!(None, parse_topic("melcloud", "melcloudx/42/set/mode"));
+        assert_eq!(None, parse_topic("melcloud", "melcloud//set/mode"));
+    }
+}
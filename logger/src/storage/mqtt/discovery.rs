@@ -0,0 +1,159 @@
+use serde_json::{json, Value};
+
+use crate::app::snapshot::DeviceSnapshot;
+
+/// Topics of a device under the MQTT prefix
+pub struct DeviceTopics {
+    base: String,
+    pub state: String,
+    pub availability: String,
+    pub bridge_availability: String,
+}
+
+impl DeviceTopics {
+    pub fn new(prefix: &str, device_id: impl std::fmt::Display) -> DeviceTopics {
+        let base = format!("{}/{}", prefix, device_id);
+        DeviceTopics {
+            state: format!("{}/state", base),
+            availability: format!("{}/availability", base),
+            bridge_availability: format!("{}/status", prefix),
+            base,
+        }
+    }
+
+    pub fn field(&self, field: &str) -> String {
+        format!("{}/{}", self.base, field)
+    }
+
+    pub fn command(&self, field: &str) -> String {
+        format!("{}/set/{}", self.base, field)
+    }
+}
+
+struct Sensor {
+    component: &'static str,
+    field: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    state_class: Option<&'static str>,
+}
+
+const SENSORS: &[Sensor] = &[
+    Sensor { component: "sensor", field: "room_temperature", name: "Room temperature", device_class: Some("temperature"), unit: Some("°C"), state_class: Some("measurement") },
+    Sensor { component: "sensor", field: "set_temperature", name: "Target temperature", device_class: Some("temperature"), unit: Some("°C"), state_class: Some("measurement") },
+    Sensor { component: "sensor", field: "energy_total", name: "Energy", device_class: Some("energy"), unit: Some("kWh"), state_class: Some("total_increasing") },
+    Sensor { component: "sensor", field: "wifi_signal_strength", name: "Wifi signal", device_class: Some("signal_strength"), unit: Some("dBm"), state_class: Some("measurement") },
+    Sensor { component: "sensor", field: "actual_fan_speed", name: "Fan speed", device_class: None, unit: None, state_class: Some("measurement") },
+    Sensor { component: "binary_sensor", field: "has_error", name: "Error", device_class: Some("problem"), unit: None, state_class: None },
+];
+
+fn sensor_config(topics: &DeviceTopics, device: &Value, unique_id: &str, sensor: &Sensor) -> Value {
+    let mut config = json!({
+        "name": sensor.name,
+        "unique_id": unique_id,
+        "device": device,
+        "availability_mode": "all",
+        "availability": [
+            { "topic": topics.bridge_availability },
+            { "topic": topics.availability },
+        ],
+        "state_topic": topics.state,
+        "value_template": format!("{{{{ value_json.{} }}}}", sensor.field),
+    });
+    for (key, value) in [("device_class", sensor.device_class), ("unit_of_measurement", sensor.unit), ("state_class", sensor.state_class)] {
+        if let Some(value) = value {
+            config[key] = json!(value);
+        }
+    }
+    if sensor.component == "binary_sensor" {
+        config["value_template"] = json!(format!("{{{{ 'ON' if value_json.{} else 'OFF' }}}}", sensor.field));
+    }
+
+    config
+}
+
+/// The retained `(topic, config)` messages that announce a device's entities to Home Assistant,
+/// the climate entity is controllable when `commands` are accepted
+pub fn discovery_messages(discovery_prefix: &str, prefix: &str, snapshot: &DeviceSnapshot, commands: bool) -> Vec<(String, Value)> {
+    let device_id = snapshot.device_id;
+    let object_id = format!("melcloud_{}", device_id);
+    let topics = DeviceTopics::new(prefix, device_id);
+    let device = json!({
+        "identifiers": [object_id],
+        "name": snapshot.device_name.clone().unwrap_or_else(|| format!("MELCloud {}", device_id)),
+        "manufacturer": "Mitsubishi Electric",
+        "model": "MELCloud",
+        "suggested_area": snapshot.building_name,
+    });
+
+    let mut climate = json!({
+        "name": null,
+        "unique_id": format!("{}_climate", object_id),
+        "device": device,
+        "availability_mode": "all",
+        "availability": [
+            { "topic": topics.bridge_availability },
+            { "topic": topics.availability },
+        ],
+        "current_temperature_topic": topics.state,
+        "current_temperature_template": "{{ value_json.room_temperature }}",
+        "temperature_state_topic": topics.state,
+        "temperature_state_template": "{{ value_json.set_temperature }}",
+        "mode_state_topic": topics.state,
+        "mode_state_template": "{{ value_json.mode }}",
+        "modes": ["off", "heat", "cool", "dry", "fan_only", "auto"],
+        "fan_mode_state_topic": topics.state,
+        "fan_mode_state_template": "{{ value_json.fan_mode }}",
+        "fan_modes": ["auto", "1", "2", "3", "4", "5"],
+        "min_temp": 10,
+        "max_temp": 31,
+        "temp_step": 0.5,
+        "temperature_unit": "C",
+    });
+    if commands {
+        climate["mode_command_topic"] = json!(topics.command("mode"));
+        climate["temperature_command_topic"] = json!(topics.command("temperature"));
+        climate["fan_mode_command_topic"] = json!(topics.command("fan"));
+        climate["power_command_topic"] = json!(topics.command("power"));
+    }
+
+    let mut messages = vec![(format!("{}/climate/{}/config", discovery_prefix, object_id), climate)];
+    for sensor in SENSORS {
+        let unique_id = format!("{}_{}", object_id, sensor.field);
+        messages.push((
+            format!("{}/{}/{}/config", discovery_prefix, sensor.component, unique_id),
+            sensor_config(&topics, &device, &unique_id, sensor),
+        ));
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_discovery_messages() {
+        let snapshot = DeviceSnapshot::for_test(42, Utc::now());
+        let messages = discovery_messages("homeassistant", "melcloud", &snapshot, false);
+
+        let (topic, climate) = &messages[0];
+        assert_eq!("homeassistant/climate/melcloud_42/config", topic);
+        assert_eq!("melcloud/42/state", climate["current_temperature_topic"]);
+        assert_eq!("Device 42", climate["device"]["name"]);
+        assert!(climate.get("mode_command_topic").is_none());
+
+        let (topic, energy) = messages.iter().find(|(topic, _)| topic.contains("energy_total")).unwrap();
+        assert_eq!("homeassistant/sensor/melcloud_42_energy_total/config", topic);
+        assert_eq!("{{ value_json.energy_total }}", energy["value_template"]);
+        assert_eq!("total_increasing", energy["state_class"]);
+
+        assert_eq!("melcloud/42/room_temperature", DeviceTopics::new("melcloud", 42).field("room_temperature"));
+
+        let messages = discovery_messages("homeassistant", "melcloud", &snapshot, true);
+        assert_eq!("melcloud/42/set/mode", messages[0].1["mode_command_topic"]);
+    }
+}
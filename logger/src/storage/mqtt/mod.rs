@@ -0,0 +1,4 @@
+pub mod client;
+pub mod commands;
+pub mod discovery;
+pub mod state;
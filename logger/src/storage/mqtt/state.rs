@@ -0,0 +1,139 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::app::snapshot::DeviceSnapshot;
+
+/// Home Assistant's HVAC mode for MELCloud's operation mode
+pub fn hvac_mode(power: bool, operation_mode: u8) -> &'static str {
+    if !power {
+        return "off";
+    }
+    match operation_mode {
+        1 => "heat",
+        2 => "dry",
+        3 => "cool",
+        7 => "fan_only",
+        8 => "auto",
+        _ => "off",
+    }
+}
+
+/// Home Assistant's fan mode for MELCloud's fan speed, 0 is automatic
+pub fn fan_mode(fan_speed: u8) -> String {
+    match fan_speed {
+        0 => "auto".to_string(),
+        speed => speed.to_string(),
+    }
+}
+
+/// The device state published as retained JSON, each field is also published on its own topic
+#[derive(Debug, Serialize)]
+pub struct MqttState {
+    pub time: DateTime<Utc>,
+    pub device_name: Option<String>,
+    pub power: bool,
+    pub offline: bool,
+    pub mode: &'static str,
+    pub operation_mode: u8,
+    pub room_temperature: f32,
+    pub set_temperature: f32,
+    pub fan_mode: String,
+    pub fan_speed: u8,
+    pub actual_fan_speed: u8,
+    pub vane_vertical_direction: u8,
+    pub vane_horizontal_direction: u8,
+    pub energy_total: Option<f32>,
+    pub current_energy_consumed: Option<f32>,
+    pub wifi_signal_strength: Option<f32>,
+    pub has_error: Option<bool>,
+}
+
+impl From<&DeviceSnapshot> for MqttState {
+    fn from(snapshot: &DeviceSnapshot) -> Self {
+        let counters = [
+            snapshot.heating_energy_consumed_rate1,
+            snapshot.heating_energy_consumed_rate2,
+            snapshot.cooling_energy_consumed_rate1,
+            snapshot.cooling_energy_consumed_rate2,
+            snapshot.auto_energy_consumed_rate1,
+            snapshot.auto_energy_consumed_rate2,
+            snapshot.dry_energy_consumed_rate1,
+            snapshot.dry_energy_consumed_rate2,
+            snapshot.fan_energy_consumed_rate1,
+            snapshot.fan_energy_consumed_rate2,
+            snapshot.other_energy_consumed_rate1,
+            snapshot.other_energy_consumed_rate2,
+        ];
+        let energy_total = counters
+            .iter()
+            .flatten()
+            .fold(None, |total: Option<f32>, counter| Some(total.unwrap_or(0.0) + counter));
+
+        MqttState {
+            time: snapshot.time,
+            device_name: snapshot.device_name.clone(),
+            power: snapshot.power,
+            offline: snapshot.offline,
+            mode: hvac_mode(snapshot.power, snapshot.operation_mode),
+            operation_mode: snapshot.operation_mode,
+            room_temperature: snapshot.room_temperature,
+            set_temperature: snapshot.set_temperature,
+            fan_mode: fan_mode(snapshot.fan_speed),
+            fan_speed: snapshot.fan_speed,
+            actual_fan_speed: snapshot.actual_fan_speed,
+            vane_vertical_direction: snapshot.vane_vertical_direction,
+            vane_horizontal_direction: snapshot.vane_horizontal_direction,
+            energy_total,
+            current_energy_consumed: snapshot.current_energy_consumed,
+            wifi_signal_strength: snapshot.wifi_signal_strength,
+            has_error: snapshot.has_error,
+        }
+    }
+}
+
+impl MqttState {
+    /// The `(field, value)` pairs for the per-field topics, the missing values are left out
+    pub fn fields(&self) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let value = serde_json::to_value(self)?;
+        let object = value.as_object().ok_or_else(|| anyhow::anyhow!("State isn't an object"))?;
+
+        Ok(object
+            .iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(field, value)| {
+                let value = match value {
+                    serde_json::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (field.clone(), value)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_fields() {
+        let mut snapshot = DeviceSnapshot::for_test(1, Utc::now());
+        snapshot.operation_mode = 3;
+        snapshot.fan_speed = 0;
+        snapshot.heating_energy_consumed_rate1 = Some(1.5);
+        snapshot.cooling_energy_consumed_rate1 = Some(2.0);
+
+        let state = MqttState::from(&snapshot);
+        assert_eq!("cool", state.mode);
+        assert_eq!(Some(3.5), state.energy_total);
+
+        let fields = state.fields().unwrap();
+        assert!(fields.contains(&("mode".to_string(), "cool".to_string())));
+        assert!(fields.contains(&("fan_mode".to_string(), "auto".to_string())));
+        assert!(fields.contains(&("room_temperature".to_string(), "21.0".to_string())));
+        assert!(!fields.iter().any(|(field, _)| field == "current_energy_consumed"));
+
+        snapshot.power = false;
+        assert_eq!("off", MqttState::from(&snapshot).mode);
+    }
+}
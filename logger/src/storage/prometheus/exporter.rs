@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use ::prometheus::{GaugeVec, Opts, Registry};
+
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::sink::Sink;
+
+const DEVICE_LABELS: &[&str] = &["device_id", "device_name", "building"];
+const ENERGY_MODES: [&str; 6] = ["heating", "cooling", "auto", "dry", "fan", "other"];
+
+/// Keeps the latest state of each device as gauges for the `/metrics` endpoint
+pub struct PrometheusSink {
+    room_temperature: GaugeVec,
+    set_temperature: GaugeVec,
+    power: GaugeVec,
+    offline: GaugeVec,
+    operation_mode: GaugeVec,
+    fan_speed: GaugeVec,
+    actual_fan_speed: GaugeVec,
+    wifi_signal_strength: GaugeVec,
+    current_energy_consumed: GaugeVec,
+    energy_consumed: GaugeVec,
+    labels: Mutex<HashMap<u32, [String; 3]>>,
+}
+
+fn gauge(registry: &Registry, name: &str, help: &str, extra_labels: &[&str]) -> Result<GaugeVec, anyhow::Error> {
+    let labels = [DEVICE_LABELS, extra_labels].concat();
+    let gauge = GaugeVec::new(Opts::new(name, help), &labels)?;
+    registry.register(Box::new(gauge.clone()))?;
+    Ok(gauge)
+}
+
+fn device_labels(snapshot: &DeviceSnapshot) -> [String; 3] {
+    let building = snapshot
+        .building_name
+        .clone()
+        .or_else(|| snapshot.building_id.map(|building_id| building_id.to_string()))
+        .unwrap_or_default();
+    [snapshot.device_id.to_string(), snapshot.device_name.clone().unwrap_or_default(), building]
+}
+
+impl PrometheusSink {
+    pub fn new(registry: &Registry) -> Result<PrometheusSink, anyhow::Error> {
+        Ok(PrometheusSink {
+            room_temperature: gauge(registry, "melcloud_room_temperature_celsius", "Room temperature", &[])?,
+            set_temperature: gauge(registry, "melcloud_set_temperature_celsius", "Target temperature", &[])?,
+            power: gauge(registry, "melcloud_power", "1 when the device is on", &[])?,
+            offline: gauge(registry, "melcloud_offline", "1 when MELCloud reports the device offline", &[])?,
+            operation_mode: gauge(
+                registry,
+                "melcloud_operation_mode",
+                "Operation mode: 1 heat, 2 dry, 3 cool, 7 fan, 8 auto",
+                &[],
+            )?,
+            fan_speed: gauge(registry, "melcloud_fan_speed", "Set fan speed, 0 is automatic", &[])?,
+            actual_fan_speed: gauge(registry, "melcloud_actual_fan_speed", "Actual fan speed", &[])?,
+            wifi_signal_strength: gauge(registry, "melcloud_wifi_signal_strength_dbm", "Wifi signal strength", &[])?,
+            current_energy_consumed: gauge(
+                registry,
+                "melcloud_current_energy_consumed",
+                "Energy consumed as reported by MELCloud",
+                &[],
+            )?,
+            energy_consumed: gauge(
+                registry,
+                "melcloud_energy_consumed_kwh",
+                "Energy consumed per mode and rate as reported by MELCloud",
+                &["mode", "rate"],
+            )?,
+            labels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn device_gauges(&self) -> [&GaugeVec; 9] {
+        [
+            &self.room_temperature,
+            &self.set_temperature,
+            &self.power,
+            &self.offline,
+            &self.operation_mode,
+            &self.fan_speed,
+            &self.actual_fan_speed,
+            &self.wifi_signal_strength,
+            &self.current_energy_consumed,
+        ]
+    }
+
+    /// Drops the series of a device whose name or building changed, so it isn't reported twice
+    fn relabel(&self, device_id: u32, labels: &[String; 3]) {
+        let previous = self.labels.lock().unwrap().insert(device_id, labels.clone());
+        let previous = match previous {
+            Some(previous) if previous != *labels => previous,
+            _ => return,
+        };
+
+        let previous: Vec<&str> = previous.iter().map(String::as_str).collect();
+        for gauge in self.device_gauges() {
+            let _ = gauge.remove_label_values(&previous);
+        }
+        for mode in ENERGY_MODES {
+            for rate in ["1", "2"] {
+                let _ = self.energy_consumed.remove_label_values(&[previous.as_slice(), &[mode, rate]].concat());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for PrometheusSink {
+    fn name(&self) -> &'static str {
+        "prometheus"
+    }
+
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        let labels = device_labels(snapshot);
+        self.relabel(snapshot.device_id, &labels);
+        let labels: Vec<&str> = labels.iter().map(String::as_str).collect();
+
+        let flag = |value: bool| if value { 1.0 } else { 0.0 };
+        self.room_temperature.with_label_values(&labels).set(snapshot.room_temperature.into());
+        self.set_temperature.with_label_values(&labels).set(snapshot.set_temperature.into());
+        self.power.with_label_values(&labels).set(flag(snapshot.power));
+        self.offline.with_label_values(&labels).set(flag(snapshot.offline));
+        self.operation_mode.with_label_values(&labels).set(snapshot.operation_mode.into());
+        self.fan_speed.with_label_values(&labels).set(snapshot.fan_speed.into());
+        self.actual_fan_speed.with_label_values(&labels).set(snapshot.actual_fan_speed.into());
+        if let Some(wifi_signal_strength) = snapshot.wifi_signal_strength {
+            self.wifi_signal_strength.with_label_values(&labels).set(wifi_signal_strength.into());
+        }
+        if let Some(current_energy_consumed) = snapshot.current_energy_consumed {
+            self.current_energy_consumed.with_label_values(&labels).set(current_energy_consumed.into());
+        }
+
+        let counters = [
+            ("heating", "1", snapshot.heating_energy_consumed_rate1),
+            ("heating", "2", snapshot.heating_energy_consumed_rate2),
+            ("cooling", "1", snapshot.cooling_energy_consumed_rate1),
+            ("cooling", "2", snapshot.cooling_energy_consumed_rate2),
+            ("auto", "1", snapshot.auto_energy_consumed_rate1),
+            ("auto", "2", snapshot.auto_energy_consumed_rate2),
+            ("dry", "1", snapshot.dry_energy_consumed_rate1),
+            ("dry", "2", snapshot.dry_energy_consumed_rate2),
+            ("fan", "1", snapshot.fan_energy_consumed_rate1),
+            ("fan", "2", snapshot.fan_energy_consumed_rate2),
+            ("other", "1", snapshot.other_energy_consumed_rate1),
+            ("other", "2", snapshot.other_energy_consumed_rate2),
+        ];
+        for (mode, rate, value) in counters {
+            if let Some(value) = value {
+                let labels = [labels.as_slice(), &[mode, rate]].concat();
+                self.energy_consumed.with_label_values(&labels).set(value.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::prometheus::{Encoder, TextEncoder};
+    use chrono::Utc;
+
+    fn render(registry: &Registry) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_device_gauges() {
+        let registry = Registry::new();
+        let sink = PrometheusSink::new(&registry).unwrap();
+
+        let mut snapshot = DeviceSnapshot::for_test(7, Utc::now());
+        snapshot.heating_energy_consumed_rate1 = Some(12.5);
+        sink.write(&snapshot).await.unwrap();
+
+        let text = render(&registry);
+        assert!(text.contains(r#"melcloud_room_temperature_celsius{building="Home",device_id="7",device_name="Device 7"} 21"#));
+        assert!(text.contains(r#"melcloud_power{building="Home",device_id="7",device_name="Device 7"} 1"#));
+        assert!(text.contains(
+            r#"melcloud_energy_consumed_kwh{building="Home",device_id="7",device_name="Device 7",mode="heating",rate="1"} 12.5"#
+        ));
+        assert!(!text.contains("melcloud_current_energy_consumed{"));
+
+        snapshot.device_name = Some("Bedroom".to_string());
+        sink.write(&snapshot).await.unwrap();
+        let text = render(&registry);
+        assert!(!text.contains(r#"device_name="Device 7""#));
+        assert!(text.contains(r#"device_name="Bedroom",mode="heating""#));
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::app::events::{DeviceEvent, DeviceState};
+use crate::app::snapshot::DeviceSnapshot;
+
+/// A storage backend the device snapshots are written into
+#[async_trait]
+pub trait Sink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error>;
+
+    /// Stores the changes between the samples, most sinks only keep the samples themselves
+    async fn write_events(&self, _events: &[DeviceEvent]) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// The time of the latest sample stored per device, `None` when the sink can't tell
+    async fn last_written(&self) -> Result<Option<HashMap<u32, DateTime<Utc>>>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// The state of the latest sample stored per device, `None` when the sink can't tell
+    async fn last_states(&self) -> Result<Option<Vec<DeviceState>>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Finishes up before the logger exits
+    async fn flush(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// The backend refused the snapshot itself, writing it again would fail the same way
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+pub fn is_rejected(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Rejected>().is_some()
+}
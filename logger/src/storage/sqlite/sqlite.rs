@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection};
+
+use crate::app::events::DeviceState;
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::sink::Sink;
+
+// Applied in order and tracked in `PRAGMA user_version`, append new ones to the end
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../../migrations/sqlite/0001_create_melcloud.sql"),
+    include_str!("../../../migrations/sqlite/0002_add_melcloud_energy_deltas.sql"),
+    include_str!("../../../migrations/sqlite/0003_add_melcloud_energy_cost.sql"),
+];
+
+/// How often the samples past the retention are deleted
+const PRUNE_INTERVAL_MINUTES: i64 = 60;
+
+pub fn is_enabled() -> bool {
+    dotenv::var("SQLITE_ENABLED")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+fn database_path() -> String {
+    dotenv::var("SQLITE_PATH").unwrap_or_else(|_| "melcloud.db".to_string())
+}
+
+fn retention() -> Option<Duration> {
+    dotenv::var("SQLITE_RETENTION_DAYS")
+        .ok()
+        .map(|var| Duration::days(var.parse::<i64>().unwrap()))
+        .filter(|retention| *retention > Duration::zero())
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), anyhow::Error> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(sql)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+        info!("Applied SQLite migration {}", index + 1);
+    }
+
+    Ok(())
+}
+
+fn upsert_snapshot_into_sqlite(connection: &Connection, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+    // Same as in TimescaleDB, the nullable columns keep their earlier value when a sample
+    // without them lands on the same row
+    let mut statement = connection.prepare_cached(
+        "INSERT INTO melcloud (
+            time, device_id, device_type, power, offline, room_temperature, set_temperature,
+            last_communication, actual_fan_speed, fan_speed, automatic_fan_speed,
+            vane_vertical_direction, vane_vertical_swing, vane_horizontal_direction,
+            vane_horizontal_swing, operation_mode, in_standby_mode, heating_energy_consumed_rate1,
+            heating_energy_consumed_rate2, cooling_energy_consumed_rate1, cooling_energy_consumed_rate2,
+            auto_energy_consumed_rate1, auto_energy_consumed_rate2, dry_energy_consumed_rate1,
+            dry_energy_consumed_rate2, fan_energy_consumed_rate1, fan_energy_consumed_rate2,
+            other_energy_consumed_rate1, other_energy_consumed_rate2, current_energy_consumed,
+            current_energy_mode, energy_correction_model, energy_correction_active,
+            wifi_signal_strength, wifi_adapter_status, has_error, heating_energy_delta,
+            cooling_energy_delta, auto_energy_delta, dry_energy_delta, fan_energy_delta,
+            other_energy_delta, total_energy_delta, energy_delta_seconds, energy_counter_reset,
+            energy_gap, energy_cost, energy_price
+        ) VALUES (
+            ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+            ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33, ?34, ?35, ?36,
+            ?37, ?38, ?39, ?40, ?41, ?42, ?43, ?44, ?45, ?46, ?47, ?48
+        ) ON CONFLICT (time, device_id) DO UPDATE SET
+            device_type = excluded.device_type, power = excluded.power, offline = excluded.offline,
+            room_temperature = excluded.room_temperature, set_temperature = excluded.set_temperature,
+            last_communication = excluded.last_communication, actual_fan_speed = excluded.actual_fan_speed,
+            fan_speed = excluded.fan_speed,
+            automatic_fan_speed = COALESCE(excluded.automatic_fan_speed, melcloud.automatic_fan_speed),
+            vane_vertical_direction = excluded.vane_vertical_direction,
+            vane_vertical_swing = COALESCE(excluded.vane_vertical_swing, melcloud.vane_vertical_swing),
+            vane_horizontal_direction = excluded.vane_horizontal_direction,
+            vane_horizontal_swing = COALESCE(excluded.vane_horizontal_swing, melcloud.vane_horizontal_swing),
+            operation_mode = excluded.operation_mode, in_standby_mode = excluded.in_standby_mode,
+            heating_energy_consumed_rate1 = COALESCE(excluded.heating_energy_consumed_rate1, melcloud.heating_energy_consumed_rate1),
+            heating_energy_consumed_rate2 = COALESCE(excluded.heating_energy_consumed_rate2, melcloud.heating_energy_consumed_rate2),
+            cooling_energy_consumed_rate1 = COALESCE(excluded.cooling_energy_consumed_rate1, melcloud.cooling_energy_consumed_rate1),
+            cooling_energy_consumed_rate2 = COALESCE(excluded.cooling_energy_consumed_rate2, melcloud.cooling_energy_consumed_rate2),
+            auto_energy_consumed_rate1 = COALESCE(excluded.auto_energy_consumed_rate1, melcloud.auto_energy_consumed_rate1),
+            auto_energy_consumed_rate2 = COALESCE(excluded.auto_energy_consumed_rate2, melcloud.auto_energy_consumed_rate2),
+            dry_energy_consumed_rate1 = COALESCE(excluded.dry_energy_consumed_rate1, melcloud.dry_energy_consumed_rate1),
+            dry_energy_consumed_rate2 = COALESCE(excluded.dry_energy_consumed_rate2, melcloud.dry_energy_consumed_rate2),
+            fan_energy_consumed_rate1 = COALESCE(excluded.fan_energy_consumed_rate1, melcloud.fan_energy_consumed_rate1),
+            fan_energy_consumed_rate2 = COALESCE(excluded.fan_energy_consumed_rate2, melcloud.fan_energy_consumed_rate2),
+            other_energy_consumed_rate1 = COALESCE(excluded.other_energy_consumed_rate1, melcloud.other_energy_consumed_rate1),
+            other_energy_consumed_rate2 = COALESCE(excluded.other_energy_consumed_rate2, melcloud.other_energy_consumed_rate2),
+            current_energy_consumed = COALESCE(excluded.current_energy_consumed, melcloud.current_energy_consumed),
+            current_energy_mode = COALESCE(excluded.current_energy_mode, melcloud.current_energy_mode),
+            energy_correction_model = COALESCE(excluded.energy_correction_model, melcloud.energy_correction_model),
+            energy_correction_active = COALESCE(excluded.energy_correction_active, melcloud.energy_correction_active),
+            wifi_signal_strength = COALESCE(excluded.wifi_signal_strength, melcloud.wifi_signal_strength),
+            wifi_adapter_status = COALESCE(excluded.wifi_adapter_status, melcloud.wifi_adapter_status),
+            has_error = COALESCE(excluded.has_error, melcloud.has_error),
+            heating_energy_delta = COALESCE(excluded.heating_energy_delta, melcloud.heating_energy_delta),
+            cooling_energy_delta = COALESCE(excluded.cooling_energy_delta, melcloud.cooling_energy_delta),
+            auto_energy_delta = COALESCE(excluded.auto_energy_delta, melcloud.auto_energy_delta),
+            dry_energy_delta = COALESCE(excluded.dry_energy_delta, melcloud.dry_energy_delta),
+            fan_energy_delta = COALESCE(excluded.fan_energy_delta, melcloud.fan_energy_delta),
+            other_energy_delta = COALESCE(excluded.other_energy_delta, melcloud.other_energy_delta),
+            total_energy_delta = COALESCE(excluded.total_energy_delta, melcloud.total_energy_delta),
+            energy_delta_seconds = COALESCE(excluded.energy_delta_seconds, melcloud.energy_delta_seconds),
+            energy_counter_reset = COALESCE(excluded.energy_counter_reset, melcloud.energy_counter_reset),
+            energy_gap = COALESCE(excluded.energy_gap, melcloud.energy_gap),
+            energy_cost = COALESCE(excluded.energy_cost, melcloud.energy_cost),
+            energy_price = COALESCE(excluded.energy_price, melcloud.energy_price)",
+    )?;
+
+    let energy = snapshot.energy;
+    statement.execute(params![
+        snapshot.time, snapshot.device_id, snapshot.device_type, snapshot.power, snapshot.offline, snapshot.room_temperature, snapshot.set_temperature,
+        snapshot.last_communication, snapshot.actual_fan_speed, snapshot.fan_speed, snapshot.automatic_fan_speed,
+        snapshot.vane_vertical_direction, snapshot.vane_vertical_swing, snapshot.vane_horizontal_direction,
+        snapshot.vane_horizontal_swing, snapshot.operation_mode, snapshot.in_standby_mode, snapshot.heating_energy_consumed_rate1,
+        snapshot.heating_energy_consumed_rate2, snapshot.cooling_energy_consumed_rate1, snapshot.cooling_energy_consumed_rate2,
+        snapshot.auto_energy_consumed_rate1, snapshot.auto_energy_consumed_rate2, snapshot.dry_energy_consumed_rate1,
+        snapshot.dry_energy_consumed_rate2, snapshot.fan_energy_consumed_rate1, snapshot.fan_energy_consumed_rate2,
+        snapshot.other_energy_consumed_rate1, snapshot.other_energy_consumed_rate2, snapshot.current_energy_consumed,
+        snapshot.current_energy_mode, snapshot.energy_correction_model, snapshot.energy_correction_active,
+        snapshot.wifi_signal_strength, snapshot.wifi_adapter_status, snapshot.has_error, energy.and_then(|energy| energy.heating),
+        energy.and_then(|energy| energy.cooling), energy.and_then(|energy| energy.auto), energy.and_then(|energy| energy.dry),
+        energy.and_then(|energy| energy.fan), energy.and_then(|energy| energy.other), energy.and_then(|energy| energy.total),
+        energy.map(|energy| energy.seconds), energy.map(|energy| energy.reset), energy.map(|energy| energy.gap), energy.and_then(|energy| energy.cost),
+        energy.and_then(|energy| energy.price),
+    ])?;
+
+    Ok(())
+}
+
+fn select_last_written_from_sqlite(connection: &Connection) -> Result<HashMap<u32, DateTime<Utc>>, anyhow::Error> {
+    let mut statement = connection.prepare("SELECT device_id, max(time) FROM melcloud GROUP BY device_id")?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, DateTime<Utc>>(1)?)))?;
+
+    Ok(rows.collect::<Result<HashMap<_, _>, _>>()?)
+}
+
+fn select_last_states_from_sqlite(connection: &Connection) -> Result<Vec<DeviceState>, anyhow::Error> {
+    let mut statement = connection.prepare(
+        "SELECT time, device_id, power, offline, set_temperature, fan_speed, vane_vertical_direction,
+            vane_horizontal_direction, operation_mode, in_standby_mode, has_error,
+            heating_energy_consumed_rate1, heating_energy_consumed_rate2, cooling_energy_consumed_rate1,
+            cooling_energy_consumed_rate2, auto_energy_consumed_rate1, auto_energy_consumed_rate2,
+            dry_energy_consumed_rate1, dry_energy_consumed_rate2, fan_energy_consumed_rate1,
+            fan_energy_consumed_rate2, other_energy_consumed_rate1, other_energy_consumed_rate2,
+            current_energy_consumed
+        FROM melcloud
+        WHERE (device_id, time) IN (SELECT device_id, max(time) FROM melcloud GROUP BY device_id)",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(DeviceState {
+            time: row.get(0)?,
+            device_id: row.get(1)?,
+            power: row.get(2)?,
+            offline: row.get(3)?,
+            set_temperature: row.get(4)?,
+            fan_speed: row.get(5)?,
+            vane_vertical_direction: row.get(6)?,
+            vane_horizontal_direction: row.get(7)?,
+            operation_mode: row.get(8)?,
+            in_standby_mode: row.get(9)?,
+            has_error: row.get(10)?,
+
+            heating_energy_consumed_rate1: row.get(11)?,
+            heating_energy_consumed_rate2: row.get(12)?,
+            cooling_energy_consumed_rate1: row.get(13)?,
+            cooling_energy_consumed_rate2: row.get(14)?,
+            auto_energy_consumed_rate1: row.get(15)?,
+            auto_energy_consumed_rate2: row.get(16)?,
+            dry_energy_consumed_rate1: row.get(17)?,
+            dry_energy_consumed_rate2: row.get(18)?,
+            fan_energy_consumed_rate1: row.get(19)?,
+            fan_energy_consumed_rate2: row.get(20)?,
+            other_energy_consumed_rate1: row.get(21)?,
+            other_energy_consumed_rate2: row.get(22)?,
+            current_energy_consumed: row.get(23)?,
+        })
+    })?;
+
+    Ok(rows.collect::<Result<Vec<_>, _>>()?)
+}
+
+struct State {
+    connection: Connection,
+    pruned_at: Option<DateTime<Utc>>,
+}
+
+/// A single file database for setups without a database server
+pub struct SqliteSink {
+    state: Arc<Mutex<State>>,
+    retention: Option<Duration>,
+}
+
+impl SqliteSink {
+    pub fn open(path: &Path, retention: Option<Duration>) -> Result<SqliteSink, anyhow::Error> {
+        let mut connection = Connection::open(path)?;
+        // WAL lets readers such as Grafana query the file while the logger writes
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrate(&mut connection)?;
+
+        Ok(SqliteSink {
+            state: Arc::new(Mutex::new(State { connection, pruned_at: None })),
+            retention,
+        })
+    }
+
+    /// Deletes the samples older than the retention, at most once per prune interval
+    fn prune(state: &mut State, retention: Option<Duration>, now: DateTime<Utc>) -> Result<(), anyhow::Error> {
+        let retention = match retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+        if state.pruned_at.is_some_and(|pruned_at| now - pruned_at < Duration::minutes(PRUNE_INTERVAL_MINUTES)) {
+            return Ok(());
+        }
+
+        let deleted = state.connection.execute("DELETE FROM melcloud WHERE time < ?1", params![now - retention])?;
+        if deleted > 0 {
+            info!("Deleted {} SQLite sample(s) past the retention", deleted);
+        }
+        state.pruned_at = Some(now);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for SqliteSink {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        let state = self.state.clone();
+        let retention = self.retention;
+        let snapshot = snapshot.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut state = state.lock().unwrap();
+            upsert_snapshot_into_sqlite(&state.connection, &snapshot)?;
+            SqliteSink::prune(&mut state, retention, Utc::now())
+        })
+        .await?
+    }
+
+    async fn last_written(&self) -> Result<Option<HashMap<u32, DateTime<Utc>>>, anyhow::Error> {
+        let state = self.state.clone();
+
+        tokio::task::spawn_blocking(move || select_last_written_from_sqlite(&state.lock().unwrap().connection).map(Some)).await?
+    }
+
+    async fn last_states(&self) -> Result<Option<Vec<DeviceState>>, anyhow::Error> {
+        let state = self.state.clone();
+
+        tokio::task::spawn_blocking(move || select_last_states_from_sqlite(&state.lock().unwrap().connection).map(Some)).await?
+    }
+
+    /// Moves the WAL into the database file so that a copy of the file alone is complete
+    async fn flush(&self) -> Result<(), anyhow::Error> {
+        let state = self.state.clone();
+
+        tokio::task::spawn_blocking(move || {
+            state.lock().unwrap().connection.pragma_update(None, "wal_checkpoint", "TRUNCATE")?;
+            Ok(())
+        })
+        .await?
+    }
+}
+
+pub fn connect_to_db() -> Result<SqliteSink, anyhow::Error> {
+    let path = database_path();
+    info!("Using SQLite database {}", path);
+
+    SqliteSink::open(Path::new(&path), retention())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::energy::EnergyDeltas;
+
+    #[tokio::test]
+    async fn test_upsert_keeps_columns_and_prunes() {
+        let directory = std::env::temp_dir().join(format!("melcloud-sqlite-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let sink = SqliteSink::open(&directory.join("melcloud.db"), Some(Duration::days(1))).unwrap();
+
+        let time = Utc::now() - Duration::minutes(5);
+        let mut snapshot = DeviceSnapshot::for_test(1, time);
+        snapshot.heating_energy_consumed_rate1 = Some(1.5);
+        snapshot.energy = Some(EnergyDeltas {
+            total: Some(0.25),
+            ..Default::default()
+        });
+        sink.write(&snapshot).await.unwrap();
+
+        // A sample without the energy counters doesn't clear them
+        snapshot.heating_energy_consumed_rate1 = None;
+        snapshot.energy = None;
+        snapshot.room_temperature = 22.5;
+        sink.write(&snapshot).await.unwrap();
+
+        {
+            let state = sink.state.lock().unwrap();
+            let (count, room_temperature, energy, delta): (u32, f32, Option<f32>, Option<f32>) = state
+                .connection
+                .query_row(
+                    "SELECT count(*), room_temperature, heating_energy_consumed_rate1, total_energy_delta FROM melcloud",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .unwrap();
+            assert_eq!((1, 22.5, Some(1.5), Some(0.25)), (count, room_temperature, energy, delta));
+        }
+
+        let last_written = sink.last_written().await.unwrap().unwrap();
+        assert_eq!(Some(&time), last_written.get(&1));
+        let mut stored = DeviceState::from(&snapshot);
+        stored.heating_energy_consumed_rate1 = Some(1.5);
+        assert_eq!(vec![stored], sink.last_states().await.unwrap().unwrap());
+
+        {
+            let mut state = sink.state.lock().unwrap();
+            SqliteSink::prune(&mut state, sink.retention, Utc::now() + Duration::days(2)).unwrap();
+        }
+        assert!(sink.last_written().await.unwrap().unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
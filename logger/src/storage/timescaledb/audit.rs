@@ -0,0 +1,28 @@
+use deadpool_postgres::Client;
+
+use crate::app::command::CommandAudit;
+use crate::storage::timescaledb::timescale::classify_error;
+
+pub async fn insert_command_into_timescaledb(client: &Client, audit: &CommandAudit) -> Result<(), anyhow::Error> {
+    client
+        .execute(
+            "INSERT INTO melcloud_commands (
+                time, device_id, actor, source, commands, success, error, previous_state, response
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            &[
+                &audit.time,
+                &(audit.device_id as i32),
+                &audit.actor,
+                &audit.source,
+                &serde_json::to_value(&audit.commands)?,
+                &audit.error.is_none(),
+                &audit.error,
+                &audit.previous.as_ref().map(serde_json::to_value).transpose()?,
+                &audit.response.as_ref().map(serde_json::to_value).transpose()?,
+            ],
+        )
+        .await
+        .map_err(classify_error)?;
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use deadpool_postgres::Client;
+
+use crate::app::events::DeviceEvent;
+use crate::storage::timescaledb::timescale::classify_error;
+
+pub async fn insert_events_into_timescaledb(client: &Client, events: &[DeviceEvent]) -> Result<(), anyhow::Error> {
+    let statement = client
+        .prepare(
+            "INSERT INTO melcloud_events (
+                time, device_id, device_name, kind, from_value, to_value, description
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT DO NOTHING",
+        )
+        .await?;
+
+    for event in events {
+        client
+            .execute(
+                &statement,
+                &[
+                    &event.time,
+                    &(event.device_id as i32),
+                    &event.device_name,
+                    &event.kind.as_str(),
+                    &event.from,
+                    &event.to,
+                    &event.description(),
+                ],
+            )
+            .await
+            .map_err(classify_error)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use deadpool_postgres::Client;
+use serde::Serialize;
+use tokio_postgres::Row;
+
+use crate::storage::timescaledb::policies::{continuous_aggregate_for, OPERATION_MODES};
+
+/// The most points a single history query returns
+pub const MAX_POINTS: i64 = 10_000;
+
+/// `raw` for the samples as they are, otherwise a bucket size such as `30s`, `5m`, `1h` or `1d`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Raw,
+    Bucket(Duration),
+}
+
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "raw" {
+            return Ok(Resolution::Raw);
+        }
+
+        let split = value.char_indices().last().map(|(index, _)| index).unwrap_or(0);
+        let (amount, unit) = value.split_at(split);
+        let amount = amount
+            .parse::<i64>()
+            .map_err(|_| anyhow::anyhow!("Expected raw or a size such as 5m, not {}", value))?;
+        let bucket = match unit {
+            "s" => Duration::seconds(amount),
+            "m" => Duration::minutes(amount),
+            "h" => Duration::hours(amount),
+            "d" => Duration::days(amount),
+            _ => return Err(anyhow::anyhow!("Expected the unit s, m, h or d in {}", value)),
+        };
+        if bucket <= Duration::zero() {
+            return Err(anyhow::anyhow!("The resolution must be positive, not {}", value));
+        }
+
+        Ok(Resolution::Bucket(bucket))
+    }
+}
+
+impl Resolution {
+    /// How many buckets the range spans at most, `None` for the raw samples
+    pub fn bucket_count(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<i64> {
+        match self {
+            Resolution::Raw => None,
+            // Buckets are aligned to the epoch, so the range can touch one more
+            Resolution::Bucket(bucket) => Some((to - from).num_seconds() / bucket.num_seconds() + 1),
+        }
+    }
+}
+
+/// A sample, or the aggregate of the samples in a bucket: averages for the measurements, the
+/// most common mode and whether the device was on or offline at any point. The hourly and daily
+/// continuous aggregates don't keep the offline state, fan speed, energy or signal strength, and
+/// their mode is the one the device was on in the longest.
+#[derive(Debug, Serialize)]
+pub struct HistoryPoint {
+    pub time: DateTime<Utc>,
+    pub samples: i64,
+    pub power: bool,
+    pub offline: Option<bool>,
+    pub operation_mode: Option<i16>,
+    pub room_temperature: f32,
+    pub set_temperature: f32,
+    pub fan_speed: Option<i16>,
+    pub current_energy_consumed: Option<f32>,
+    pub wifi_signal_strength: Option<f32>,
+}
+
+impl From<&Row> for HistoryPoint {
+    fn from(row: &Row) -> Self {
+        HistoryPoint {
+            time: row.get(0),
+            samples: row.get(1),
+            power: row.get(2),
+            offline: row.get(3),
+            operation_mode: row.get(4),
+            room_temperature: row.get(5),
+            set_temperature: row.get(6),
+            fan_speed: row.get(7),
+            current_energy_consumed: row.get(8),
+            wifi_signal_strength: row.get(9),
+        }
+    }
+}
+
+pub async fn select_history_from_timescaledb(
+    client: &Client,
+    device_id: u32,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    resolution: Resolution,
+) -> Result<Vec<HistoryPoint>, anyhow::Error> {
+    let device_id = device_id as i32;
+
+    let rows = match resolution {
+        Resolution::Raw => {
+            client
+                .query(
+                    "SELECT time, 1::bigint, power, offline, operation_mode, room_temperature, set_temperature,
+                        fan_speed, current_energy_consumed, wifi_signal_strength
+                    FROM melcloud
+                    WHERE device_id = $1 AND time >= $2 AND time < $3
+                    ORDER BY time
+                    LIMIT $4",
+                    &[&device_id, &from, &to, &(MAX_POINTS + 1)],
+                )
+                .await?
+        }
+        Resolution::Bucket(bucket) if continuous_aggregate_for(bucket.num_seconds()).is_some() => {
+            let aggregate = continuous_aggregate_for(bucket.num_seconds()).unwrap();
+            client
+                .query(
+                    &format!(
+                        "SELECT bucket, samples, {on} > 0, NULL::boolean, {mode}, avg_room_temperature::real,
+                            avg_set_temperature::real, NULL::smallint, NULL::real, NULL::real
+                        FROM {aggregate}
+                        WHERE device_id = $1 AND bucket >= time_bucket(INTERVAL '{seconds} seconds', $2::timestamptz) AND bucket < $3
+                        ORDER BY bucket
+                        LIMIT $4",
+                        on = mode_seconds().join(" + "),
+                        mode = longest_mode_sql(),
+                        aggregate = aggregate,
+                        seconds = bucket.num_seconds(),
+                    ),
+                    &[&device_id, &from, &to, &(MAX_POINTS + 1)],
+                )
+                .await?
+        }
+        Resolution::Bucket(bucket) => {
+            let seconds = bucket.num_seconds() as f64;
+            client
+                .query(
+                    "SELECT time_bucket(make_interval(secs => $4), time) AS bucket, count(*), bool_or(power),
+                        bool_or(offline), mode() WITHIN GROUP (ORDER BY operation_mode),
+                        avg(room_temperature)::real, avg(set_temperature)::real,
+                        mode() WITHIN GROUP (ORDER BY fan_speed), max(current_energy_consumed),
+                        avg(wifi_signal_strength)::real
+                    FROM melcloud
+                    WHERE device_id = $1 AND time >= $2 AND time < $3
+                    GROUP BY bucket
+                    ORDER BY bucket
+                    LIMIT $5",
+                    &[&device_id, &from, &to, &seconds, &(MAX_POINTS + 1)],
+                )
+                .await?
+        }
+    };
+
+    Ok(rows.iter().map(HistoryPoint::from).collect())
+}
+
+fn mode_seconds() -> Vec<String> {
+    OPERATION_MODES.iter().map(|(mode, _)| format!("{}_seconds", mode)).collect()
+}
+
+/// The mode the device was on in the longest in the bucket, `NULL` when it was off throughout
+fn longest_mode_sql() -> String {
+    let cases: Vec<String> = OPERATION_MODES
+        .iter()
+        .map(|(mode, value)| format!("WHEN {}_seconds THEN {}", mode, value))
+        .collect();
+    format!(
+        "CASE GREATEST({}) WHEN 0 THEN NULL {} END::smallint",
+        mode_seconds().join(", "),
+        cases.join(" ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolution() {
+        assert_eq!(Resolution::Raw, "raw".parse().unwrap());
+        assert_eq!(Resolution::Bucket(Duration::minutes(5)), "5m".parse().unwrap());
+        assert_eq!(Resolution::Bucket(Duration::days(1)), "1d".parse().unwrap());
+        assert!("0h".parse::<Resolution>().is_err());
+        assert!("5w".parse::<Resolution>().is_err());
+        assert!("m".parse::<Resolution>().is_err());
+        assert!("".parse::<Resolution>().is_err());
+    }
+
+    #[test]
+    fn test_bucket_count() {
+        let to = Utc::now();
+        assert_eq!(None, Resolution::Raw.bucket_count(to - Duration::days(1), to));
+        assert_eq!(Some(25), Resolution::Bucket(Duration::hours(1)).bucket_count(to - Duration::days(1), to));
+        assert_eq!(
+            "CASE GREATEST(heating_seconds, dry_seconds, cooling_seconds, fan_seconds, auto_seconds) WHEN 0 THEN NULL \
+            WHEN heating_seconds THEN 1 WHEN dry_seconds THEN 2 WHEN cooling_seconds THEN 3 WHEN fan_seconds THEN 7 \
+            WHEN auto_seconds THEN 8 END::smallint",
+            longest_mode_sql()
+        );
+    }
+}
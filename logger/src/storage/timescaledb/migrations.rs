@@ -0,0 +1,133 @@
+use tokio_postgres::Client;
+
+use crate::storage::timescaledb::policies;
+
+// Arbitrary key for the advisory lock that keeps two loggers from migrating at the same time
+const MIGRATION_LOCK_KEY: i64 = 0x6d656c636c6f7564;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// Append new migrations to the end, never edit one that has already been released
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_melcloud",
+        sql: include_str!("../../../migrations/timescaledb/0001_create_melcloud.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_melcloud_commands",
+        sql: include_str!("../../../migrations/timescaledb/0002_create_melcloud_commands.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "create_melcloud_events",
+        sql: include_str!("../../../migrations/timescaledb/0003_create_melcloud_events.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "add_melcloud_energy_deltas",
+        sql: include_str!("../../../migrations/timescaledb/0004_add_melcloud_energy_deltas.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "add_melcloud_energy_cost",
+        sql: include_str!("../../../migrations/timescaledb/0005_add_melcloud_energy_cost.sql"),
+    },
+];
+
+pub fn is_enabled() -> bool {
+    dotenv::var("TIMESCALEDB_MIGRATE")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(true))
+        .unwrap()
+}
+
+async fn applied_versions(client: &Client) -> Result<Vec<i32>, anyhow::Error> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS melcloud_schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL
+            )",
+        )
+        .await?;
+
+    let rows = client
+        .query("SELECT version FROM melcloud_schema_migrations ORDER BY version", &[])
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+async fn apply_pending(client: &mut Client) -> Result<usize, anyhow::Error> {
+    let applied = applied_versions(client).await?;
+
+    let mut count = 0;
+    for migration in MIGRATIONS.iter().filter(|m| !applied.contains(&m.version)) {
+        info!("Applying TimescaleDB migration {} {}", migration.version, migration.name);
+
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(migration.sql).await.map_err(|err| {
+            anyhow::anyhow!("Migration {} {} failed: {:#}", migration.version, migration.name, anyhow::Error::from(err))
+        })?;
+        transaction
+            .execute(
+                "INSERT INTO melcloud_schema_migrations (version, name) VALUES ($1, $2)",
+                &[&migration.version, &migration.name],
+            )
+            .await?;
+        transaction.commit().await?;
+
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+pub async fn run(client: &mut Client) -> Result<usize, anyhow::Error> {
+    client
+        .execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+
+    let result = apply_pending(client).await;
+    // The schema is usable without the policies, so a failing one doesn't hold up the writes
+    if result.is_ok() {
+        if let Err(err) = policies::apply(client).await {
+            error!("Failed to apply the TimescaleDB policies: {}", err);
+        }
+    }
+
+    if let Err(err) = client
+        .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY])
+        .await
+    {
+        warn!("Failed to release the migration lock: {}", err);
+    }
+
+    let count = result?;
+    if count == 0 {
+        info!("TimescaleDB schema is up to date");
+    } else {
+        info!("Applied {} TimescaleDB migration(s)", count);
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered() {
+        let versions: Vec<i32> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<i32> = (1..=MIGRATIONS.len() as i32).collect();
+        assert_eq!(expected, versions);
+    }
+}
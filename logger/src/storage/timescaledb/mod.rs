@@ -1 +1,8 @@
-pub mod timescale;
\ No newline at end of file
+pub mod audit;
+pub mod events;
+pub mod history;
+pub mod migrations;
+pub mod policies;
+pub mod pool;
+pub mod timescale;
+pub mod tls;
@@ -0,0 +1,317 @@
+use tokio_postgres::Client;
+
+// MELCloud operation modes for air-to-air units
+pub const OPERATION_MODES: [(&str, i16); 5] = [
+    ("heating", 1),
+    ("dry", 2),
+    ("cooling", 3),
+    ("fan", 7),
+    ("auto", 8),
+];
+
+struct ContinuousAggregate {
+    name: &'static str,
+    bucket: &'static str,
+    bucket_seconds: u32,
+    start_offset: &'static str,
+    end_offset: &'static str,
+    schedule_interval: &'static str,
+}
+
+const CONTINUOUS_AGGREGATES: [ContinuousAggregate; 2] = [
+    ContinuousAggregate {
+        name: "melcloud_hourly",
+        bucket: "1 hour",
+        bucket_seconds: 3_600,
+        start_offset: "3 hours",
+        end_offset: "1 hour",
+        schedule_interval: "30 minutes",
+    },
+    ContinuousAggregate {
+        name: "melcloud_daily",
+        bucket: "1 day",
+        bucket_seconds: 86_400,
+        start_offset: "3 days",
+        end_offset: "1 hour",
+        schedule_interval: "1 hour",
+    },
+];
+
+pub fn continuous_aggregates_enabled() -> bool {
+    dotenv::var("TIMESCALEDB_CONTINUOUS_AGGREGATES")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+/// The continuous aggregate with buckets of this size, when the aggregates are enabled
+pub fn continuous_aggregate_for(bucket_seconds: i64) -> Option<&'static str> {
+    if !continuous_aggregates_enabled() {
+        return None;
+    }
+    CONTINUOUS_AGGREGATES
+        .iter()
+        .find(|aggregate| aggregate.bucket_seconds as i64 == bucket_seconds)
+        .map(|aggregate| aggregate.name)
+}
+
+/// Reads a policy interval such as `30 days`. `None` leaves the policy untouched,
+/// `Some(None)` means the policy should be removed (`off`).
+fn policy_interval(key: &str) -> Option<Option<String>> {
+    match dotenv::var(key) {
+        Ok(value) if value.trim().is_empty() => None,
+        Ok(value) if value.trim().eq_ignore_ascii_case("off") => Some(None),
+        Ok(value) => Some(Some(value.trim().to_string())),
+        Err(_) => None,
+    }
+}
+
+/// The expressions of the aggregate and their column names
+fn continuous_aggregate_columns(aggregate: &ContinuousAggregate) -> Vec<(String, String)> {
+    let mut columns = vec![
+        (format!("time_bucket(INTERVAL '{}', time)", aggregate.bucket), "bucket".to_string()),
+        ("device_id".to_string(), "device_id".to_string()),
+        ("count(*)".to_string(), "samples".to_string()),
+    ];
+
+    for column in ["room_temperature", "set_temperature"] {
+        for function in ["avg", "min", "max"] {
+            columns.push((format!("{}({})", function, column), format!("{}_{}", function, column)));
+        }
+    }
+
+    // The deltas already account for counter resets and the energy between the samples
+    for mode in ["heating", "cooling", "auto", "dry", "fan", "other", "total"] {
+        columns.push((format!("sum({}_energy_delta)", mode), format!("{}_energy", mode)));
+    }
+    columns.push(("sum(energy_cost)".to_string(), "energy_cost".to_string()));
+
+    // Share of the samples in each mode scaled to the bucket length, which equals the
+    // time on as long as the samples are evenly spaced
+    for (mode, value) in OPERATION_MODES {
+        columns.push((
+            format!(
+                "count(*) FILTER (WHERE power AND operation_mode = {}) * {}.0 / count(*)",
+                value, aggregate.bucket_seconds
+            ),
+            format!("{}_seconds", mode),
+        ));
+    }
+
+    columns
+}
+
+fn continuous_aggregate_sql(aggregate: &ContinuousAggregate) -> String {
+    let columns: Vec<String> = continuous_aggregate_columns(aggregate)
+        .iter()
+        .map(|(expression, name)| format!("{} AS {}", expression, name))
+        .collect();
+
+    format!(
+        "CREATE MATERIALIZED VIEW IF NOT EXISTS {} WITH (timescaledb.continuous) AS
+        SELECT {}
+        FROM melcloud
+        GROUP BY bucket, device_id",
+        aggregate.name,
+        columns.join(",\n            ")
+    )
+}
+
+fn rebuild_aggregates() -> bool {
+    dotenv::var("TIMESCALEDB_REBUILD_AGGREGATES")
+        .map(|var| var.parse::<bool>())
+        .unwrap_or(Ok(false))
+        .unwrap()
+}
+
+/// Whether the aggregate has the columns of the current definition, an older one is only
+/// dropped and created again from the raw data when `TIMESCALEDB_REBUILD_AGGREGATES` allows it
+/// and no bucket would be lost, since the raw chunks may already be gone
+async fn is_current_continuous_aggregate(client: &Client, aggregate: &ContinuousAggregate) -> Result<bool, anyhow::Error> {
+    let rows = client
+        .query(
+            "SELECT column_name::text FROM information_schema.columns
+            WHERE table_name = $1 ORDER BY ordinal_position",
+            &[&aggregate.name],
+        )
+        .await?;
+    let existing: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    let expected: Vec<String> = continuous_aggregate_columns(aggregate)
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    if existing.is_empty() || existing == expected {
+        return Ok(true);
+    }
+
+    if !rebuild_aggregates() {
+        warn!(
+            "Continuous aggregate {} has the columns of an older version, set TIMESCALEDB_REBUILD_AGGREGATES=true to rebuild it",
+            aggregate.name
+        );
+        return Ok(false);
+    }
+
+    let row = client
+        .query_one(
+            &format!(
+                "SELECT (SELECT min(bucket) FROM {}) < (SELECT time_bucket(INTERVAL '{}', min(time)) FROM melcloud)",
+                aggregate.name, aggregate.bucket
+            ),
+            &[],
+        )
+        .await?;
+    if row.get::<_, Option<bool>>(0).unwrap_or(false) {
+        error!(
+            "Not rebuilding continuous aggregate {}, its oldest buckets are older than the raw data",
+            aggregate.name
+        );
+        return Ok(false);
+    }
+
+    warn!("Rebuilding continuous aggregate {} from the raw data", aggregate.name);
+    client
+        .batch_execute(&format!("DROP MATERIALIZED VIEW {} CASCADE", aggregate.name))
+        .await?;
+
+    Ok(true)
+}
+
+async fn apply_continuous_aggregates(client: &Client) -> Result<(), anyhow::Error> {
+    for aggregate in CONTINUOUS_AGGREGATES.iter() {
+        if !is_current_continuous_aggregate(client, aggregate).await? {
+            continue;
+        }
+        // Continuous aggregates can't be created inside a transaction, so every statement
+        // is sent on its own
+        client.batch_execute(&continuous_aggregate_sql(aggregate)).await?;
+        client
+            .batch_execute(&format!(
+                "SELECT add_continuous_aggregate_policy('{}',
+                    start_offset => INTERVAL '{}',
+                    end_offset => INTERVAL '{}',
+                    schedule_interval => INTERVAL '{}',
+                    if_not_exists => TRUE)",
+                aggregate.name,
+                aggregate.start_offset,
+                aggregate.end_offset,
+                aggregate.schedule_interval
+            ))
+            .await?;
+        info!("Continuous aggregate {} is in place", aggregate.name);
+    }
+
+    Ok(())
+}
+
+/// Whether the melcloud job of the policy already matches the interval, or is missing when the
+/// policy is `off`
+async fn policy_in_place(client: &Client, proc_name: &str, key: &str, interval: &Option<String>) -> Result<bool, anyhow::Error> {
+    let row = client
+        .query_one(
+            "SELECT count(*), count(*) FILTER (WHERE (config->>$2)::interval = CAST($3::text AS INTERVAL))
+            FROM timescaledb_information.jobs
+            WHERE proc_name = $1 AND hypertable_name = 'melcloud'",
+            &[&proc_name, &key, interval],
+        )
+        .await?;
+    let (jobs, matching): (i64, i64) = (row.get(0), row.get(1));
+
+    Ok(match interval {
+        Some(_) => jobs == 1 && matching == 1,
+        None => jobs == 0,
+    })
+}
+
+async fn apply_compression_policy(client: &Client, interval: &Option<String>) -> Result<(), anyhow::Error> {
+    if policy_in_place(client, "policy_compression", "compress_after", interval).await? {
+        debug!("Compression policy of melcloud is up to date");
+        return Ok(());
+    }
+
+    client
+        .execute("SELECT remove_compression_policy('melcloud', if_exists => TRUE)", &[])
+        .await?;
+
+    let interval = match interval {
+        Some(interval) => interval,
+        None => {
+            info!("Compression policy removed from melcloud");
+            return Ok(());
+        }
+    };
+
+    let row = client
+        .query_one(
+            "SELECT compression_enabled FROM timescaledb_information.hypertables
+            WHERE hypertable_name = 'melcloud'",
+            &[],
+        )
+        .await?;
+    let compression_enabled: bool = row.get(0);
+
+    // The settings can't be changed once there are compressed chunks
+    if !compression_enabled {
+        client
+            .batch_execute(
+                "ALTER TABLE melcloud SET (
+                    timescaledb.compress,
+                    timescaledb.compress_segmentby = 'device_id',
+                    timescaledb.compress_orderby = 'time DESC'
+                )",
+            )
+            .await?;
+    }
+
+    client
+        .execute(
+            "SELECT add_compression_policy('melcloud', CAST($1::text AS INTERVAL))",
+            &[interval],
+        )
+        .await?;
+    info!("Compressing melcloud chunks older than {}", interval);
+
+    Ok(())
+}
+
+async fn apply_retention_policy(client: &Client, interval: &Option<String>) -> Result<(), anyhow::Error> {
+    if policy_in_place(client, "policy_retention", "drop_after", interval).await? {
+        debug!("Retention policy of melcloud is up to date");
+        return Ok(());
+    }
+
+    client
+        .execute("SELECT remove_retention_policy('melcloud', if_exists => TRUE)", &[])
+        .await?;
+
+    match interval {
+        Some(interval) => {
+            client
+                .execute(
+                    "SELECT add_retention_policy('melcloud', CAST($1::text AS INTERVAL))",
+                    &[interval],
+                )
+                .await?;
+            info!("Dropping melcloud chunks older than {}", interval);
+        }
+        None => info!("Retention policy removed from melcloud"),
+    }
+
+    Ok(())
+}
+
+pub async fn apply(client: &Client) -> Result<(), anyhow::Error> {
+    if continuous_aggregates_enabled() {
+        apply_continuous_aggregates(client).await?;
+    }
+
+    if let Some(interval) = policy_interval("TIMESCALEDB_COMPRESS_AFTER") {
+        apply_compression_policy(client, &interval).await?;
+    }
+
+    if let Some(interval) = policy_interval("TIMESCALEDB_RETENTION") {
+        apply_retention_policy(client, &interval).await?;
+    }
+
+    Ok(())
+}
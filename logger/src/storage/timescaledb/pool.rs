@@ -0,0 +1,195 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod, Runtime};
+use serde::Serialize;
+
+use crate::storage::timescaledb::{migrations, tls};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+fn pool_size() -> usize {
+    dotenv::var("TIMESCALEDB_POOL_SIZE")
+        .map(|var| var.parse::<usize>())
+        .unwrap_or(Ok(4))
+        .unwrap()
+}
+
+fn connect_timeout() -> Duration {
+    let seconds = dotenv::var("TIMESCALEDB_CONNECT_TIMEOUT")
+        .map(|var| var.parse::<u64>())
+        .unwrap_or(Ok(10))
+        .unwrap();
+    Duration::from_secs(seconds)
+}
+
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    fn delay(&self) -> Duration {
+        let exponent = self.failures.saturating_sub(1).min(16);
+        (MIN_BACKOFF * 2u32.pow(exponent)).min(MAX_BACKOFF)
+    }
+}
+
+/// Where the schema migrations stand for this process
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Disabled,
+    Pending,
+    Applied,
+    Failed(String),
+}
+
+/// Connection pool that verifies connections before handing them out, waits with an
+/// exponential backoff after failed connects or migrations and migrates the schema on the first success
+#[derive(Clone)]
+pub struct TimescalePool {
+    pool: Pool,
+    backoff: Arc<Mutex<Backoff>>,
+    migration: Arc<Mutex<MigrationState>>,
+}
+
+impl TimescalePool {
+    pub fn new(connection_string: &str) -> Result<TimescalePool, anyhow::Error> {
+        let (connection_string, tls_options) = tls::split_connection_string(connection_string)?;
+        let mut pg_config = tokio_postgres::Config::from_str(&connection_string)?;
+        pg_config.ssl_mode(tls_options.pg_ssl_mode());
+
+        let manager = Manager::from_config(
+            pg_config,
+            tls::make_tls_connect(&tls_options)?,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Verified,
+            },
+        );
+        let timeout = Some(connect_timeout());
+        let pool = Pool::builder(manager)
+            .max_size(pool_size())
+            .runtime(Runtime::Tokio1)
+            .wait_timeout(timeout)
+            .create_timeout(timeout)
+            .recycle_timeout(timeout)
+            .build()?;
+
+        Ok(TimescalePool {
+            pool,
+            backoff: Arc::new(Mutex::new(Backoff::default())),
+            migration: Arc::new(Mutex::new(if migrations::is_enabled() {
+                MigrationState::Pending
+            } else {
+                MigrationState::Disabled
+            })),
+        })
+    }
+
+    /// Closes the idle connections, any later `get` fails
+    pub fn close(&self) {
+        self.pool.close();
+    }
+
+    async fn connect(&self) -> Result<Object, anyhow::Error> {
+        if let Some(retry_at) = self.backoff.lock().unwrap().retry_at {
+            let now = Instant::now();
+            if retry_at > now {
+                return Err(anyhow::anyhow!(
+                    "TimescaleDB is unavailable, reconnecting in {}s",
+                    (retry_at - now).as_secs() + 1
+                ));
+            }
+        }
+
+        match self.pool.get().await {
+            Ok(client) => Ok(client),
+            Err(err) => {
+                let delay = self.back_off();
+                Err(anyhow::anyhow!(
+                    "Failed to connect to TimescaleDB, retrying in {}s: {:#}",
+                    delay.as_secs(),
+                    anyhow::Error::from(err)
+                ))
+            }
+        }
+    }
+
+    fn back_off(&self) -> Duration {
+        let mut backoff = self.backoff.lock().unwrap();
+        backoff.failures += 1;
+        let delay = backoff.delay();
+        backoff.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+
+    fn recovered(&self) {
+        let mut backoff = self.backoff.lock().unwrap();
+        if backoff.failures > 0 {
+            info!("Recovered TimescaleDB after {} failed attempt(s)", backoff.failures);
+        }
+        *backoff = Backoff::default();
+    }
+
+    pub fn migration_state(&self) -> MigrationState {
+        self.migration.lock().unwrap().clone()
+    }
+
+    async fn run_migrations(&self, client: &mut Object) -> Result<usize, anyhow::Error> {
+        match migrations::run(client).await {
+            Ok(count) => {
+                *self.migration.lock().unwrap() = MigrationState::Applied;
+                Ok(count)
+            }
+            Err(err) => {
+                *self.migration.lock().unwrap() = MigrationState::Failed(err.to_string());
+                let delay = self.back_off();
+                Err(anyhow::anyhow!(
+                    "Failed to migrate TimescaleDB, retrying in {}s: {}",
+                    delay.as_secs(),
+                    err
+                ))
+            }
+        }
+    }
+
+    pub async fn get(&self) -> Result<Object, anyhow::Error> {
+        let mut client = self.connect().await?;
+
+        if matches!(self.migration_state(), MigrationState::Pending | MigrationState::Failed(_)) {
+            self.run_migrations(&mut client).await?;
+        }
+        self.recovered();
+
+        Ok(client)
+    }
+
+    pub async fn migrate(&self) -> Result<usize, anyhow::Error> {
+        let mut client = self.connect().await?;
+        let count = self.run_migrations(&mut client).await?;
+        self.recovered();
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay_is_capped() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (1..=10)
+            .map(|failures| {
+                backoff.failures = failures;
+                backoff.delay().as_secs()
+            })
+            .collect();
+        assert_eq!(vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300], delays);
+    }
+}
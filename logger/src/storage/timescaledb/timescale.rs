@@ -1,5 +1,14 @@
-use api::{ListDevicesResponse, CurrentDataResponse};
-use tokio_postgres::{Error, NoTls, Client};
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::Client;
+
+use crate::app::events::{DeviceEvent, DeviceState};
+use crate::app::snapshot::DeviceSnapshot;
+use crate::storage::sink::{Rejected, Sink};
+use crate::storage::timescaledb::events::insert_events_into_timescaledb;
+use crate::storage::timescaledb::pool::TimescalePool;
 
 pub fn is_enabled() -> bool {
     dotenv::var("TIMESCALEDB_ENABLED")
@@ -8,26 +17,15 @@ pub fn is_enabled() -> bool {
         .unwrap()
 }
 
-pub async fn upsert_device_list_entry_into_timescaledb(client: &Client, data: &Vec<ListDevicesResponse>) -> Result<(), anyhow::Error> {
+pub async fn upsert_snapshot_into_timescaledb(client: &Client, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
     if !is_enabled() {
         return Ok(());
     }
 
-    let list_devices = &data[0];
-    let structure = &list_devices.structure;
-    let devices = &structure.devices;
-    let device = &devices[0].device;
-
-    let system_time = device.last_time_stamp_to_utc_datetime();
-    if system_time.is_none() {
-        return Err(anyhow::anyhow!("Skipping logging because system time couldn't be parsed"));
-    }
-
-    let system_time = system_time.unwrap();
-    info!("System Time UTC: {:?}", system_time);
-
-    let _ = client
-    .execute(
+    // The nullable columns keep their earlier value when a sample without them (current data)
+    // lands on the same row
+    let statement = client
+    .prepare_cached(
         "INSERT INTO melcloud (
             time, device_id, device_type, power, offline, room_temperature, set_temperature, 
             last_communication, actual_fan_speed, fan_speed, automatic_fan_speed, 
@@ -38,90 +36,201 @@ pub async fn upsert_device_list_entry_into_timescaledb(client: &Client, data: &V
             dry_energy_consumed_rate2, fan_energy_consumed_rate1, fan_energy_consumed_rate2, 
             other_energy_consumed_rate1, other_energy_consumed_rate2, current_energy_consumed, 
             current_energy_mode, energy_correction_model, energy_correction_active, 
-            wifi_signal_strength, wifi_adapter_status, has_error
+            wifi_signal_strength, wifi_adapter_status, has_error, heating_energy_delta,
+            cooling_energy_delta, auto_energy_delta, dry_energy_delta, fan_energy_delta,
+            other_energy_delta, total_energy_delta, energy_delta_seconds, energy_counter_reset,
+            energy_gap, energy_cost, energy_price
         ) VALUES (
             $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, 
-            $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36
+            $19, $20, $21, $22, $23, $24, $25, $26, $27, $28, $29, $30, $31, $32, $33, $34, $35, $36,
+            $37, $38, $39, $40, $41, $42, $43, $44, $45, $46, $47, $48
         ) ON CONFLICT (time, device_id) DO UPDATE SET 
             device_type = $3, power = $4, offline = $5, room_temperature = $6, set_temperature = $7, 
-            last_communication = $8, actual_fan_speed = $9, fan_speed = $10, automatic_fan_speed = $11, 
-            vane_vertical_direction = $12, vane_vertical_swing = $13, vane_horizontal_direction = $14, 
-            vane_horizontal_swing = $15, operation_mode = $16, in_standby_mode = $17, 
-            heating_energy_consumed_rate1 = $18, heating_energy_consumed_rate2 = $19, 
-            cooling_energy_consumed_rate1 = $20, cooling_energy_consumed_rate2 = $21, 
-            auto_energy_consumed_rate1 = $22, auto_energy_consumed_rate2 = $23, 
-            dry_energy_consumed_rate1 = $24, dry_energy_consumed_rate2 = $25, 
-            fan_energy_consumed_rate1 = $26, fan_energy_consumed_rate2 = $27, 
-            other_energy_consumed_rate1 = $28, other_energy_consumed_rate2 = $29, 
-            current_energy_consumed = $30, current_energy_mode = $31, 
-            energy_correction_model = $32, energy_correction_active = $33, 
-            wifi_signal_strength = $34, wifi_adapter_status = $35, has_error = $36",
-        &[&system_time, &(device.device_iD as i32), &(device.device_type as i16), &device.power, &device.offline, &device.room_temperature, &device.set_temperature, 
-          &device.last_time_stamp.clone(), &(device.actual_fan_speed as i16), &(device.fan_speed as i16), &device.automatic_fan_speed, 
-          &(device.vane_vertical_direction as i16), &device.vane_vertical_swing, &(device.vane_horizontal_direction as i16), 
-          &device.vane_horizontal_swing, &(device.operation_mode as i16), &device.in_standby_mode, &device.heating_energy_consumed_rate1, 
-          &device.heating_energy_consumed_rate2, &device.cooling_energy_consumed_rate1, &device.cooling_energy_consumed_rate2, 
-          &device.auto_energy_consumed_rate1, &device.auto_energy_consumed_rate2, &device.dry_energy_consumed_rate1, 
-          &device.dry_energy_consumed_rate2, &device.fan_energy_consumed_rate1, &device.fan_energy_consumed_rate2, 
-          &device.other_energy_consumed_rate1, &device.other_energy_consumed_rate2, &device.current_energy_consumed, 
-          &(device.current_energy_mode.map(|num| num as i16)), &device.energy_correction_model, &device.energy_correction_active, 
-          &device.wifi_signal_strength, &device.wifi_adapter_status, &device.has_error]
+            last_communication = $8, actual_fan_speed = $9, fan_speed = $10, 
+            automatic_fan_speed = COALESCE($11, melcloud.automatic_fan_speed), 
+            vane_vertical_direction = $12, vane_vertical_swing = COALESCE($13, melcloud.vane_vertical_swing), 
+            vane_horizontal_direction = $14, vane_horizontal_swing = COALESCE($15, melcloud.vane_horizontal_swing), 
+            operation_mode = $16, in_standby_mode = $17, 
+            heating_energy_consumed_rate1 = COALESCE($18, melcloud.heating_energy_consumed_rate1), 
+            heating_energy_consumed_rate2 = COALESCE($19, melcloud.heating_energy_consumed_rate2), 
+            cooling_energy_consumed_rate1 = COALESCE($20, melcloud.cooling_energy_consumed_rate1), 
+            cooling_energy_consumed_rate2 = COALESCE($21, melcloud.cooling_energy_consumed_rate2), 
+            auto_energy_consumed_rate1 = COALESCE($22, melcloud.auto_energy_consumed_rate1), 
+            auto_energy_consumed_rate2 = COALESCE($23, melcloud.auto_energy_consumed_rate2), 
+            dry_energy_consumed_rate1 = COALESCE($24, melcloud.dry_energy_consumed_rate1), 
+            dry_energy_consumed_rate2 = COALESCE($25, melcloud.dry_energy_consumed_rate2), 
+            fan_energy_consumed_rate1 = COALESCE($26, melcloud.fan_energy_consumed_rate1), 
+            fan_energy_consumed_rate2 = COALESCE($27, melcloud.fan_energy_consumed_rate2), 
+            other_energy_consumed_rate1 = COALESCE($28, melcloud.other_energy_consumed_rate1), 
+            other_energy_consumed_rate2 = COALESCE($29, melcloud.other_energy_consumed_rate2), 
+            current_energy_consumed = COALESCE($30, melcloud.current_energy_consumed), 
+            current_energy_mode = COALESCE($31, melcloud.current_energy_mode), 
+            energy_correction_model = COALESCE($32, melcloud.energy_correction_model), 
+            energy_correction_active = COALESCE($33, melcloud.energy_correction_active), 
+            wifi_signal_strength = COALESCE($34, melcloud.wifi_signal_strength), 
+            wifi_adapter_status = COALESCE($35, melcloud.wifi_adapter_status), 
+            has_error = COALESCE($36, melcloud.has_error), 
+            heating_energy_delta = COALESCE($37, melcloud.heating_energy_delta), 
+            cooling_energy_delta = COALESCE($38, melcloud.cooling_energy_delta), 
+            auto_energy_delta = COALESCE($39, melcloud.auto_energy_delta), 
+            dry_energy_delta = COALESCE($40, melcloud.dry_energy_delta), 
+            fan_energy_delta = COALESCE($41, melcloud.fan_energy_delta), 
+            other_energy_delta = COALESCE($42, melcloud.other_energy_delta), 
+            total_energy_delta = COALESCE($43, melcloud.total_energy_delta), 
+            energy_delta_seconds = COALESCE($44, melcloud.energy_delta_seconds), 
+            energy_counter_reset = COALESCE($45, melcloud.energy_counter_reset), 
+            energy_gap = COALESCE($46, melcloud.energy_gap), 
+            energy_cost = COALESCE($47, melcloud.energy_cost), 
+            energy_price = COALESCE($48, melcloud.energy_price)"
     )
     .await?;
 
+    let energy = snapshot.energy;
+    let _ = client
+    .execute(
+        &statement,
+        &[&snapshot.time, &(snapshot.device_id as i32), &(snapshot.device_type as i16), &snapshot.power, &snapshot.offline, &snapshot.room_temperature, &snapshot.set_temperature, 
+          &snapshot.last_communication, &(snapshot.actual_fan_speed as i16), &(snapshot.fan_speed as i16), &snapshot.automatic_fan_speed, 
+          &(snapshot.vane_vertical_direction as i16), &snapshot.vane_vertical_swing, &(snapshot.vane_horizontal_direction as i16), 
+          &snapshot.vane_horizontal_swing, &(snapshot.operation_mode as i16), &snapshot.in_standby_mode, &snapshot.heating_energy_consumed_rate1, 
+          &snapshot.heating_energy_consumed_rate2, &snapshot.cooling_energy_consumed_rate1, &snapshot.cooling_energy_consumed_rate2, 
+          &snapshot.auto_energy_consumed_rate1, &snapshot.auto_energy_consumed_rate2, &snapshot.dry_energy_consumed_rate1, 
+          &snapshot.dry_energy_consumed_rate2, &snapshot.fan_energy_consumed_rate1, &snapshot.fan_energy_consumed_rate2, 
+          &snapshot.other_energy_consumed_rate1, &snapshot.other_energy_consumed_rate2, &snapshot.current_energy_consumed, 
+          &(snapshot.current_energy_mode.map(|num| num as i16)), &snapshot.energy_correction_model, &snapshot.energy_correction_active, 
+          &snapshot.wifi_signal_strength, &snapshot.wifi_adapter_status, &snapshot.has_error, &energy.and_then(|energy| energy.heating), 
+          &energy.and_then(|energy| energy.cooling), &energy.and_then(|energy| energy.auto), &energy.and_then(|energy| energy.dry), 
+          &energy.and_then(|energy| energy.fan), &energy.and_then(|energy| energy.other), &energy.and_then(|energy| energy.total), 
+          &energy.map(|energy| energy.seconds as i32), &energy.map(|energy| energy.reset), &energy.map(|energy| energy.gap), 
+          &energy.and_then(|energy| energy.cost), &energy.and_then(|energy| energy.price)]
+    )
+    .await
+    .map_err(classify_error)?;
+
     Ok(())
 }
 
-pub async fn upsert_current_data_into_timescaledb(client: &Client, data: &CurrentDataResponse) -> Result<(), anyhow::Error> {
-    if !is_enabled() {
-        return Ok(());
+/// Data exceptions and constraint violations come from the row itself, they won't go away on a retry
+pub fn classify_error(err: tokio_postgres::Error) -> anyhow::Error {
+    match err.code().map(|code| &code.code()[..2]) {
+        Some("22") | Some("23") => Rejected(format!("TimescaleDB rejected the row: {}", err)).into(),
+        _ => err.into(),
     }
+}
+
+pub async fn select_last_written_from_timescaledb(client: &Client) -> Result<HashMap<u32, DateTime<Utc>>, anyhow::Error> {
+    // Limited to the recent chunks, older samples can't come back from MELCloud anyway
+    let rows = client
+        .query(
+            "SELECT device_id, max(time) FROM melcloud
+            WHERE time > NOW() - INTERVAL '7 days'
+            GROUP BY device_id",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get::<_, i32>(0) as u32, row.get::<_, DateTime<Utc>>(1)))
+        .collect())
+}
+
+pub async fn select_last_states_from_timescaledb(client: &Client) -> Result<Vec<DeviceState>, anyhow::Error> {
+    let rows = client
+        .query(
+            "SELECT DISTINCT ON (device_id)
+                time, device_id, power, offline, set_temperature, fan_speed, vane_vertical_direction,
+                vane_horizontal_direction, operation_mode, in_standby_mode, has_error,
+                heating_energy_consumed_rate1, heating_energy_consumed_rate2, cooling_energy_consumed_rate1,
+                cooling_energy_consumed_rate2, auto_energy_consumed_rate1, auto_energy_consumed_rate2,
+                dry_energy_consumed_rate1, dry_energy_consumed_rate2, fan_energy_consumed_rate1,
+                fan_energy_consumed_rate2, other_energy_consumed_rate1, other_energy_consumed_rate2,
+                current_energy_consumed
+            FROM melcloud
+            WHERE time > NOW() - INTERVAL '7 days'
+            ORDER BY device_id, time DESC",
+            &[],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| DeviceState {
+            time: row.get(0),
+            device_id: row.get::<_, i32>(1) as u32,
+            power: row.get(2),
+            offline: row.get(3),
+            set_temperature: row.get(4),
+            fan_speed: row.get::<_, i16>(5) as u8,
+            vane_vertical_direction: row.get::<_, i16>(6) as u8,
+            vane_horizontal_direction: row.get::<_, i16>(7) as u8,
+            operation_mode: row.get::<_, i16>(8) as u8,
+            in_standby_mode: row.get(9),
+            has_error: row.get(10),
+
+            heating_energy_consumed_rate1: row.get(11),
+            heating_energy_consumed_rate2: row.get(12),
+            cooling_energy_consumed_rate1: row.get(13),
+            cooling_energy_consumed_rate2: row.get(14),
+            auto_energy_consumed_rate1: row.get(15),
+            auto_energy_consumed_rate2: row.get(16),
+            dry_energy_consumed_rate1: row.get(17),
+            dry_energy_consumed_rate2: row.get(18),
+            fan_energy_consumed_rate1: row.get(19),
+            fan_energy_consumed_rate2: row.get(20),
+            other_energy_consumed_rate1: row.get(21),
+            other_energy_consumed_rate2: row.get(22),
+            current_energy_consumed: row.get(23),
+        })
+        .collect())
+}
 
-    let system_time = data.last_communication_to_utc_datetime();
-    if system_time.is_none() {
-        return Err(anyhow::anyhow!("Skipping logging because system time couldn't be parsed"));
+#[async_trait]
+impl Sink for TimescalePool {
+    fn name(&self) -> &'static str {
+        "timescaledb"
     }
 
-    let system_time = system_time.unwrap();
-    info!("System Time UTC: {:?}", system_time);
+    async fn write(&self, snapshot: &DeviceSnapshot) -> Result<(), anyhow::Error> {
+        let client = self.get().await?;
+        upsert_snapshot_into_timescaledb(&client, snapshot).await
+    }
 
-    let _ = client
-    .execute(
-        "INSERT INTO melcloud (
-            time, device_id, device_type, power, offline, room_temperature, set_temperature, 
-            last_communication, actual_fan_speed, fan_speed, vane_vertical_direction, 
-            vane_horizontal_direction, operation_mode, in_standby_mode
-        ) VALUES (
-            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14
-        ) ON CONFLICT (time, device_id) DO UPDATE SET 
-            device_type = $3, power = $4, offline = $5, room_temperature = $6, set_temperature = $7, 
-            last_communication = $8, actual_fan_speed = $9, fan_speed = $10, vane_vertical_direction = $11, 
-            vane_horizontal_direction = $12, operation_mode = $13, in_standby_mode = $14",
-        &[&system_time, &(data.device_iD as i32), &(data.device_type as i16), &data.power, &data.offline, &data.room_temperature, &data.set_temperature, 
-          &data.last_communication.clone(), &(data.set_fan_speed as i16), &(data.set_fan_speed as i16), &(data.vane_vertical as i16), 
-          &(data.vane_horizontal as i16), &(data.operation_mode as i16), &data.in_standby_mode]
-    )
-    .await?;
+    async fn write_events(&self, events: &[DeviceEvent]) -> Result<(), anyhow::Error> {
+        let client = self.get().await?;
+        insert_events_into_timescaledb(&client, events).await
+    }
 
-    Ok(())
+    async fn last_written(&self) -> Result<Option<HashMap<u32, DateTime<Utc>>>, anyhow::Error> {
+        let client = self.get().await?;
+        select_last_written_from_timescaledb(&client).await.map(Some)
+    }
+
+    async fn last_states(&self) -> Result<Option<Vec<DeviceState>>, anyhow::Error> {
+        let client = self.get().await?;
+        select_last_states_from_timescaledb(&client).await.map(Some)
+    }
+
+    async fn flush(&self) -> Result<(), anyhow::Error> {
+        self.close();
+        Ok(())
+    }
 }
 
-pub async fn connect_to_db() -> Result<tokio_postgres::Client, Error> {
-    let (client, connection) = tokio_postgres::connect(
-        &dotenv::var("TIMESCALEDB_CONNECTION_STRING").unwrap_or(
-            "host=localhost user=myuser password=mysecretpassword dbname=melcloud".to_string(),
-        ),
-        NoTls,
+pub fn connection_string() -> String {
+    dotenv::var("TIMESCALEDB_CONNECTION_STRING").unwrap_or(
+        "host=localhost user=myuser password=mysecretpassword dbname=melcloud".to_string(),
     )
-    .await?;
+}
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            error!("connection error: {}", e);
-        }
-    });
+pub async fn connect_to_db() -> Result<TimescalePool, anyhow::Error> {
+    let pool = TimescalePool::new(&connection_string())?;
+
+    // The pool keeps reconnecting in the background of later writes, so a database that is
+    // down at startup only delays the logging
+    if let Err(err) = pool.get().await {
+        error!("Failed to connect to timescale database: {}", err);
+    }
 
-    Ok(client)
+    Ok(pool)
 }
@@ -1,3 +1,6 @@
+-- The logger creates this table automatically on startup (see logger/migrations/timescaledb),
+-- this script is only needed when setting up the database by hand.
+
 CREATE TABLE melcloud (
     time TIMESTAMP WITH TIME ZONE DEFAULT NOW() NOT NULL,
     device_id INTEGER NOT NULL,